@@ -0,0 +1,608 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use ndarray::{Array2, ArrayView1};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use serde::{Deserialize, Serialize};
+use crate::cache::CacheNamespace;
+use crate::distance::{DistanceMetric, NeighborSearch};
+use crate::encryption::{CacheCipher, is_encrypted};
+use crate::errors::CustomError;
+
+
+/// The first bytes of a saved index.
+const INDEX_MAGIC: &[u8; 8] = b"EVTXHNSW";
+/// The version of the saved index layout.
+const INDEX_VERSION: u32 = 2;
+/// The purpose authenticated with an encrypted index.
+const INDEX_AAD: &[u8] = b"hnsw_index";
+/// The highest layer a node is placed in.
+const MAX_LEVEL: usize = 16;
+
+
+/// The graph parameters of an HNSW index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HnswParams {
+    /// The neighbors of a node in each layer, twice as many in the bottom layer.
+    pub m: usize,
+    /// The candidates considered when linking a new node. Higher builds a better graph slower.
+    pub ef_construction: usize,
+    /// The candidates considered by a query. Higher finds more of the true neighbors slower.
+    pub ef_search: usize,
+    /// The seed the layer of each node is drawn with.
+    pub seed: u64
+}
+impl Default for HnswParams {
+    fn default() -> Self {
+        Self {
+            m: 16,
+            ef_construction: 100,
+            ef_search: 64,
+            seed: 42
+        }
+    }
+}
+
+
+/// A node and its distance to a query, ordered by the distance.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Candidate {
+    distance: f32,
+    node: u32
+}
+impl Eq for Candidate {}
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance.total_cmp(&other.distance).then(self.node.cmp(&other.node))
+    }
+}
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+
+/// The metadata at the start of a saved index.
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexHeader {
+    version: u32,
+    metric: String,
+    dimensions: usize,
+    params: HnswParams,
+    nodes: usize,
+    entry: Option<u32>
+}
+
+
+/// An approximate nearest-neighbor index of vectors, a Hierarchical Navigable Small World graph.
+/// Vectors are prepared for the metric when they are added and each is keyed by the cache entry
+/// key of its input, so an input is indexed once even when its cached vector is stored with less
+/// precision. Vectors can be added to a saved index, so it only grows with the new inputs of a run.
+#[derive(Debug, Clone)]
+pub struct HnswIndex {
+    metric: DistanceMetric,
+    params: HnswParams,
+    dimensions: usize,
+    vectors: Vec<f32>,
+    /// The neighbors of each node in each of its layers, from the bottom layer.
+    links: Vec<Vec<Vec<u32>>>,
+    entry: Option<u32>,
+    /// The key of each node.
+    keys: Vec<blake3::Hash>,
+    /// The node of each key.
+    nodes: HashMap<blake3::Hash, u32>
+}
+impl HnswIndex {
+    pub fn new(metric: DistanceMetric, dimensions: usize, params: HnswParams) -> Self {
+        Self {
+            metric,
+            params,
+            dimensions,
+            vectors: Vec::new(),
+            links: Vec::new(),
+            entry: None,
+            keys: Vec::new(),
+            nodes: HashMap::new()
+        }
+    }
+
+    /// The path of the index of a cache namespace and metric. Indexes are kept in a directory
+    /// next to the cache directory, named after it with an `.ann` extension.
+    pub fn path_for(cache: impl AsRef<Path>, namespace: &CacheNamespace, metric: DistanceMetric) -> PathBuf {
+        let cache = cache.as_ref();
+        let name = cache.file_name()
+            .map_or("cache".to_string(), |n| n.to_string_lossy().to_string());
+        let hash = blake3::hash(namespace.name().as_bytes()).to_hex();
+        cache.with_file_name(format!("{name}.ann"))
+            .join(format!("{}-{metric}.hnsw", &hash[..16]))
+    }
+
+    /// Load the index at `path`, or create an empty one when there is no index or it was built
+    /// with another metric, dimensions or graph parameters. The `ef_search` of `params` is used
+    /// either way.
+    pub fn load_or_new(path: impl AsRef<Path>, metric: DistanceMetric, dimensions: usize, params: HnswParams) -> Result<Self, CustomError> {
+        Self::load_or_new_w_cipher(path, metric, dimensions, params, None)
+    }
+
+    /// `load_or_new` with the cipher of an encrypted cache. An index that is not encrypted with
+    /// it is rebuilt.
+    pub fn load_or_new_w_cipher(
+        path: impl AsRef<Path>,
+        metric: DistanceMetric,
+        dimensions: usize,
+        params: HnswParams,
+        cipher: Option<&CacheCipher>
+    ) -> Result<Self, CustomError> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::new(metric, dimensions, params));
+        }
+        let mut index = match Self::load_w_cipher(path, cipher) {
+            Ok(index) => index,
+            Err(e) => {
+                warn!("Rebuilding the index {:?}: {}", path, e.message);
+                return Ok(Self::new(metric, dimensions, params));
+            }
+        };
+        let same_graph = index.params.m == params.m
+            && index.params.ef_construction == params.ef_construction
+            && index.params.seed == params.seed;
+        if index.metric != metric || index.dimensions != dimensions || !same_graph {
+            info!("Rebuilding the index {:?}, it was built with other parameters.", path);
+            return Ok(Self::new(metric, dimensions, params));
+        }
+        index.params.ef_search = params.ef_search;
+        Ok(index)
+    }
+
+    /// The number of indexed vectors.
+    pub fn len(&self) -> usize {
+        self.links.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.links.is_empty()
+    }
+
+    pub fn metric(&self) -> DistanceMetric {
+        self.metric
+    }
+
+    pub fn params(&self) -> &HnswParams {
+        &self.params
+    }
+
+    /// Add the rows of a dataset whose `keys` are not indexed yet, one key per row. Returns the
+    /// number of rows added.
+    pub fn extend(&mut self, keys: &[blake3::Hash], dataset: &Array2<f32>) -> Result<usize, CustomError> {
+        self.check_dimensions(dataset.ncols())?;
+        check_keys(keys, dataset)?;
+        let dataset = self.metric.prepare(dataset);
+        let mut added = 0;
+        for (key, row) in keys.iter().zip(dataset.rows()) {
+            if !self.nodes.contains_key(key) {
+                self.insert(row.to_vec(), *key);
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    /// The node of a key, if it is indexed.
+    fn node(&self, key: &blake3::Hash) -> Option<u32> {
+        self.nodes.get(key).copied()
+    }
+
+    fn check_dimensions(&self, dimensions: usize) -> Result<(), CustomError> {
+        if dimensions != self.dimensions {
+            return Err(CustomError::general_error(format!(
+                "The index has {} dimensions, got {dimensions}.", self.dimensions
+            )));
+        }
+        Ok(())
+    }
+
+    fn vector(&self, node: u32) -> ArrayView1<'_, f32> {
+        let start = node as usize * self.dimensions;
+        ArrayView1::from(&self.vectors[start..start + self.dimensions])
+    }
+
+    fn distance(&self, query: ArrayView1<f32>, node: u32) -> f32 {
+        self.metric.distance(query, self.vector(node))
+    }
+
+    /// The `ef` nearest nodes to a query in one layer, from the nearest, searching from
+    /// `entry_points`.
+    fn search_layer(&self, query: ArrayView1<f32>, entry_points: &[Candidate], ef: usize, level: usize) -> Vec<Candidate> {
+        let mut visited: HashSet<u32> = entry_points.iter().map(|c| c.node).collect();
+        let mut candidates: BinaryHeap<Reverse<Candidate>> = entry_points.iter().map(|c| Reverse(*c)).collect();
+        let mut found: BinaryHeap<Candidate> = entry_points.iter().copied().collect();
+
+        while let Some(Reverse(candidate)) = candidates.pop() {
+            let farthest = found.peek().map_or(f32::INFINITY, |c| c.distance);
+            if candidate.distance > farthest && found.len() >= ef {
+                break;
+            }
+            for neighbor in &self.links[candidate.node as usize][level] {
+                if !visited.insert(*neighbor) {
+                    continue;
+                }
+                let distance = self.distance(query, *neighbor);
+                let farthest = found.peek().map_or(f32::INFINITY, |c| c.distance);
+                if found.len() < ef || distance < farthest {
+                    let next = Candidate { distance, node: *neighbor };
+                    candidates.push(Reverse(next));
+                    found.push(next);
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        }
+        found.into_sorted_vec()
+    }
+
+    /// Descend from the entry point through the upper layers and search the bottom layer.
+    fn search_prepared(&self, query: ArrayView1<f32>, k: usize, ef: usize) -> Vec<Candidate> {
+        let Some(entry) = self.entry else {
+            return Vec::new();
+        };
+        let mut entry_points = vec![Candidate { distance: self.distance(query, entry), node: entry }];
+        for level in (1..self.links[entry as usize].len()).rev() {
+            entry_points = self.search_layer(query, &entry_points, 1, level);
+        }
+        let mut found = self.search_layer(query, &entry_points, ef.max(k), 0);
+        found.truncate(k);
+        found
+    }
+
+    /// Keep up to `m` of the candidates, sorted from the nearest, that are closer to the node
+    /// than to any candidate kept before them so links spread in every direction. Skipped
+    /// candidates fill the remaining links.
+    fn select_neighbors(&self, candidates: &[Candidate], m: usize) -> Vec<u32> {
+        let mut selected: Vec<u32> = Vec::with_capacity(m);
+        let mut skipped = Vec::new();
+        for candidate in candidates {
+            if selected.len() >= m {
+                break;
+            }
+            let vector = self.vector(candidate.node);
+            if selected.iter().all(|s| self.distance(vector, *s) > candidate.distance) {
+                selected.push(candidate.node);
+            } else {
+                skipped.push(candidate.node);
+            }
+        }
+        let missing = m.saturating_sub(selected.len());
+        selected.extend(skipped.into_iter().take(missing));
+        selected
+    }
+
+    /// Link a vector prepared for the metric into the graph.
+    fn insert(&mut self, vector: Vec<f32>, key: blake3::Hash) {
+        let node = self.links.len() as u32;
+        let mut rng = StdRng::seed_from_u64(self.params.seed.wrapping_add(node as u64));
+        let scale = 1.0 / (self.params.m.max(2) as f64).ln();
+        let level = ((-(1.0 - rng.gen::<f64>()).ln() * scale) as usize).min(MAX_LEVEL);
+
+        self.vectors.extend_from_slice(&vector);
+        self.links.push(vec![Vec::new(); level + 1]);
+        self.keys.push(key);
+        self.nodes.insert(key, node);
+        let Some(entry) = self.entry else {
+            self.entry = Some(node);
+            return;
+        };
+
+        let query = ArrayView1::from(&vector);
+        let top = self.links[entry as usize].len() - 1;
+        let mut entry_points = vec![Candidate { distance: self.distance(query, entry), node: entry }];
+        for layer in ((level + 1)..=top).rev() {
+            entry_points = self.search_layer(query, &entry_points, 1, layer);
+        }
+        for layer in (0..=level.min(top)).rev() {
+            let found = self.search_layer(query, &entry_points, self.params.ef_construction, layer);
+            let max_links = if layer == 0 { 2 * self.params.m } else { self.params.m };
+            let neighbors = self.select_neighbors(&found, self.params.m);
+            for neighbor in &neighbors {
+                self.links[*neighbor as usize][layer].push(node);
+                if self.links[*neighbor as usize][layer].len() > max_links {
+                    let vector = self.vector(*neighbor);
+                    let mut candidates: Vec<Candidate> = self.links[*neighbor as usize][layer].iter()
+                        .map(|n| Candidate { distance: self.distance(vector, *n), node: *n })
+                        .collect();
+                    candidates.sort();
+                    self.links[*neighbor as usize][layer] = self.select_neighbors(&candidates, max_links);
+                }
+            }
+            self.links[node as usize][layer] = neighbors;
+            entry_points = found;
+        }
+        if level > top {
+            self.entry = Some(node);
+        }
+    }
+
+    /// Save the index. It is written to a temporary file that replaces `path` when complete.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), CustomError> {
+        self.save_w_cipher(path, None)
+    }
+
+    /// Save the index encrypted with the cipher of an encrypted cache, so the vectors are not
+    /// written in cleartext next to it.
+    pub fn save_w_cipher(&self, path: impl AsRef<Path>, cipher: Option<&CacheCipher>) -> Result<(), CustomError> {
+        let path = path.as_ref();
+        let io_error = |e: std::io::Error| CustomError::general_error(format!("Error writing index {:?}: {e:?}", path));
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(io_error)?;
+        }
+        let bytes = self.to_bytes()?;
+        let bytes = match cipher {
+            Some(cipher) => cipher.encrypt(INDEX_AAD, &bytes)?,
+            None => bytes
+        };
+
+        let temp_path = path.with_extension("hnsw.tmp");
+        std::fs::write(&temp_path, bytes).map_err(io_error)?;
+        std::fs::rename(&temp_path, path).map_err(io_error)?;
+        Ok(())
+    }
+
+    /// The saved layout of the index: the magic, the header length and header, the vectors,
+    /// the keys and the links of each node.
+    fn to_bytes(&self) -> Result<Vec<u8>, CustomError> {
+        let header = serde_json::to_vec(&IndexHeader {
+            version: INDEX_VERSION,
+            metric: self.metric.to_string(),
+            dimensions: self.dimensions,
+            params: self.params,
+            nodes: self.len(),
+            entry: self.entry
+        }).map_err(|e| CustomError::general_error(format!("Error serializing index header: {e:?}")))?;
+
+        let mut bytes = Vec::with_capacity(12 + header.len() + self.vectors.len() * 4 + self.len() * 33);
+        bytes.extend_from_slice(INDEX_MAGIC);
+        bytes.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&header);
+        for value in &self.vectors {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        for key in &self.keys {
+            bytes.extend_from_slice(key.as_bytes());
+        }
+        for layers in &self.links {
+            bytes.push(layers.len() as u8);
+            for neighbors in layers {
+                bytes.extend_from_slice(&(neighbors.len() as u32).to_le_bytes());
+                for neighbor in neighbors {
+                    bytes.extend_from_slice(&neighbor.to_le_bytes());
+                }
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Load an index saved with `save`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, CustomError> {
+        Self::load_w_cipher(path, None)
+    }
+
+    /// Load an index saved with `save_w_cipher`. An encrypted cache only loads indexes that
+    /// are encrypted with its cipher.
+    pub fn load_w_cipher(path: impl AsRef<Path>, cipher: Option<&CacheCipher>) -> Result<Self, CustomError> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)
+            .map_err(|e| CustomError::general_error(format!("Error reading index {:?}: {e:?}", path)))?;
+        let bytes = match (cipher, is_encrypted(&bytes)) {
+            (Some(cipher), true) => cipher.decrypt(INDEX_AAD, &bytes)?,
+            (Some(_), false) => return Err(CustomError::general_error(format!("{:?} is not encrypted.", path))),
+            (None, true) => return Err(CustomError::general_error(format!("{:?} is encrypted.", path))),
+            (None, false) => bytes
+        };
+        Self::from_bytes(path, &bytes)
+    }
+
+    /// Read the saved layout of an index. The sizes in the header are checked against the
+    /// length of the saved bytes before anything is allocated for them.
+    fn from_bytes(path: &Path, bytes: &[u8]) -> Result<Self, CustomError> {
+        let io_error = |e: std::io::Error| CustomError::general_error(format!("Error reading index {:?}: {e:?}", path));
+        let file_length = bytes.len() as u64;
+        let mut reader = Cursor::new(bytes);
+        let read_u32 = |reader: &mut Cursor<&[u8]>| -> Result<u32, CustomError> {
+            let mut bytes = [0u8; 4];
+            reader.read_exact(&mut bytes).map_err(io_error)?;
+            Ok(u32::from_le_bytes(bytes))
+        };
+
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic).map_err(io_error)?;
+        if &magic != INDEX_MAGIC {
+            return Err(CustomError::general_error(format!("{:?} is not an index.", path)));
+        }
+        let header_length = read_u32(&mut reader)? as u64;
+        if 12 + header_length > file_length {
+            return Err(CustomError::general_error(format!("{:?} is truncated.", path)));
+        }
+        let mut header = vec![0u8; header_length as usize];
+        reader.read_exact(&mut header).map_err(io_error)?;
+        let header: IndexHeader = serde_json::from_slice(&header)
+            .map_err(|e| CustomError::general_error(format!("Invalid index header in {:?}: {e:?}", path)))?;
+        if header.version != INDEX_VERSION {
+            return Err(CustomError::general_error(format!(
+                "{:?} has index version {}, expected {INDEX_VERSION}.", path, header.version
+            )));
+        }
+        let metric = DistanceMetric::from_str(&header.metric)
+            .map_err(CustomError::general_error)?;
+
+        // The vectors, keys and a level count for each node must fit in the rest of the bytes
+        let remaining = file_length - 12 - header_length;
+        let vector_bytes = (header.nodes as u64).checked_mul(header.dimensions as u64)
+            .and_then(|values| values.checked_mul(4));
+        let node_bytes = vector_bytes
+            .zip((header.nodes as u64).checked_mul(33))
+            .and_then(|(vectors, nodes)| vectors.checked_add(nodes));
+        if node_bytes.is_none_or(|bytes| bytes > remaining) {
+            return Err(CustomError::general_error(format!(
+                "{:?} declares {} vectors of {} dimensions, the index is too short.",
+                path, header.nodes, header.dimensions
+            )));
+        }
+
+        let mut index = Self::new(metric, header.dimensions, header.params);
+        let mut bytes = vec![0u8; header.nodes * header.dimensions * 4];
+        reader.read_exact(&mut bytes).map_err(io_error)?;
+        index.vectors = bytes.chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        for node in 0..header.nodes {
+            let mut key = [0u8; 32];
+            reader.read_exact(&mut key).map_err(io_error)?;
+            let key = blake3::Hash::from_bytes(key);
+            index.keys.push(key);
+            index.nodes.insert(key, node as u32);
+        }
+        for _ in 0..header.nodes {
+            let mut levels = [0u8; 1];
+            reader.read_exact(&mut levels).map_err(io_error)?;
+            if levels[0] as usize > MAX_LEVEL + 1 {
+                return Err(CustomError::general_error(format!("{:?} has an invalid level.", path)));
+            }
+            let mut layers = Vec::with_capacity(levels[0] as usize);
+            for _ in 0..levels[0] {
+                let count = read_u32(&mut reader)? as usize;
+                if count > header.nodes {
+                    return Err(CustomError::general_error(format!("{:?} has an invalid link count.", path)));
+                }
+                let mut neighbors = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let neighbor = read_u32(&mut reader)?;
+                    if neighbor as usize >= header.nodes {
+                        return Err(CustomError::general_error(format!("{:?} has an invalid link.", path)));
+                    }
+                    neighbors.push(neighbor);
+                }
+                layers.push(neighbors);
+            }
+            index.links.push(layers);
+        }
+        if header.entry.is_some_and(|entry| entry as usize >= header.nodes) {
+            return Err(CustomError::general_error(format!("{:?} has an invalid entry point.", path)));
+        }
+        index.entry = header.entry;
+        Ok(index)
+    }
+}
+
+
+/// Check that there is one key for each row of a dataset.
+fn check_keys(keys: &[blake3::Hash], dataset: &Array2<f32>) -> Result<(), CustomError> {
+    if keys.len() != dataset.nrows() {
+        return Err(CustomError::general_error(format!(
+            "Got {} keys for {} rows.", keys.len(), dataset.nrows()
+        )));
+    }
+    Ok(())
+}
+
+
+/// The rows of a dataset in an `HnswIndex`, for approximate neighbor queries between the rows.
+/// Indexed vectors that are not rows of the dataset are skipped, and rows with the same key are
+/// at a distance of 0.
+#[derive(Debug, Clone)]
+pub struct IndexedRows {
+    index: Arc<HnswIndex>,
+    nodes: Vec<u32>,
+    rows: HashMap<u32, Vec<usize>>
+}
+impl IndexedRows {
+    /// Find the node of the key of each row. Every key must be indexed.
+    pub fn new(index: Arc<HnswIndex>, keys: &[blake3::Hash]) -> Result<Self, CustomError> {
+        let mut nodes = Vec::with_capacity(keys.len());
+        let mut rows: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (row, key) in keys.iter().enumerate() {
+            let node = index.node(key)
+                .ok_or_else(|| CustomError::general_error(format!("Row {row} is not in the index.")))?;
+            nodes.push(node);
+            rows.entry(node).or_default().push(row);
+        }
+        Ok(Self {
+            index,
+            nodes,
+            rows
+        })
+    }
+
+    /// Index the rows of a dataset in a new in-memory index, keyed by their row numbers.
+    pub fn build(dataset: &Array2<f32>, metric: DistanceMetric, params: HnswParams) -> Result<Self, CustomError> {
+        let keys: Vec<blake3::Hash> = (0..dataset.nrows() as u64)
+            .map(|row| blake3::hash(&row.to_le_bytes()))
+            .collect();
+        let mut index = HnswIndex::new(metric, dataset.ncols(), params);
+        index.extend(&keys, dataset)?;
+        Self::new(Arc::new(index), &keys)
+    }
+
+    /// The `k` rows nearest to a query vector and their distances, from the nearest.
+    pub fn search(&self, query: &[f32], k: usize) -> Result<Vec<(usize, f32)>, CustomError> {
+        self.index.check_dimensions(query.len())?;
+        let query = Array2::from_shape_vec((1, query.len()), query.to_vec())
+            .map_err(|e| CustomError::general_error(format!("Invalid query: {e:?}")))?;
+        let query = self.index.metric.prepare(&query);
+        Ok(self.nearest(query.row(0), k, None))
+    }
+
+    /// The `k` rows nearest to a prepared query other than `skip`. Searches with more candidates
+    /// until `k` rows are found or every node reachable in the graph was searched.
+    fn nearest(&self, query: ArrayView1<f32>, k: usize, skip: Option<usize>) -> Vec<(usize, f32)> {
+        let mut ef = self.index.params.ef_search.max(k + 1);
+        loop {
+            let found = self.index.search_prepared(query, ef, ef);
+            let mut neighbors: Vec<(usize, f32)> = Vec::with_capacity(k);
+            for candidate in &found {
+                for other in self.rows.get(&candidate.node).into_iter().flatten() {
+                    if Some(*other) != skip {
+                        neighbors.push((*other, candidate.distance));
+                    }
+                }
+            }
+            if neighbors.len() >= k || found.len() < ef || ef >= self.index.len() {
+                neighbors.truncate(k);
+                return neighbors;
+            }
+            ef *= 2;
+        }
+    }
+}
+impl NeighborSearch for IndexedRows {
+    fn rows(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn metric(&self) -> DistanceMetric {
+        self.index.metric
+    }
+
+    fn knn(&self, row: usize, k: usize) -> Vec<(usize, f32)> {
+        self.nearest(self.index.vector(self.nodes[row]), k, Some(row))
+    }
+
+    /// Search for more neighbors until one is farther than `radius`.
+    fn range(&self, row: usize, radius: f32) -> Vec<(usize, f32)> {
+        let mut k = 32;
+        loop {
+            let neighbors = self.knn(row, k);
+            if neighbors.len() < k || neighbors.last().is_some_and(|(_, d)| *d > radius) {
+                let mut within = vec![(row, 0.0)];
+                within.extend(neighbors.into_iter().filter(|(_, d)| *d <= radius));
+                return within;
+            }
+            k *= 2;
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use std::fmt;
+use std::sync::Arc;
+use ndarray::Array2;
+use crate::ann::{HnswParams, IndexedRows};
+use crate::distance::{DistanceMetric, NeighborSearch};
+use crate::errors::CustomError;
+
+
+/// Datasets with more rows than this find the neighbors of the knn and lof methods with an
+/// in-memory `HnswIndex` when no `NeighborSearch` is set, instead of comparing every pair of rows.
+pub const MAX_EXACT_ROWS: usize = 5000;
+
+
+/// How unusual each row is measured. Higher scores are more anomalous.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AnomalyMethod {
+    /// The distance to the k'th nearest other row.
+    #[default]
+    Knn,
+    /// The Local Outlier Factor, the local density of a row's k nearest rows relative to its
+    /// own. Around 1 for rows as dense as their neighbors and above 1 for outliers.
+    Lof,
+    /// The distance to the nearest cluster medoid.
+    Medoid
+}
+impl std::str::FromStr for AnomalyMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "knn" => Ok(Self::Knn),
+            "lof" => Ok(Self::Lof),
+            "medoid" => Ok(Self::Medoid),
+            _ => Err(format!("invalid anomaly score: {s}"))
+        }
+    }
+}
+impl fmt::Display for AnomalyMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Knn => write!(f, "knn"),
+            Self::Lof => write!(f, "lof"),
+            Self::Medoid => write!(f, "medoid")
+        }
+    }
+}
+
+
+/// Scores each row of a dataset with an `AnomalyMethod` and `k` neighbors.
+#[derive(Debug, Clone)]
+pub struct AnomalyScorer {
+    pub method: AnomalyMethod,
+    pub k: usize,
+    /// Finds the neighbors of the knn and lof methods instead of comparing every pair of rows.
+    pub neighbors: Option<Arc<dyn NeighborSearch>>,
+    /// Larger datasets are indexed to find neighbors when `neighbors` is not set.
+    pub max_exact_rows: usize
+}
+impl Default for AnomalyScorer {
+    fn default() -> Self {
+        Self::new(AnomalyMethod::default(), 5)
+    }
+}
+impl AnomalyScorer {
+    pub fn new(method: AnomalyMethod, k: usize) -> Self {
+        Self {
+            method,
+            k,
+            neighbors: None,
+            max_exact_rows: MAX_EXACT_ROWS
+        }
+    }
+
+    /// Find neighbors with a `NeighborSearch` over the rows of the scored dataset.
+    pub fn with_neighbors(mut self, neighbors: Arc<dyn NeighborSearch>) -> Self {
+        self.neighbors = Some(neighbors);
+        self
+    }
+
+    /// Index datasets with more than `max_exact_rows` rows when no `NeighborSearch` is set.
+    pub fn with_max_exact_rows(mut self, max_exact_rows: usize) -> Self {
+        self.max_exact_rows = max_exact_rows;
+        self
+    }
+
+    /// Score each row with distances in `metric`. `medoids` are the rows of the cluster
+    /// medoids. Without any, such as when every row is noise, the medoid method falls back to
+    /// the knn method.
+    pub fn score(&self, dataset: &Array2<f32>, metric: DistanceMetric, medoids: &[usize]) -> Result<Vec<f32>, CustomError> {
+        let dataset = metric.prepare(dataset);
+        match self.method {
+            AnomalyMethod::Knn => Ok(knn_scores_w_neighbors(&self.k_nearest(&dataset, metric)?)),
+            AnomalyMethod::Lof => Ok(lof_scores_w_neighbors(&self.k_nearest(&dataset, metric)?)),
+            AnomalyMethod::Medoid if medoids.is_empty() => {
+                warn!("There are no clusters for the medoid anomaly score, using the {} score.", AnomalyMethod::Knn);
+                Ok(knn_scores_w_neighbors(&self.k_nearest(&dataset, metric)?))
+            },
+            AnomalyMethod::Medoid => Ok(medoid_scores(&dataset, medoids, metric))
+        }
+    }
+
+    /// The `k` nearest other rows of each row of a prepared dataset.
+    fn k_nearest(&self, dataset: &Array2<f32>, metric: DistanceMetric) -> Result<Vec<Vec<(usize, f32)>>, CustomError> {
+        let Some(search) = &self.neighbors else {
+            if dataset.nrows() <= self.max_exact_rows {
+                return Ok(k_nearest(dataset, self.k, metric));
+            }
+            info!("Indexing {} rows to find their nearest neighbors.", dataset.nrows());
+            let search = IndexedRows::build(dataset, metric, HnswParams::default())?;
+            return Ok((0..dataset.nrows()).map(|row| search.knn(row, self.k)).collect());
+        };
+        if search.rows() != dataset.nrows() || search.metric() != metric {
+            return Err(CustomError::general_error(format!(
+                "The neighbor search has {} {} rows, the dataset has {} {} rows.",
+                search.rows(), search.metric(), dataset.nrows(), metric
+            )));
+        }
+        Ok((0..dataset.nrows()).map(|row| search.knn(row, self.k)).collect())
+    }
+}
+
+
+/// The `k` nearest other rows of each row and their distances, from the nearest. Fewer when
+/// there are not `k` other rows. The rows must be prepared for the metric.
+pub fn k_nearest(dataset: &Array2<f32>, k: usize, metric: DistanceMetric) -> Vec<Vec<(usize, f32)>> {
+    let rows = dataset.nrows();
+    let k = k.min(rows.saturating_sub(1));
+    (0..rows)
+        .map(|i| {
+            if k == 0 {
+                return Vec::new();
+            }
+            let mut distances: Vec<(usize, f32)> = (0..rows)
+                .filter(|j| *j != i)
+                .map(|j| (j, metric.distance(dataset.row(i), dataset.row(j))))
+                .collect();
+            distances.select_nth_unstable_by(k - 1, |a, b| a.1.total_cmp(&b.1));
+            distances.truncate(k);
+            distances.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+            distances
+        })
+        .collect()
+}
+
+
+/// The distance of each row to its `k`th nearest other row. The rows must be prepared for the
+/// metric.
+pub fn knn_scores(dataset: &Array2<f32>, k: usize, metric: DistanceMetric) -> Vec<f32> {
+    knn_scores_w_neighbors(&k_nearest(dataset, k, metric))
+}
+
+
+/// The distance of each row to the last of its nearest other rows, from `k_nearest`.
+pub fn knn_scores_w_neighbors(neighbors: &[Vec<(usize, f32)>]) -> Vec<f32> {
+    neighbors.iter()
+        .map(|neighbors| neighbors.last().map_or(0.0, |(_, d)| *d))
+        .collect()
+}
+
+
+/// The Local Outlier Factor of each row with `k` neighbors. The rows must be prepared for the
+/// metric.
+pub fn lof_scores(dataset: &Array2<f32>, k: usize, metric: DistanceMetric) -> Vec<f32> {
+    lof_scores_w_neighbors(&k_nearest(dataset, k, metric))
+}
+
+
+/// The Local Outlier Factor of each row from its nearest other rows, from `k_nearest`.
+pub fn lof_scores_w_neighbors(neighbors: &[Vec<(usize, f32)>]) -> Vec<f32> {
+    let k_distances: Vec<f64> = neighbors.iter()
+        .map(|n| n.last().map_or(0.0, |(_, d)| *d as f64))
+        .collect();
+
+    // The local reachability density, with a small term so duplicates do not divide by zero
+    let densities: Vec<f64> = neighbors.iter()
+        .map(|n| {
+            if n.is_empty() {
+                return 0.0;
+            }
+            let reachability = n.iter()
+                .map(|(o, d)| k_distances[*o].max(*d as f64))
+                .sum::<f64>() / n.len() as f64;
+            1.0 / (reachability + 1e-10)
+        })
+        .collect();
+
+    neighbors.iter()
+        .zip(&densities)
+        .map(|(n, density)| {
+            if n.is_empty() {
+                return 1.0;
+            }
+            let neighbor_density = n.iter().map(|(o, _)| densities[*o]).sum::<f64>() / n.len() as f64;
+            (neighbor_density / density) as f32
+        })
+        .collect()
+}
+
+
+/// The distance of each row to the nearest of the `medoids` rows. The rows must be prepared
+/// for the metric.
+pub fn medoid_scores(dataset: &Array2<f32>, medoids: &[usize], metric: DistanceMetric) -> Vec<f32> {
+    dataset.rows()
+        .into_iter()
+        .map(|row| {
+            medoids.iter()
+                .map(|m| metric.distance(row, dataset.row(*m)))
+                .fold(f32::INFINITY, f32::min)
+        })
+        .collect()
+}
@@ -1,63 +1,381 @@
 #[macro_use] extern crate log;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::fs::File;
-use polars::prelude::{DataFrameJoinOps, SortMultipleOptions};
-use clap::{Parser, ValueEnum, builder::PossibleValue};
+use std::sync::Arc;
+use std::time::Duration;
+use ndarray::Array2;
+use polars::prelude::{DataFrameJoinOps, NamedFrom, Series, SortMultipleOptions};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap::error::ErrorKind;
 use chrono::Local;
 use fern::Dispatch;
 use log::LevelFilter;
+use console::Term;
 use evtx_clustering::evtx::EvtxHandler;
 use evtx_clustering::errors::CustomError;
 use evtx_clustering::filter::{Filter, FilterRule};
-use evtx_clustering::embedding::EmbeddingsHandler;
-use evtx_clustering::cluster::get_cluster_mapping;
+use evtx_clustering::embedding::{EmbeddingProgress, EmbeddingsHandler, OfflineMissPolicy};
+use evtx_clustering::cache::{CacheNamespace, CacheRecord, EmbeddingCache, SCHEMA_VERSION, VectorDType};
+use evtx_clustering::encryption::{CacheCipher, KeySource};
+use evtx_clustering::provider::{EmbeddingProvider, OpenAIProvider, OpenAICompatibleProvider, AzureOpenAIProvider};
+use evtx_clustering::lexical::LexicalEmbedder;
+use evtx_clustering::retry::RetryPolicy;
+use evtx_clustering::tokens::LongInputStrategy;
+use evtx_clustering::usage::{Budget, PriceTable};
+use evtx_clustering::template::InputTemplate;
+use evtx_clustering::cluster::{ClusterOptions, get_cluster_mapping, get_embedding_matrix};
+use evtx_clustering::anomaly::{AnomalyMethod, AnomalyScorer};
+use evtx_clustering::clusterer::{
+    AgglomerativeClusterer, ClusterAlgorithm, Clusterer, DbscanClusterer, GaussianMixtureClusterer,
+    HdbscanClusterer, KMeansClusterer, NeighborDbscanClusterer, OpticsClusterer
+};
+use evtx_clustering::distance::{DistanceMetric, NeighborSearch, PairwiseDistances};
+use evtx_clustering::ann::{HnswIndex, HnswParams, IndexedRows};
+use evtx_clustering::hierarchy::Linkage;
+use evtx_clustering::tolerance::KDistanceAnalysis;
+use evtx_clustering::sweep::{sweep, sweep_dataframe};
+use evtx_clustering::features::get_feature_dataframe;
 use polars::prelude::{SerWriter, CsvWriter};
-use openai_api_rs::v1::common::*;
+use indicatif::{ProgressBar, ProgressStyle};
 
 static VERSION: &str = env!("CARGO_PKG_VERSION");
+/// The columns that are always extracted from each record.
+static RECORD_COLUMNS: [&str; 5] = ["Timestamp", "Computer", "Provider", "EventID", "CommandLine"];
+/// The column of the rendered input template.
+static INPUT_COLUMN: &str = "EmbeddingInput";
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
-enum EmbeddingModel {
-    TextEmbedding3Small,
-    TextEmbedding3Large,
-    TextEmbeddingAda002,
+/// The service used to create embeddings.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum EmbeddingBackend {
+    /// The OpenAI API.
+    Openai,
+    /// An Azure OpenAI deployment.
+    Azure,
+    /// Any server implementing the OpenAI embeddings API (Ollama, vLLM, LocalAI, TEI).
+    OpenaiCompatible,
+    /// Offline hashed character n-gram and token TF-IDF vectors, weighted by the commands of the
+    /// run and not cached.
+    Lexical,
+    /// A local sentence-transformer model run on the CPU (requires the local-models feature).
+    Local,
 }
-impl ValueEnum for EmbeddingModel {
-    fn value_variants<'a>() -> &'a [Self] {
-        &[Self::TextEmbedding3Small, Self::TextEmbedding3Large, Self::TextEmbeddingAda002]
-    }
 
-    fn to_possible_value(&self) -> Option<PossibleValue> {
-        Some(match self {
-            Self::TextEmbedding3Small => PossibleValue::new(TEXT_EMBEDDING_3_SMALL).help("Model"),
-            Self::TextEmbedding3Large => PossibleValue::new(TEXT_EMBEDDING_3_LARGE).help("Model"),
-            Self::TextEmbeddingAda002 => PossibleValue::new(TEXT_EMBEDDING_ADA_002).help("Model"),
-        })
+
+/// Commands other than clustering.
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Inspect and maintain an embeddings cache.
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+    /// Run DBSCAN over a grid of tolerances and min points on the vectors of a cache and report
+    /// the clusters, noise ratio, largest cluster and silhouette of each combination.
+    Sweep(SweepArgs),
+    /// Find the cached inputs most similar to a cached input with the approximate
+    /// nearest-neighbor index of the cache namespace.
+    Similar(SimilarArgs),
+}
+
+
+/// Embeddings cache operations. Operations act on one namespace (provider/model/dimensions)
+/// of a cache and `--namespace` is needed when a cache holds more than one.
+#[derive(Subcommand, Debug)]
+enum CacheAction {
+    /// List the namespaces of a cache.
+    Namespaces {
+        /// The embeddings cache directory.
+        cache: PathBuf,
+    },
+    /// Show the entries, sizes, model and dimensions of a cache.
+    Stats {
+        /// The embeddings cache directory.
+        cache: PathBuf,
+        /// The namespace to use.
+        #[arg(long, required=false)]
+        namespace: Option<String>,
+    },
+    /// Check that every cached vector can be read and has the cache dimensions.
+    Verify {
+        /// The embeddings cache directory.
+        cache: PathBuf,
+        /// The namespace to use.
+        #[arg(long, required=false)]
+        namespace: Option<String>,
+    },
+    /// Export the input text, hash and vector of every entry to a JSONL or .parquet file.
+    Export {
+        /// The embeddings cache directory.
+        cache: PathBuf,
+        /// The output file. Files ending in .parquet are written as Parquet, others as JSONL.
+        output: PathBuf,
+        /// The namespace to use.
+        #[arg(long, required=false)]
+        namespace: Option<String>,
+    },
+    /// Import entries from a JSONL or .parquet file created by export.
+    Import {
+        /// The embeddings cache directory.
+        cache: PathBuf,
+        /// The file to import.
+        input: PathBuf,
+        /// The provider of the namespace to import into.
+        #[arg(long, required=false, default_value="openai")]
+        provider: String,
+        /// The model of the namespace to import into.
+        #[arg(long, required=true)]
+        embedding_model: String,
+        /// The dimensions of the namespace to import into.
+        #[arg(long, required=false)]
+        embedding_dimensions: Option<i32>,
+        /// The input template of the namespace to import into.
+        #[arg(long, required=false, default_value="{CommandLine}")]
+        input_template: String,
+    },
+    /// Copy the entries of another cache with the same model and dimensions.
+    Merge {
+        /// The embeddings cache directory to merge into.
+        cache: PathBuf,
+        /// The embeddings cache directory to merge from.
+        other: PathBuf,
+        /// The namespace to use in both caches.
+        #[arg(long, required=false)]
+        namespace: Option<String>,
+        /// Open the other cache with a passphrase instead of the key of the cache. The passphrase
+        /// is read from OTHER_CACHE_PASSPHRASE, or prompted for when the flag is given.
+        #[arg(long, required=false, conflicts_with_all=["other_keyfile", "other_unencrypted"],
+            env="OTHER_CACHE_PASSPHRASE", hide_env_values=true, num_args=0, default_missing_value="")]
+        other_passphrase: Option<String>,
+        /// Open the other cache with a keyfile instead of the key of the cache.
+        #[arg(long, required=false, conflicts_with="other_unencrypted")]
+        other_keyfile: Option<PathBuf>,
+        /// The other cache is not encrypted.
+        #[arg(long, required=false)]
+        other_unencrypted: bool,
+    },
+    /// Migrate a cache from an older schema, which is only read until it is migrated.
+    Migrate {
+        /// The embeddings cache directory.
+        cache: PathBuf,
+    },
+    /// Remove entries that are not one of the inputs in a file.
+    Prune {
+        /// The embeddings cache directory.
+        cache: PathBuf,
+        /// A file with one input per line.
+        #[arg(long, required=true)]
+        inputs: PathBuf,
+        /// The namespace to use.
+        #[arg(long, required=false)]
+        namespace: Option<String>,
+    },
+}
+impl CacheAction {
+    /// Run the cache operation.
+    fn run(&self, key: Option<&KeySource>) -> Result<(), CustomError> {
+        match self {
+            Self::Namespaces { cache } => {
+                for namespace in EmbeddingCache::namespaces(cache)? {
+                    println!("{}", namespace.name());
+                }
+            },
+            Self::Stats { cache, namespace } => {
+                let stats = EmbeddingCache::open_existing_w_key(cache, namespace.as_deref(), key)?.stats()?;
+                println!("{}", serde_json::to_string_pretty(&stats)
+                    .map_err(|e| CustomError::general_error(format!("{e:?}")))?);
+            },
+            Self::Verify { cache, namespace } => {
+                let report = EmbeddingCache::open_existing_w_key(cache, namespace.as_deref(), key)?.verify()?;
+                for (key, problem) in &report.invalid {
+                    println!("{key}: {problem}");
+                }
+                println!("{} entries checked, {} invalid.", report.checked, report.invalid.len());
+                if !report.invalid.is_empty() {
+                    return Err(CustomError::cache_error(format!("{:?} has invalid entries.", cache)));
+                }
+            },
+            Self::Export { cache, output, namespace } => {
+                let count = EmbeddingCache::open_existing_w_key(cache, namespace.as_deref(), key)?.export(output)?;
+                println!("Exported {count} entries to {:?}.", output);
+            },
+            Self::Import { cache, input, provider, embedding_model, embedding_dimensions, input_template } => {
+                let mut namespace = CacheNamespace::new(provider, embedding_model, *embedding_dimensions);
+                let template = InputTemplate::parse(input_template)?;
+                if !template.is_default() {
+                    namespace = namespace.with_template(template.to_string());
+                }
+                let count = EmbeddingCache::open_w_key(cache, &namespace, key)?.import(input)?;
+                println!("Imported {count} entries from {:?} into {}.", input, namespace.name());
+            },
+            Self::Merge { cache, other, namespace, other_passphrase, other_keyfile, other_unencrypted } => {
+                let other_key = match (other_passphrase, other_keyfile, other_unencrypted) {
+                    (None, None, false) => key.cloned(),
+                    (None, None, true) => None,
+                    _ => key_source(other_passphrase.as_deref(), other_keyfile.as_deref(), "Other cache passphrase: ")?
+                };
+                let count = EmbeddingCache::open_existing_w_key(cache, namespace.as_deref(), key)?
+                    .merge(&EmbeddingCache::open_existing_w_key(other, namespace.as_deref(), other_key.as_ref())?)?;
+                println!("Merged {count} entries from {:?}.", other);
+            },
+            Self::Migrate { cache } => {
+                let count = EmbeddingCache::upgrade(cache)?;
+                println!("Migrated {:?} to schema version {SCHEMA_VERSION}, {count} entries converted.", cache);
+            },
+            Self::Prune { cache, inputs, namespace } => {
+                let inputs = std::fs::read_to_string(inputs)
+                    .map_err(|e| CustomError::general_error(format!("Error reading {:?}: {e:?}", inputs)))?
+                    .lines()
+                    .map(|l| l.to_string())
+                    .collect();
+                let count = EmbeddingCache::open_existing_w_key(cache, namespace.as_deref(), key)?.prune(&inputs)?;
+                println!("Pruned {count} entries.");
+            },
+        }
+        Ok(())
     }
 }
-impl std::fmt::Display for EmbeddingModel {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.to_possible_value()
-            .expect("no values are skipped")
-            .get_name()
-            .fmt(f)
+
+
+/// The cache and parameter grid of a sweep.
+#[derive(Args, Debug)]
+struct SweepArgs {
+    /// The embeddings cache directory.
+    cache: PathBuf,
+    /// The namespace to use.
+    #[arg(long, required=false)]
+    namespace: Option<String>,
+    /// A file with one input per line. Only the vectors of these inputs are clustered, all
+    /// vectors of the namespace are clustered when not set.
+    #[arg(long, required=false)]
+    inputs: Option<PathBuf>,
+    /// The tolerances to try.
+    #[arg(long, required=false, value_delimiter=',', default_value="0.1,0.2,0.3,0.4,0.5")]
+    tolerances: Vec<f32>,
+    /// The min points to try.
+    #[arg(long, required=false, value_delimiter=',', default_value="2,3,5,10")]
+    min_points: Vec<usize>,
+    /// How distances between embeddings are measured.
+    #[arg(long, required=false, default_value="l2", value_parser=["cosine", "l2", "normalized-l2"])]
+    distance_metric: String,
+    /// A CSV file to write the table to.
+    #[arg(long, required=false)]
+    output: Option<PathBuf>,
+}
+impl SweepArgs {
+    /// Compute the distances between the cached vectors once and cluster them with each combination.
+    fn run(&self, key: Option<&KeySource>) -> Result<(), CustomError> {
+        let metric = DistanceMetric::from_str(&self.distance_metric)
+            .map_err(CustomError::general_error)?;
+        let mut records = EmbeddingCache::open_existing_w_key(&self.cache, self.namespace.as_deref(), key)?
+            .records()?;
+        if let Some(inputs) = &self.inputs {
+            let inputs: HashSet<String> = std::fs::read_to_string(inputs)
+                .map_err(|e| CustomError::general_error(format!("Error reading {:?}: {e:?}", inputs)))?
+                .lines()
+                .map(|l| l.to_string())
+                .collect();
+            records.retain(|r| r.input.as_ref().is_some_and(|i| inputs.contains(i)));
+        }
+        let dataset = record_matrix(&records)?;
+
+        info!("Computing the {metric} distances of {} vectors.", dataset.nrows());
+        let distances = PairwiseDistances::new(&dataset, metric);
+        let results = sweep(&distances, &self.tolerances, &self.min_points);
+        let mut df = sweep_dataframe(&results)?;
+
+        if let Some(output) = &self.output {
+            let mut output_fh: File = File::create(output)
+                .map_err(|e| CustomError::general_error(format!("Error creating {:?}: {e:?}", output)))?;
+            CsvWriter::new(&mut output_fh)
+                .include_header(true)
+                .finish(&mut df)?;
+        }
+        println!("{df}");
+        Ok(())
     }
 }
-impl std::str::FromStr for EmbeddingModel {
-    type Err = String;
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        for variant in Self::value_variants() {
-            if variant.to_possible_value().unwrap().matches(s, false) {
-                return Ok(*variant);
-            }
+
+/// The cache and query of a similarity search.
+#[derive(Args, Debug)]
+struct SimilarArgs {
+    /// The embeddings cache directory.
+    cache: PathBuf,
+    /// The namespace to use.
+    #[arg(long, required=false)]
+    namespace: Option<String>,
+    /// The cached input to find similar inputs to.
+    #[arg(long, required=true)]
+    input: String,
+    /// The number of similar inputs to list.
+    #[arg(short, long, required=false, default_value="10")]
+    k: usize,
+    /// How distances between embeddings are measured.
+    #[arg(long, required=false, default_value="l2", value_parser=["cosine", "l2", "normalized-l2"])]
+    distance_metric: String,
+}
+impl SimilarArgs {
+    /// Add the cached vectors that are not indexed yet to the index and search it.
+    fn run(&self, key: Option<&KeySource>, params: HnswParams, index_path: Option<&Path>) -> Result<(), CustomError> {
+        let metric = DistanceMetric::from_str(&self.distance_metric)
+            .map_err(CustomError::general_error)?;
+        let cache = EmbeddingCache::open_existing_w_key(&self.cache, self.namespace.as_deref(), key)?;
+        let records = cache.records()?;
+        let row = records.iter()
+            .position(|r| r.input.as_deref() == Some(self.input.as_str()))
+            .ok_or_else(|| CustomError::general_error(format!("{:?} is not in the cache.", self.input)))?;
+        let dataset = record_matrix(&records)?;
+        let keys = records.iter()
+            .map(CacheRecord::key)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let index_path = index_path.map(Path::to_path_buf)
+            .unwrap_or_else(|| HnswIndex::path_for(&self.cache, cache.namespace(), metric));
+        let neighbors = indexed_rows(&index_path, &keys, &dataset, metric, params, cache.cipher())?;
+        for (other, distance) in neighbors.knn(row, self.k) {
+            println!("{distance:.6}\t{}", records[other].input.as_deref().unwrap_or(""));
         }
-        Err(format!("invalid variant: {s}"))
+        Ok(())
     }
 }
 
 
+/// The matrix of the vectors of cache records, one row per record.
+fn record_matrix(records: &[CacheRecord]) -> Result<Array2<f32>, CustomError> {
+    let dimensions = records.first()
+        .map(|r| r.vector.len())
+        .ok_or_else(|| CustomError::general_error("There are no vectors to cluster."))?;
+    let values: Vec<f32> = records.iter()
+        .flat_map(|r| r.vector.iter().copied())
+        .collect();
+    Array2::from_shape_vec((records.len(), dimensions), values)
+        .map_err(|e| CustomError::general_error(format!("Vectors have different dimensions: {e:?}")))
+}
+
+
+/// Load the index at `path`, add the rows of the dataset whose cache entry `keys` are not
+/// indexed yet and save it when rows were added. The index of an encrypted cache is encrypted
+/// with its `cipher`.
+fn indexed_rows(
+    path: &Path,
+    keys: &[blake3::Hash],
+    dataset: &Array2<f32>,
+    metric: DistanceMetric,
+    params: HnswParams,
+    cipher: Option<&CacheCipher>
+) -> Result<IndexedRows, CustomError> {
+    let mut index = HnswIndex::load_or_new_w_cipher(path, metric, dataset.ncols(), params, cipher)?;
+    let added = index.extend(keys, dataset)?;
+    if added > 0 {
+        info!("Added {added} vectors to the index {:?}, it has {}.", path, index.len());
+        index.save_w_cipher(path, cipher)?;
+    }
+    IndexedRows::new(Arc::new(index), keys)
+}
+
+
 /// A tool that can extract commands from EVTX files and summarize clusters.
 /// Currently this tool only extracts commands that are found in the Event.EventData.CommandLine
 /// attribute.
@@ -65,37 +383,303 @@ impl std::str::FromStr for EmbeddingModel {
 #[command(
     author = "Matthew Seyer",
     version=VERSION,
+    subcommand_negates_reqs=true,
 )]
 struct App {
+    #[command(subcommand)]
+    command: Option<Command>,
     /// The source that contains EVTX records.
     #[arg(short, long, required=true)]
-    source: PathBuf,
+    source: Option<PathBuf>,
     /// The csv output file to write output to.
     #[arg(short, long, required=true)]
-    csv_output: PathBuf,
-    /// The embeddings cache directory.
+    csv_output: Option<PathBuf>,
+    /// The embeddings cache directory. One directory holds the embeddings of every backend,
+    /// model and dimensions.
     #[arg(short, long, required=true)]
-    cache: PathBuf,
-    /// OpenAI API token. If not used, the OPENAI_KEY env var will be used or an error will be thrown.
+    cache: Option<PathBuf>,
+    /// Encrypt the cache with a key derived from a passphrase. Needed to open an encrypted cache.
+    /// The passphrase is read from CACHE_PASSPHRASE, or prompted for when the flag is given.
+    #[arg(long, required=false, global=true, conflicts_with="cache_keyfile",
+        env="CACHE_PASSPHRASE", hide_env_values=true, num_args=0, default_missing_value="")]
+    cache_passphrase: Option<String>,
+    /// Encrypt the cache with a key derived from the contents of this file. Needed to open a
+    /// cache encrypted with the keyfile.
+    #[arg(long, required=false, global=true)]
+    cache_keyfile: Option<PathBuf>,
+    /// How vectors are stored in the cache. f16 halves the cache size at a small loss of precision.
+    #[arg(long, required=false, default_value="f32", value_parser=["f32", "f16"])]
+    cache_dtype: String,
+    /// The embedding service to use. The lexical vectors are weighted by the commands of the run,
+    /// so they are not cached and the cache commands do not see them.
+    #[arg(long, required=false, default_value="openai")]
+    embedding_backend: EmbeddingBackend,
+    /// API token. If not used, the OPENAI_KEY env var (AZURE_OPENAI_KEY for azure) will be used.
+    /// An error will be thrown if openai or azure has no token.
     #[arg(long, required=false)]
     openai_token: Option<String>,
-    /// Embedding model selection.
+    /// Base URL of the API, e.g. http://localhost:11434/v1 for openai-compatible or
+    /// https://my-resource.openai.azure.com for azure.
+    #[arg(long, required=false)]
+    api_base_url: Option<String>,
+    /// The Azure OpenAI deployment name.
+    #[arg(long, required=false)]
+    azure_deployment: Option<String>,
+    /// The Azure OpenAI api-version.
+    #[arg(long, required=false, default_value="2024-02-01")]
+    azure_api_version: String,
+    /// Embedding model name.
     #[arg(long, required=false, default_value="text-embedding-3-small")]
-    embedding_model: EmbeddingModel,
-    /// Embedding model selection.
+    embedding_model: String,
+    /// Directory with the config.json, tokenizer.json and model.safetensors of a
+    /// sentence-transformer model for the local backend.
+    #[arg(long, required=false)]
+    local_model_dir: Option<PathBuf>,
+    /// Embedding dimensions. The lexical backend uses 512 when not set.
     #[arg(long, required=false)]
     embedding_dimensions: Option<i32>,
-    /// Set the clustering tolerance threshold.
+    /// The maximum number of embedding requests sent in parallel.
+    #[arg(long, required=false, default_value="10")]
+    parallel_requests: usize,
+    /// The lowest number of parallel requests used when the API is throttling requests.
+    #[arg(long, required=false, default_value="1")]
+    min_parallel_requests: usize,
+    /// The number of times a request is retried after a rate-limit, server error or timeout.
+    #[arg(long, required=false, default_value="5")]
+    max_retries: usize,
+    /// The base delay in milliseconds of the exponential retry backoff.
+    #[arg(long, required=false, default_value="500")]
+    retry_base_delay_ms: u64,
+    /// The maximum delay in milliseconds between retries.
+    #[arg(long, required=false, default_value="60000")]
+    retry_max_delay_ms: u64,
+    /// The timeout in seconds of each embedding request.
+    #[arg(long, required=false, default_value="120")]
+    request_timeout: u64,
+    /// How inputs longer than --max-input-tokens are embedded.
+    #[arg(long, required=false, default_value="truncate", value_parser=["truncate", "chunk"])]
+    long_input_strategy: String,
+    /// The token limit (cl100k_base) of a single embedding input. Not used by the lexical and local backends.
+    #[arg(long, required=false, default_value="8191")]
+    max_input_tokens: usize,
+    /// The maximum number of inputs sent in a single embeddings request.
+    #[arg(long, required=false, default_value="256")]
+    batch_size: usize,
+    /// The maximum number of tokens sent in a single embeddings request.
+    #[arg(long, required=false, default_value="100000")]
+    batch_tokens: usize,
+    /// Stop sending embedding requests once this many prompt tokens have been used.
+    #[arg(long, required=false)]
+    max_tokens: Option<u64>,
+    /// Stop sending embedding requests once the estimated cost in USD reaches this amount.
+    #[arg(long, required=false)]
+    max_cost: Option<f64>,
+    /// A JSON file of model names to USD per million tokens, added to the built-in OpenAI prices.
+    #[arg(long, required=false)]
+    price_table: Option<PathBuf>,
+    /// Extract the commands, check the cache and print the expected requests, tokens, cost and
+    /// runtime without calling the embedding API.
+    #[arg(long, required=false)]
+    dry_run: bool,
+    /// Only use embeddings from the cache and never call the embedding API.
+    #[arg(long, required=false)]
+    offline: bool,
+    /// How --offline handles commands that are not cached: fail the run, skip them (they are left
+    /// unclustered) or embed them with the local model of --local-model-dir, or lexical vectors
+    /// of --embedding-dimensions (default 512) when no model is given.
+    #[arg(long, required=false, default_value="fail", value_parser=["fail", "skip", "fallback"])]
+    offline_miss_policy: String,
+    /// Do not show the embedding progress bar.
+    #[arg(long, required=false)]
+    no_progress: bool,
+    /// The text embedded for each record, rendered from record columns, e.g.
+    /// "{ParentImage} -> {Image}: {CommandLine} (user {User})". Fields that are not
+    /// Timestamp, Computer, Provider, EventID or CommandLine are read from Event.EventData.
+    /// Each template has its own cache namespace.
+    #[arg(long, required=false, default_value="{CommandLine}")]
+    input_template: String,
+    /// The clustering algorithm.
+    #[arg(long, required=false, default_value="dbscan", value_parser=["dbscan", "hdbscan", "optics", "kmeans", "gmm", "agglomerative"])]
+    cluster_algorithm: String,
+    /// How distances between embeddings are measured. cosine is one minus the cosine
+    /// similarity, normalized-l2 is the L2 distance of unit length vectors.
+    #[arg(long, required=false, default_value="l2", value_parser=["cosine", "l2", "normalized-l2"])]
+    distance_metric: String,
+    /// Set the clustering tolerance threshold. The neighborhood radius of dbscan and the radius
+    /// optics clusters are extracted at, as a --distance-metric distance.
     #[arg(long, required=false, default_value="0.5")]
     cluster_tolerance: f32,
-    /// Set the cluster grouping threshold.
+    /// Set the cluster grouping threshold. The minimum points of a dbscan or optics cluster.
     #[arg(long, required=false, default_value="2")]
     cluster_grouping: usize,
+    /// Set --cluster-tolerance to the knee of the distances of each embedding to its
+    /// --cluster-grouping nearest neighbor (counting itself). Used by dbscan and optics.
+    #[arg(long, required=false)]
+    auto_tolerance: bool,
+    /// The CSV file --auto-tolerance writes the k-distance curve to. Defaults to the csv output
+    /// with a .k-distance.csv extension.
+    #[arg(long, required=false)]
+    k_distance_output: Option<PathBuf>,
+    /// The largest neighborhood radius of the optics ordering. Unbounded when not set.
+    #[arg(long, required=false)]
+    optics_max_tolerance: Option<f32>,
+    /// The smallest hdbscan cluster.
+    #[arg(long, required=false, default_value="5")]
+    min_cluster_size: usize,
+    /// The neighborhood size hdbscan estimates density with. Defaults to --min-cluster-size.
+    #[arg(long, required=false)]
+    min_samples: Option<usize>,
+    /// The number of kmeans, gmm or agglomerative clusters. Required for kmeans and gmm.
+    #[arg(long, required=false)]
+    cluster_count: Option<usize>,
+    /// The maximum kmeans or gmm iterations. Defaults to 300 for kmeans and 100 for gmm.
+    #[arg(long, required=false)]
+    cluster_max_iterations: Option<u64>,
+    /// The random seed of kmeans and gmm.
+    #[arg(long, required=false, default_value="42")]
+    cluster_seed: u64,
+    /// How agglomerative clustering measures the distance between clusters.
+    #[arg(long, required=false, default_value="ward", value_parser=["ward", "complete", "average", "single"])]
+    cluster_linkage: String,
+    /// Stop agglomerative merges farther apart than this distance. Used when --cluster-count
+    /// is not set.
+    #[arg(long, required=false)]
+    cluster_distance_threshold: Option<f32>,
+    /// The JSON file the cluster quality metrics are written to. Defaults to the csv output with
+    /// a .metrics.json extension.
+    #[arg(long, required=false)]
+    metrics_output: Option<PathBuf>,
+    /// The number of exemplars of each cluster: the medoid and the commands nearest the cluster
+    /// centroid, then the most different commands from farthest-point sampling.
+    #[arg(long, required=false, default_value="5")]
+    exemplars: usize,
+    /// The CSV file the cluster summary, with the medoid and exemplars of each cluster, is written
+    /// to. Defaults to the csv output with a .summary.csv extension.
+    #[arg(long, required=false)]
+    summary_output: Option<PathBuf>,
+    /// Find neighbors with an approximate nearest-neighbor (HNSW) index instead of comparing every
+    /// pair of embeddings. Used by dbscan, --auto-tolerance and the knn and lof anomaly scores.
+    /// The index is saved next to the cache and only new embeddings are added to it.
+    #[arg(long, required=false, conflicts_with="feature_weight")]
+    ann_index: bool,
+    /// The index file. Defaults to a file of the namespace and distance metric in the cache
+    /// directory name with an .ann extension.
+    #[arg(long, required=false, global=true)]
+    ann_index_path: Option<PathBuf>,
+    /// The neighbors of each embedding in the index graph.
+    #[arg(long, required=false, global=true, default_value="16")]
+    ann_m: usize,
+    /// The candidates considered when adding an embedding to the index.
+    #[arg(long, required=false, global=true, default_value="100")]
+    ann_ef_construction: usize,
+    /// The candidates considered by an index query. Higher finds more of the true neighbors.
+    #[arg(long, required=false, global=true, default_value="64")]
+    ann_ef_search: usize,
+    /// How the anomaly_score column is computed: the distance to the --anomaly-neighbors nearest
+    /// command, its Local Outlier Factor or the distance to the nearest cluster medoid.
+    #[arg(long, required=false, default_value="knn", value_parser=["knn", "lof", "medoid"])]
+    anomaly_score: String,
+    /// The number of neighbors of the knn and lof anomaly scores.
+    #[arg(long, required=false, default_value="5")]
+    anomaly_neighbors: usize,
+    /// Add lexical feature columns (length, entropy, LOLBin, suspicious flags, etc.) to the output.
+    #[arg(long, required=false)]
+    lexical_features: bool,
+    /// Append the standardized lexical features, scaled by this weight, to the embedding vectors
+    /// used for clustering.
+    #[arg(long, required=false)]
+    feature_weight: Option<f32>,
+    /// Flatten all of Event.EventData and the --system-keys of Event.System into output columns.
+    #[arg(long, required=false)]
+    flatten_event_data: bool,
+    /// The Event.System keys to include when flattening.
+    #[arg(long, required=false, value_delimiter=',', default_value="EventID,EventRecordID,Computer,Channel,Provider,TimeCreated")]
+    system_keys: Vec<String>,
     /// The logging level to use.
     #[arg(long, required=false, default_value="Info", value_parser=["Off", "Error", "Warn", "Info", "Debug", "Trace"])]
     logging: String,
 }
 impl App {
+    /// Create the clusterer of --cluster-algorithm with its parameters and a tolerance.
+    fn clusterer(&self, tolerance: f32) -> Result<Box<dyn Clusterer>, CustomError> {
+        self.clusterer_w_neighbors(tolerance, None)
+    }
+
+    /// Create the clusterer of --cluster-algorithm. dbscan finds neighbors with `search` when set.
+    fn clusterer_w_neighbors(&self, tolerance: f32, search: Option<Arc<dyn NeighborSearch>>) -> Result<Box<dyn Clusterer>, CustomError> {
+        let algorithm = ClusterAlgorithm::from_str(&self.cluster_algorithm)
+            .map_err(CustomError::general_error)?;
+        let cluster_count = || self.cluster_count.ok_or_else(|| CustomError::general_error(
+            format!("--cluster-count is required for {algorithm}.")
+        ));
+
+        let clusterer: Box<dyn Clusterer> = match algorithm {
+            ClusterAlgorithm::Dbscan => match search {
+                Some(search) => Box::new(NeighborDbscanClusterer::new(self.cluster_grouping, tolerance, search)),
+                None => Box::new(DbscanClusterer::new(self.cluster_grouping, tolerance))
+            },
+            ClusterAlgorithm::Optics => {
+                let mut clusterer = OpticsClusterer::new(self.cluster_grouping, tolerance);
+                if let Some(max_tolerance) = self.optics_max_tolerance {
+                    clusterer = clusterer.with_max_tolerance(max_tolerance);
+                }
+                Box::new(clusterer)
+            },
+            ClusterAlgorithm::Hdbscan => {
+                let mut clusterer = HdbscanClusterer::new(self.min_cluster_size);
+                if let Some(min_samples) = self.min_samples {
+                    clusterer = clusterer.with_min_samples(min_samples);
+                }
+                Box::new(clusterer)
+            },
+            ClusterAlgorithm::KMeans => {
+                let mut clusterer = KMeansClusterer::new(cluster_count()?)
+                    .with_seed(self.cluster_seed);
+                if let Some(max_iterations) = self.cluster_max_iterations {
+                    clusterer = clusterer.with_max_iterations(max_iterations);
+                }
+                Box::new(clusterer)
+            },
+            ClusterAlgorithm::GaussianMixture => {
+                let mut clusterer = GaussianMixtureClusterer::new(cluster_count()?)
+                    .with_seed(self.cluster_seed);
+                if let Some(max_iterations) = self.cluster_max_iterations {
+                    clusterer = clusterer.with_max_iterations(max_iterations);
+                }
+                Box::new(clusterer)
+            },
+            ClusterAlgorithm::Agglomerative => {
+                let linkage = Linkage::from_str(&self.cluster_linkage)
+                    .map_err(CustomError::general_error)?;
+                let mut clusterer = AgglomerativeClusterer::new(linkage);
+                match (self.cluster_count, self.cluster_distance_threshold) {
+                    (Some(count), _) => clusterer = clusterer.with_n_clusters(count),
+                    (None, Some(threshold)) => clusterer = clusterer.with_distance_threshold(threshold),
+                    (None, None) => return Err(CustomError::general_error(
+                        "--cluster-count or --cluster-distance-threshold is required for agglomerative."
+                    ))
+                }
+                Box::new(clusterer)
+            }
+        };
+        Ok(clusterer)
+    }
+
+    /// The graph parameters of the approximate nearest-neighbor index.
+    fn hnsw_params(&self) -> HnswParams {
+        HnswParams {
+            m: self.ann_m,
+            ef_construction: self.ann_ef_construction,
+            ef_search: self.ann_ef_search,
+            ..HnswParams::default()
+        }
+    }
+
+    /// The cache encryption key source.
+    fn cache_key(&self) -> Result<Option<KeySource>, CustomError> {
+        key_source(self.cache_passphrase.as_deref(), self.cache_keyfile.as_deref(), "Cache passphrase: ")
+    }
+
     /// Set logging
     fn set_logging(&self) -> Result<(), CustomError> {
         let level = self.logging.as_str();
@@ -131,18 +715,96 @@ impl App {
 }
 
 
+/// The error of the local backend in builds without the local-models feature.
+static LOCAL_MODELS_REQUIRED: &str = "The local backend requires building with --features local-models.";
+
+/// Load the local sentence-transformer model.
+#[cfg(feature = "local-models")]
+fn local_provider(model_dir: &Option<PathBuf>) -> Result<Arc<dyn EmbeddingProvider>, CustomError> {
+    let model_dir = model_dir.as_ref()
+        .ok_or_else(|| CustomError::general_error("--local-model-dir is required for local."))?;
+    let embedder = evtx_clustering::local::LocalModelEmbedder::from_dir(model_dir)?;
+    Ok(Arc::new(embedder))
+}
+
+#[cfg(not(feature = "local-models"))]
+fn local_provider(_model_dir: &Option<PathBuf>) -> Result<Arc<dyn EmbeddingProvider>, CustomError> {
+    Err(CustomError::general_error(LOCAL_MODELS_REQUIRED))
+}
+
+
+/// The key source of a passphrase or keyfile. An empty passphrase, from a passphrase flag
+/// without its environment variable, is read from the terminal.
+fn key_source(passphrase: Option<&str>, keyfile: Option<&Path>, prompt: &str) -> Result<Option<KeySource>, CustomError> {
+    match (passphrase, keyfile) {
+        (Some(""), _) => {
+            let term = Term::stderr();
+            term.write_str(prompt)
+                .and_then(|_| term.read_secure_line())
+                .map(|passphrase| Some(KeySource::Passphrase(passphrase)))
+                .map_err(|e| CustomError::general_error(format!("Error reading the passphrase: {e:?}")))
+        },
+        (Some(passphrase), _) => Ok(Some(KeySource::Passphrase(passphrase.to_string()))),
+        (None, Some(keyfile)) => Ok(Some(KeySource::Keyfile(keyfile.to_path_buf()))),
+        (None, None) => Ok(None)
+    }
+}
+
+
 #[tokio::main]
 async fn main() {
     let app: App = App::parse();
+    let uses_local_model = app.embedding_backend == EmbeddingBackend::Local || app.local_model_dir.is_some();
+    if !cfg!(feature = "local-models") && uses_local_model {
+        App::command().error(ErrorKind::InvalidValue, LOCAL_MODELS_REQUIRED).exit();
+    }
     app.set_logging()
         .expect("Error setting logging!");
+    let cache_key = app.cache_key()
+        .expect("Error reading the cache key.");
 
-    let csv_output_location = app.csv_output.clone();
-    let source_location = app.source.clone();
-    let embedding_model = app.embedding_model.to_string();
+    match &app.command {
+        Some(Command::Cache { action }) => {
+            action.run(cache_key.as_ref())
+                .expect("Error running cache command.");
+            return;
+        },
+        Some(Command::Sweep(args)) => {
+            args.run(cache_key.as_ref())
+                .expect("Error running sweep.");
+            return;
+        },
+        Some(Command::Similar(args)) => {
+            args.run(cache_key.as_ref(), app.hnsw_params(), app.ann_index_path.as_deref())
+                .expect("Error running similarity search.");
+            return;
+        },
+        None => {}
+    }
+
+    let csv_output_location = app.csv_output.clone()
+        .expect("--csv-output is required.");
+    let source_location = app.source.clone()
+        .expect("--source is required.");
+    let cache_location = app.cache.clone()
+        .expect("--cache is required.");
+    let embedding_model = app.embedding_model.clone();
     let embedding_dimensions = app.embedding_dimensions.clone();
-    let cluster_tolerance = app.cluster_tolerance.clone();
-    let cluster_grouping = app.cluster_grouping.clone();
+    let mut clusterer = app.clusterer(app.cluster_tolerance)
+        .expect("Invalid clustering parameters.");
+    let distance_metric = DistanceMetric::from_str(&app.distance_metric)
+        .expect("Invalid distance metric.");
+    let feature_weight = app.feature_weight;
+    let anomaly_method = AnomalyMethod::from_str(&app.anomaly_score)
+        .expect("Invalid anomaly score.");
+    let mut anomaly_scorer = AnomalyScorer::new(anomaly_method, app.anomaly_neighbors);
+    let request_timeout = Duration::from_secs(app.request_timeout);
+    let long_input_strategy = LongInputStrategy::from_str(&app.long_input_strategy)
+        .expect("Invalid long input strategy.");
+    let cache_dtype = VectorDType::from_str(&app.cache_dtype)
+        .expect("Invalid cache dtype.");
+    let input_template = InputTemplate::parse(&app.input_template)
+        .expect("Invalid input template.");
 
     if !csv_output_location.parent().unwrap().exists() {
         std::fs::create_dir_all(&csv_output_location.parent().unwrap())
@@ -155,34 +817,55 @@ async fn main() {
     ]);
 
     // Create a EvtxHandler to perform EVTX opterations
-    let evtx_handler = EvtxHandler::from_source(source_location)
+    let mut evtx_handler = EvtxHandler::from_source(source_location)
         .with_filter(filter)
         .add_transformer_field_from_pattern("Timestamp", "Event.System.TimeCreated_attributes.SystemTime").unwrap()
         .add_transformer_field_from_pattern("Computer", "Event.System.Computer").unwrap()
         .add_transformer_field_from_pattern("Provider", "Event.System.Provider_attributes.Name").unwrap()
         .add_transformer_field_from_pattern("EventID", "Event.System.EventID").unwrap()
         .add_transformer_field_from_pattern("CommandLine", "Event.EventData.CommandLine").unwrap();
+    // Extract the EventData fields used by the input template
+    let template_patterns: Vec<(String, String)> = input_template.fields()
+        .into_iter()
+        .filter(|field| !RECORD_COLUMNS.contains(field))
+        .map(|field| (field.to_string(), format!("Event.EventData.\"{field}\"")))
+        .collect();
+    for (field, pattern) in &template_patterns {
+        evtx_handler = evtx_handler.add_transformer_field_from_pattern(field, pattern)
+            .expect("Invalid input template field.");
+    }
+    if app.flatten_event_data {
+        evtx_handler = evtx_handler.with_flattened_event_data(app.system_keys.clone());
+    }
+
+    let mut df = evtx_handler.parse_into_dataframe()
+        .expect("Error parsing evtx records into dataframe.");
 
-    // Fetch the OpenAI API key
-    let api_key = match app.openai_token {
-        Some(k) => k,
-        None => std::env::var("OPENAI_KEY")
-            .expect("No openai_token was provided and OPENAI_KEY env var is not set.")
+    // Render the input template of each record
+    let input_column = if input_template.is_default() {
+        "CommandLine"
+    } else {
+        let inputs = input_template.render_dataframe(&df)
+            .expect("Error rendering input template.");
+        df.with_column(Series::new(INPUT_COLUMN, inputs))
+            .expect("Error adding input column.");
+        INPUT_COLUMN
     };
-    // Create an EmbeddingsHandler to perform embedding tasks
-    let embedding_handler = EmbeddingsHandler::new(
-        api_key,
-        embedding_model,
-        embedding_dimensions,
-        10
-    ).with_cache(&app.cache)
-        .expect("Error setting cache.");
 
-    let df = evtx_handler.parse_into_dataframe()
-        .expect("Error parsing evtx records into dataframe.");
+    // The command line of each rendered input, which the lexical features are computed from
+    let input_commands: Option<HashMap<String, String>> = (input_column != "CommandLine").then(|| {
+        let inputs = df[input_column].str().expect("Values are not strings.");
+        let commands = df["CommandLine"].str().expect("Values are not strings.");
+        let mut input_commands = HashMap::new();
+        for (input, command) in inputs.into_iter().zip(commands) {
+            input_commands.entry(input.unwrap_or_default().to_string())
+                .or_insert_with(|| command.unwrap_or_default().to_string());
+        }
+        input_commands
+    });
 
-    // Get all the CommandLine values
-    let cmds: Vec<String> = df["CommandLine"]
+    // Get all the unique inputs to embed
+    let cmds: Vec<String> = df[input_column]
         .unique_stable()
         .expect("Error computing unique values.")
         .str()
@@ -196,30 +879,285 @@ async fn main() {
         })
         .collect();
 
-    // Get embeddings for command line values
-    let value_embeddings = embedding_handler.get_embeddings(cmds)
+    // Create the provider for the selected backend
+    let provider: Arc<dyn EmbeddingProvider> = match app.embedding_backend {
+        EmbeddingBackend::Openai => {
+            // Fetch the OpenAI API key
+            let api_key = match app.openai_token.clone() {
+                Some(k) => k,
+                // Dry and offline runs never use the key.
+                None if app.dry_run || app.offline => std::env::var("OPENAI_KEY").unwrap_or_default(),
+                None => std::env::var("OPENAI_KEY")
+                    .expect("No openai_token was provided and OPENAI_KEY env var is not set.")
+            };
+            Arc::new(
+                OpenAIProvider::new(api_key, embedding_model, embedding_dimensions)
+                    .with_timeout(request_timeout)
+            )
+        },
+        EmbeddingBackend::Azure => {
+            let api_key = match app.openai_token.clone() {
+                Some(k) => k,
+                None if app.dry_run || app.offline => std::env::var("AZURE_OPENAI_KEY").unwrap_or_default(),
+                None => std::env::var("AZURE_OPENAI_KEY")
+                    .expect("No openai_token was provided and AZURE_OPENAI_KEY env var is not set.")
+            };
+            Arc::new(AzureOpenAIProvider::new(
+                app.api_base_url.as_ref().expect("--api-base-url is required for azure."),
+                app.azure_deployment.as_ref().expect("--azure-deployment is required for azure."),
+                &app.azure_api_version,
+                api_key,
+                embedding_model,
+                embedding_dimensions
+            ).with_timeout(request_timeout))
+        },
+        EmbeddingBackend::OpenaiCompatible => {
+            let api_key = app.openai_token.clone().or(std::env::var("OPENAI_KEY").ok());
+            Arc::new(OpenAICompatibleProvider::new(
+                app.api_base_url.as_ref().expect("--api-base-url is required for openai-compatible."),
+                api_key,
+                embedding_model,
+                embedding_dimensions
+            ).with_timeout(request_timeout))
+        },
+        EmbeddingBackend::Lexical => {
+            let dimensions = embedding_dimensions.unwrap_or(512) as usize;
+            Arc::new(LexicalEmbedder::new(dimensions).fit(&cmds))
+        },
+        EmbeddingBackend::Local => local_provider(&app.local_model_dir)
+            .expect("Error loading local model.")
+    };
+
+    let price_table = match &app.price_table {
+        Some(path) => PriceTable::from_file(path)
+            .expect("Error reading price table."),
+        None => PriceTable::default()
+    };
+    if app.max_cost.is_some() && price_table.price(provider.model()).is_none() {
+        warn!("No price for {}, --max-cost will not be enforced.", provider.model());
+    }
+    let budget = Budget {
+        max_tokens: app.max_tokens,
+        max_cost: app.max_cost
+    };
+
+    // Show the progress of embedding the commands
+    let progress_bar = if app.no_progress || app.dry_run {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(cmds.len() as u64)
+    };
+    progress_bar.set_style(
+        ProgressStyle::with_template("{bar:40} {pos}/{len} {msg} [{elapsed_precise}]")
+            .expect("Invalid progress template.")
+    );
+    let progress_callback = {
+        let progress_bar = progress_bar.clone();
+        move |p: EmbeddingProgress| {
+            progress_bar.set_length(p.total as u64);
+            progress_bar.set_position(p.completed as u64);
+            progress_bar.set_message(format!("{} cached, {} failed", p.cache_hits, p.failed));
+        }
+    };
+
+    // Create an EmbeddingsHandler to perform embedding tasks
+    let retry_policy = RetryPolicy {
+        max_retries: app.max_retries,
+        base_delay: Duration::from_millis(app.retry_base_delay_ms),
+        max_delay: Duration::from_millis(app.retry_max_delay_ms)
+    };
+    let mut embedding_handler = EmbeddingsHandler::from_provider(
+        provider,
+        app.parallel_requests
+    ).with_min_parallel_requests(app.min_parallel_requests)
+        .with_retry_policy(retry_policy)
+        .with_batch_size(app.batch_size, app.batch_tokens)
+        .with_long_input_strategy(long_input_strategy, app.max_input_tokens)
+        .with_vector_dtype(cache_dtype)
+        .with_price_table(price_table)
+        .with_budget(budget)
+        .with_progress(Arc::new(progress_callback))
+        .with_input_template(&input_template)
+        .with_cache_w_key(&cache_location, cache_key.as_ref())
+        .expect("Error setting cache.");
+    if app.offline {
+        let policy = OfflineMissPolicy::from_str(&app.offline_miss_policy)
+            .expect("Invalid offline miss policy.");
+        embedding_handler = embedding_handler.with_offline(policy);
+        if policy == OfflineMissPolicy::Fallback {
+            let fallback: Arc<dyn EmbeddingProvider> = match app.local_model_dir {
+                Some(_) => local_provider(&app.local_model_dir)
+                    .expect("Error loading local model."),
+                None => {
+                    let dimensions = embedding_dimensions.unwrap_or(512) as usize;
+                    Arc::new(LexicalEmbedder::new(dimensions).fit(&cmds))
+                }
+            };
+            embedding_handler = embedding_handler.with_fallback_provider(fallback);
+        }
+    }
+
+    // Report the planned work without calling the API
+    if app.dry_run {
+        let plan = embedding_handler.plan(cmds)
+            .expect("Error planning embeddings.");
+        println!("{plan}");
+        return;
+    }
+
+    // Get embeddings for command line values. Commands that fail are left unclustered.
+    let results = embedding_handler.get_embeddings(cmds.clone())
         .await
         .expect("Error getting embeddings for commands.");
+    progress_bar.finish();
+    info!("Embedding usage: {}", embedding_handler.usage_report());
 
-    let df_embeddings = get_cluster_mapping(
-        value_embeddings,
-        cluster_grouping,
-        cluster_tolerance
-    ).expect("Error getting clustered dataframe.");
+    let mut value_embeddings = Vec::with_capacity(results.len());
+    for result in results {
+        match result {
+            Ok(value_embedding) => value_embeddings.push(value_embedding),
+            Err(failure) => warn!("Not clustering {:?}: {}", failure.value, failure.reason)
+        }
+    }
+
+    // Index the embeddings for approximate neighbor queries
+    let neighbors: Option<Arc<dyn NeighborSearch>> = if app.ann_index {
+        let dataset = get_embedding_matrix(&value_embeddings, None, None)
+            .expect("Error creating embedding matrix.");
+        let keys: Vec<blake3::Hash> = value_embeddings.iter().map(|v| v.key).collect();
+        // Fallback vectors are from another embedding space and would stay in a saved index
+        // after the input is cached
+        let fallback = value_embeddings.iter().any(|v| v.fallback);
+        if fallback && embedding_handler.cache_namespace().is_some() {
+            info!("Some inputs were embedded by the offline fallback, the index is not saved.");
+        }
+        let neighbors = match embedding_handler.cache_namespace().filter(|_| !fallback) {
+            Some(namespace) => {
+                let index_location = app.ann_index_path.clone()
+                    .unwrap_or_else(|| HnswIndex::path_for(&cache_location, namespace, distance_metric));
+                indexed_rows(
+                    &index_location,
+                    &keys,
+                    &dataset,
+                    distance_metric,
+                    app.hnsw_params(),
+                    embedding_handler.cache_cipher()
+                )
+            },
+            // Vectors that are not cached can change between runs, so their index is not saved
+            None => {
+                let mut index = HnswIndex::new(distance_metric, dataset.ncols(), app.hnsw_params());
+                index.extend(&keys, &dataset)
+                    .and_then(|_| IndexedRows::new(Arc::new(index), &keys))
+            }
+        }.expect("Error indexing embeddings.");
+        if app.cluster_algorithm != "dbscan" {
+            warn!("--ann-index is not used to cluster with {}.", app.cluster_algorithm);
+        }
+        Some(Arc::new(neighbors))
+    } else {
+        None
+    };
+    if let Some(neighbors) = &neighbors {
+        anomaly_scorer = anomaly_scorer.with_neighbors(neighbors.clone());
+    }
+
+    // Select the tolerance from the k-distance curve
+    let mut tolerance = app.cluster_tolerance;
+    if app.auto_tolerance {
+        let analysis = match &neighbors {
+            Some(neighbors) => KDistanceAnalysis::from_neighbors(neighbors.as_ref(), app.cluster_grouping),
+            None => {
+                let dataset = get_embedding_matrix(&value_embeddings, feature_weight, input_commands.as_ref())
+                    .expect("Error creating embedding matrix.");
+                KDistanceAnalysis::new(&dataset, app.cluster_grouping, distance_metric)
+            }
+        }.expect("Error selecting tolerance.");
+        info!(
+            "Selected a {} tolerance of {} at the knee ({} of {}) of the k={} distance curve.",
+            analysis.metric,
+            analysis.tolerance,
+            analysis.knee_index + 1,
+            analysis.distances.len(),
+            analysis.k
+        );
+
+        let k_distance_location = app.k_distance_output.clone()
+            .unwrap_or_else(|| csv_output_location.with_extension("k-distance.csv"));
+        let mut k_distance_fh: File = File::create(&k_distance_location)
+            .expect("Error creating k-distance output.");
+        CsvWriter::new(&mut k_distance_fh)
+            .include_header(true)
+            .finish(&mut analysis.to_dataframe().expect("Error creating k-distance dataframe."))
+            .expect("Error writing k-distance output.");
+        info!("Wrote the k-distance curve to {:?}.", k_distance_location);
+        tolerance = analysis.tolerance;
+    }
+    if app.auto_tolerance || neighbors.is_some() {
+        clusterer = app.clusterer_w_neighbors(tolerance, neighbors)
+            .expect("Invalid clustering parameters.");
+    }
+
+    let mut options = ClusterOptions::new(clusterer)
+        .with_metric(distance_metric)
+        .with_feature_weight(feature_weight)
+        .with_exemplars(app.exemplars)
+        .with_anomaly(anomaly_scorer);
+    if let Some(input_commands) = input_commands {
+        options = options.with_commands(input_commands);
+    }
+    let mapping = get_cluster_mapping(value_embeddings, &options)
+        .expect("Error getting clustered dataframe.");
+    let (df_embeddings, mut df_summary, metrics) = (mapping.df, mapping.summary, mapping.metrics);
 
     let mut df = df.left_join(
         &df_embeddings,
-        &["CommandLine"],
+        &[input_column],
         &["value"]
     ).expect("Error joining embeddings dataframe!");
 
-    let mut output_csv_fh: File = File::create(csv_output_location).unwrap();
+    // Add the lexical feature columns of each command line
+    if app.lexical_features {
+        let commands: Vec<String> = df["CommandLine"]
+            .unique_stable()
+            .expect("Error computing unique values.")
+            .str()
+            .expect("Values are not strings.")
+            .into_iter()
+            .map(|v| v.unwrap_or_default().to_string())
+            .collect();
+        let df_features = get_feature_dataframe(&commands)
+            .expect("Error computing lexical features.");
+        df = df.left_join(
+            &df_features,
+            &["CommandLine"],
+            &["value"]
+        ).expect("Error joining features dataframe!");
+    }
+
+    let mut output_csv_fh: File = File::create(&csv_output_location).unwrap();
     CsvWriter::new(&mut output_csv_fh)
         .include_header(true)
         .finish(&mut df)
         .unwrap();
 
+    let summary_location = app.summary_output.clone()
+        .unwrap_or_else(|| csv_output_location.with_extension("summary.csv"));
+    let mut summary_fh: File = File::create(&summary_location)
+        .expect("Error creating summary output.");
+    CsvWriter::new(&mut summary_fh)
+        .include_header(true)
+        .finish(&mut df_summary)
+        .expect("Error writing summary output.");
+
+    let metrics_location = app.metrics_output.clone()
+        .unwrap_or_else(|| csv_output_location.with_extension("metrics.json"));
+    std::fs::write(&metrics_location, metrics.to_json().expect("Error serializing metrics."))
+        .expect("Error writing metrics output.");
+
     println!("{}", df.sort(
         ["cluster"], SortMultipleOptions::new().with_order_descending(true)
     ).unwrap());
+    println!("{}", metrics.cluster_dataframe().expect("Error creating cluster metrics dataframe."));
+    println!("{metrics}");
 }
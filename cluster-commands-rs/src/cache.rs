@@ -0,0 +1,895 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use half::f16;
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use openai_api_rs::v1::embedding::EmbeddingResponse;
+use sled::{Db, Tree};
+use crate::encryption::{CacheCipher, EncryptionMeta, KeySource, is_encrypted};
+use crate::errors::CustomError;
+
+
+/// The current layout of cached values.
+pub const SCHEMA_VERSION: u32 = 3;
+/// Marks a value as a binary encoded vector.
+const VECTOR_MAGIC: &[u8; 2] = b"EV";
+/// Header length: magic, format version, dtype and dimensions.
+const VECTOR_HEADER_LEN: usize = 8;
+/// The tree that maps entry keys to the input text of the single-model layout.
+const INPUTS_TREE: &str = "inputs";
+/// The tree that maps namespace names to their CacheNamespace.
+const NAMESPACES_TREE: &str = "namespaces";
+/// The prefix of the vector tree of a namespace.
+const VECTORS_PREFIX: &str = "vectors:";
+/// The prefix of the input tree of a namespace.
+const INPUTS_PREFIX: &str = "inputs:";
+/// The metadata key of the EncryptionMeta of encrypted caches.
+const ENCRYPTION_KEY: &[u8] = b"encryption";
+/// The provider of the namespace of a cache with the single-model layout.
+pub const LEGACY_PROVIDER: &str = "legacy";
+
+
+/// How vector components are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorDType {
+    F32,
+    F16
+}
+impl VectorDType {
+    fn to_byte(self) -> u8 {
+        match self {
+            Self::F32 => 0,
+            Self::F16 => 1
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::F32),
+            1 => Some(Self::F16),
+            _ => None
+        }
+    }
+}
+impl std::str::FromStr for VectorDType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "f32" => Ok(Self::F32),
+            "f16" => Ok(Self::F16),
+            _ => Err(format!("invalid vector dtype: {s}"))
+        }
+    }
+}
+
+
+/// Encode a vector as a header followed by little-endian components.
+pub fn encode_vector(vector: &[f32], dtype: VectorDType) -> Vec<u8> {
+    let width = match dtype {
+        VectorDType::F32 => 4,
+        VectorDType::F16 => 2
+    };
+    let mut bytes = Vec::with_capacity(VECTOR_HEADER_LEN + vector.len() * width);
+    bytes.extend_from_slice(VECTOR_MAGIC);
+    bytes.push(1);
+    bytes.push(dtype.to_byte());
+    bytes.extend_from_slice(&(vector.len() as u32).to_le_bytes());
+    for v in vector {
+        match dtype {
+            VectorDType::F32 => bytes.extend_from_slice(&v.to_le_bytes()),
+            VectorDType::F16 => bytes.extend_from_slice(&f16::from_f32(*v).to_le_bytes())
+        }
+    }
+    bytes
+}
+
+/// Decode a vector created by `encode_vector`.
+pub fn decode_vector(bytes: &[u8]) -> Result<Vec<f32>, CustomError> {
+    if bytes.len() < VECTOR_HEADER_LEN || &bytes[..2] != VECTOR_MAGIC {
+        return Err(CustomError::cache_error("Value is not an encoded vector."));
+    }
+    let dtype = VectorDType::from_byte(bytes[3])
+        .ok_or_else(|| CustomError::cache_error(format!("Unknown vector dtype {}.", bytes[3])))?;
+    let dimensions = u32::from_le_bytes(bytes[4..8].try_into().expect("4 bytes.")) as usize;
+    let data = &bytes[VECTOR_HEADER_LEN..];
+
+    let vector: Vec<f32> = match dtype {
+        VectorDType::F32 => data.chunks_exact(4)
+            .map(|c| f32::from_le_bytes(c.try_into().expect("4 bytes.")))
+            .collect(),
+        VectorDType::F16 => data.chunks_exact(2)
+            .map(|c| f16::from_le_bytes(c.try_into().expect("2 bytes.")).to_f32())
+            .collect()
+    };
+    if vector.len() != dimensions {
+        return Err(CustomError::cache_error(format!(
+            "Vector has {} values but the header has {} dimensions.", vector.len(), dimensions
+        )));
+    }
+    Ok(vector)
+}
+
+/// Decode a cached value that is either an encoded vector or a JSON EmbeddingResponse from
+/// the first cache schema.
+pub fn decode_value(bytes: &[u8]) -> Result<Vec<f32>, CustomError> {
+    if bytes.starts_with(VECTOR_MAGIC) {
+        return decode_vector(bytes);
+    }
+
+    let response: EmbeddingResponse = serde_json::from_slice(bytes)
+        .map_err(|e| CustomError::cache_error(format!("Error parsing cached value! {e:?}")))?;
+    response.data
+        .into_iter()
+        .next()
+        .map(|d| d.embedding)
+        .ok_or_else(|| CustomError::cache_error("Cached response has no embedding data."))
+}
+
+
+fn hex_key(key: &[u8]) -> String {
+    key.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn is_parquet(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e.to_string_lossy().to_lowercase() == "parquet")
+}
+
+fn records_to_dataframe(records: &[CacheRecord]) -> Result<DataFrame, CustomError> {
+    let inputs: Vec<Option<&str>> = records.iter().map(|r| r.input.as_deref()).collect();
+    let hashes: Vec<&str> = records.iter().map(|r| r.hash.as_str()).collect();
+    let vectors: Vec<Series> = records.iter()
+        .map(|r| Series::new("", &r.vector))
+        .collect();
+
+    let df = DataFrame::new(vec![
+        Series::new("input", inputs),
+        Series::new("hash", hashes),
+        Series::new("vector", vectors),
+    ])?;
+    Ok(df)
+}
+
+fn dataframe_to_records(df: &DataFrame) -> Result<Vec<CacheRecord>, CustomError> {
+    let inputs = df.column("input")?.str()?;
+    let hashes = df.column("hash")?.str()?;
+    let vectors = df.column("vector")?.list()?;
+
+    let mut records = Vec::with_capacity(df.height());
+    for ((input, hash), vector) in inputs.into_iter().zip(hashes).zip(vectors) {
+        let vector = vector
+            .ok_or_else(|| CustomError::cache_error("Record has no vector."))?;
+        let vector: Vec<f32> = vector.cast(&DataType::Float32)?
+            .f32()?
+            .into_no_null_iter()
+            .collect();
+        records.push(CacheRecord {
+            input: input.map(|s| s.to_string()),
+            hash: hash.unwrap_or_default().to_string(),
+            vector
+        });
+    }
+    Ok(records)
+}
+
+/// Get the namespace of a cache with the single-model layout.
+fn legacy_namespace(db: &Db) -> Result<Option<CacheNamespace>, CustomError> {
+    let model = match db.get(b"model")? {
+        Some(value_bytes) => String::from_utf8_lossy(&value_bytes).to_string(),
+        None => return Ok(None)
+    };
+    let dimensions = match db.get(b"dimensions")? {
+        Some(value_bytes) => {
+            let bytes = <[u8; 4]>::try_from(value_bytes.as_ref())
+                .map_err(|_| CustomError::cache_error("dimensions did not have a 4 byte value."))?;
+            Some(i32::from_le_bytes(bytes))
+        },
+        None => None
+    };
+    Ok(Some(CacheNamespace::new(LEGACY_PROVIDER, model, dimensions)))
+}
+
+fn list_namespaces(db: &Db) -> Result<Vec<CacheNamespace>, CustomError> {
+    let mut namespaces: Vec<CacheNamespace> = legacy_namespace(db)?.into_iter().collect();
+    for entry in db.open_tree(NAMESPACES_TREE)?.iter() {
+        let (_, value) = entry?;
+        let namespace: CacheNamespace = serde_json::from_slice(&value)
+            .map_err(|e| CustomError::cache_error(format!("Error parsing namespace: {e:?}")))?;
+        namespaces.push(namespace);
+    }
+    Ok(namespaces)
+}
+
+/// The additional authenticated data of an encrypted value.
+fn entry_aad(purpose: &[u8], key: &[u8]) -> Vec<u8> {
+    [purpose, b":", key].concat()
+}
+
+/// Get the cipher of a cache. A key given for a new cache turns on encryption and the
+/// encryption metadata is recorded.
+fn open_cipher(path: &Path, db: &Db, key: Option<&KeySource>, create: bool) -> Result<Option<CacheCipher>, CustomError> {
+    let meta = match db.get(ENCRYPTION_KEY)? {
+        Some(value) => Some(serde_json::from_slice::<EncryptionMeta>(&value)
+            .map_err(|e| CustomError::cache_error(format!("Error parsing encryption metadata: {e:?}")))?),
+        None => None
+    };
+
+    match (meta, key) {
+        (None, None) => Ok(None),
+        (None, Some(key)) if create => {
+            let (cipher, meta) = CacheCipher::create(key)?;
+            let value = serde_json::to_vec(&meta)
+                .map_err(|e| CustomError::cache_error(format!("Error serializing encryption metadata: {e:?}")))?;
+            db.insert(ENCRYPTION_KEY, value)?;
+            db.flush()?;
+            Ok(Some(cipher))
+        },
+        (None, Some(_)) => Err(CustomError::cache_error(format!(
+            "{:?} is not encrypted, a passphrase or keyfile can only be used with new caches.", path
+        ))),
+        (Some(_), None) => Err(CustomError::cache_error(format!(
+            "{:?} is encrypted, a passphrase or keyfile is required.", path
+        ))),
+        (Some(meta), Some(key)) => CacheCipher::open(key, &meta)
+            .map(Some)
+            .map_err(|e| CustomError::cache_error(format!("{:?} {}", path, e.message)))
+    }
+}
+
+/// The schema version of a cache. Caches without a version are version 1.
+fn read_schema_version(db: &Db) -> Result<u32, CustomError> {
+    match db.get(b"schema_version")? {
+        Some(value_bytes) => {
+            let bytes = <[u8; 4]>::try_from(value_bytes.as_ref())
+                .map_err(|_| CustomError::cache_error("schema_version did not have a 4 byte value."))?;
+            Ok(u32::from_le_bytes(bytes))
+        },
+        None => Ok(1)
+    }
+}
+
+/// The schema version of an existing cache, refusing caches from a newer schema.
+fn check_schema_version(path: &Path, db: &Db) -> Result<u32, CustomError> {
+    let version = read_schema_version(db)?;
+    if version > SCHEMA_VERSION {
+        return Err(CustomError::cache_error(format!(
+            "{:?} has schema version {} which is newer than {}.", path, version, SCHEMA_VERSION
+        )));
+    }
+    Ok(version)
+}
+
+
+/// Counts, size and metadata of a cache.
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub with_input: usize,
+    /// The bytes of the keys, vectors and inputs of the namespace's entries.
+    pub namespace_bytes: u64,
+    /// The size of the whole cache directory, with every namespace.
+    pub database_size_on_disk: u64,
+    pub namespace: String,
+    pub model: String,
+    pub dimensions: Option<i32>,
+    pub template: Option<String>,
+    pub schema_version: u32,
+    pub encrypted: bool
+}
+
+
+/// The result of verifying a cache. `invalid` holds the hex key and the problem.
+#[derive(Debug, Default, Serialize)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub invalid: Vec<(String, String)>
+}
+
+
+/// A cache entry as it is exported and imported.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheRecord {
+    pub input: Option<String>,
+    #[serde(default)]
+    pub hash: String,
+    pub vector: Vec<f32>
+}
+impl CacheRecord {
+    /// The entry key from the hash, or the hash of the input if there is no hash.
+    pub fn key(&self) -> Result<blake3::Hash, CustomError> {
+        if !self.hash.is_empty() {
+            return blake3::Hash::from_hex(&self.hash)
+                .map_err(|e| CustomError::cache_error(format!("Invalid hash {}: {e:?}", self.hash)));
+        }
+        match &self.input {
+            Some(input) => Ok(blake3::hash(input.as_bytes())),
+            None => Err(CustomError::cache_error("Record has neither a hash nor an input."))
+        }
+    }
+}
+
+
+/// The provider, model, dimensions and input template that the vectors of a namespace were
+/// created with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheNamespace {
+    pub provider: String,
+    pub model: String,
+    pub dimensions: Option<i32>,
+    /// The template the inputs were rendered with. None when only the command line is embedded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>
+}
+impl CacheNamespace {
+    pub fn new(provider: impl AsRef<str>, model: impl AsRef<str>, dimensions: Option<i32>) -> Self {
+        Self {
+            provider: provider.as_ref().to_string(),
+            model: model.as_ref().to_string(),
+            dimensions,
+            template: None
+        }
+    }
+
+    /// Set the input template of the namespace.
+    pub fn with_template(mut self, template: impl AsRef<str>) -> Self {
+        self.template = Some(template.as_ref().to_string());
+        self
+    }
+
+    /// The name of the namespace, e.g. `openai/text-embedding-3-small/default`. Namespaces with
+    /// a template end in `/template-` and the start of the template hash.
+    pub fn name(&self) -> String {
+        let dimensions = self.dimensions.map_or("default".to_string(), |d| d.to_string());
+        match &self.template {
+            Some(template) => {
+                let hash = blake3::hash(template.as_bytes()).to_hex();
+                format!("{}/{}/{}/template-{}", self.provider, self.model, dimensions, &hash[..12])
+            },
+            None => format!("{}/{}/{}", self.provider, self.model, dimensions)
+        }
+    }
+
+    /// Check if vectors of another namespace can be used for this one. The provider is ignored
+    /// for the single-model layout, which did not record it.
+    pub fn is_compatible(&self, other: &CacheNamespace) -> bool {
+        self.model == other.model
+            && self.dimensions == other.dimensions
+            && self.template == other.template
+            && (self.provider == other.provider || self.provider == LEGACY_PROVIDER || other.provider == LEGACY_PROVIDER)
+    }
+}
+
+
+/// Cached embedding vectors keyed by the BLAKE3 hash of the input. A cache directory holds a
+/// namespace of vectors for each provider, model and dimensions. Caches created before
+/// namespaces keep their vectors in the default tree, which is used for its own model.
+#[derive(Clone)]
+pub struct EmbeddingCache {
+    db: Db,
+    namespace: CacheNamespace,
+    vectors: Tree,
+    inputs: Tree,
+    cipher: Option<CacheCipher>,
+    dtype: VectorDType,
+    read_only: bool
+}
+impl EmbeddingCache {
+    /// Open the namespace of a cache, creating the cache and namespace if needed. A cache with
+    /// the single-model layout is used as is when its model and dimensions match. Caches from an
+    /// older schema are opened read-only until they are migrated with `upgrade`.
+    pub fn open(path: impl AsRef<Path>, namespace: &CacheNamespace) -> Result<Self, CustomError> {
+        Self::open_w_key(path, namespace, None)
+    }
+
+    /// Open the namespace of a cache with an encryption key. A new cache is encrypted when a
+    /// key is given. An encrypted cache cannot be opened without its key and a key cannot be
+    /// used with an unencrypted cache.
+    pub fn open_w_key(path: impl AsRef<Path>, namespace: &CacheNamespace, key: Option<&KeySource>) -> Result<Self, CustomError> {
+        let path = path.as_ref();
+        let exists = path.is_dir();
+        let db = sled::open(path)?;
+        let version = match exists {
+            true => check_schema_version(path, &db)?,
+            false => {
+                db.insert(b"schema_version", &SCHEMA_VERSION.to_le_bytes())?;
+                SCHEMA_VERSION
+            }
+        };
+        let cipher = open_cipher(path, &db, key, !exists)?;
+
+        if version < SCHEMA_VERSION {
+            // Older caches are only read, a namespace cannot be added to them.
+            let existing = list_namespaces(&db)?.into_iter()
+                .find(|n| n.is_compatible(namespace))
+                .ok_or_else(|| CustomError::cache_error(format!(
+                    "{:?} has schema version {} and no namespace {}, migrate it with `cache migrate` first.",
+                    path, version, namespace.name()
+                )))?;
+            return Self::from_db(db, existing, cipher).map(Self::into_read_only);
+        }
+
+        if let Some(legacy) = legacy_namespace(&db)? {
+            if legacy.is_compatible(namespace) {
+                return Self::from_db(db, legacy, cipher);
+            }
+        }
+
+        // Register the namespace so it can be listed.
+        let namespaces = db.open_tree(NAMESPACES_TREE)?;
+        let name = namespace.name();
+        if !namespaces.contains_key(name.as_bytes())? {
+            let value = serde_json::to_vec(namespace)
+                .map_err(|e| CustomError::cache_error(format!("Error serializing namespace: {e:?}")))?;
+            namespaces.insert(name.as_bytes(), value)?;
+            namespaces.flush()?;
+        }
+
+        Self::from_db(db, namespace.clone(), cipher)
+    }
+
+    /// Open a namespace of an existing cache by name. Without a name the cache must hold a
+    /// single namespace. Caches from an older schema are opened read-only.
+    pub fn open_existing(path: impl AsRef<Path>, name: Option<&str>) -> Result<Self, CustomError> {
+        Self::open_existing_w_key(path, name, None)
+    }
+
+    /// Open a namespace of an existing cache by name with the encryption key of the cache.
+    pub fn open_existing_w_key(path: impl AsRef<Path>, name: Option<&str>, key: Option<&KeySource>) -> Result<Self, CustomError> {
+        let path = path.as_ref();
+        if !path.is_dir() {
+            return Err(CustomError::cache_error(format!("{:?} is not a cache directory.", path)));
+        }
+        let db = sled::open(path)?;
+        let version = check_schema_version(path, &db)?;
+        let cipher = open_cipher(path, &db, key, false)?;
+
+        let namespaces = list_namespaces(&db)?;
+        let namespace = match name {
+            Some(name) => namespaces.into_iter()
+                .find(|n| n.name() == name)
+                .ok_or_else(|| CustomError::cache_error(format!("{:?} has no namespace {}.", path, name)))?,
+            None => {
+                if namespaces.len() != 1 {
+                    let names: Vec<String> = namespaces.iter().map(|n| n.name()).collect();
+                    return Err(CustomError::cache_error(format!(
+                        "{:?} has {} namespaces, choose one of: {}.", path, names.len(), names.join(", ")
+                    )));
+                }
+                namespaces.into_iter().next().expect("One namespace.")
+            }
+        };
+
+        let cache = Self::from_db(db, namespace, cipher)?;
+        match version < SCHEMA_VERSION {
+            true => Ok(cache.into_read_only()),
+            false => Ok(cache)
+        }
+    }
+
+    /// Migrate an existing cache to the current schema. Only the single-model layout can hold
+    /// values from before schema 2 and namespaces need no conversion. Returns the number of
+    /// converted entries.
+    pub fn upgrade(path: impl AsRef<Path>) -> Result<usize, CustomError> {
+        let path = path.as_ref();
+        if !path.is_dir() {
+            return Err(CustomError::cache_error(format!("{:?} is not a cache directory.", path)));
+        }
+        let db = sled::open(path)?;
+        let version = check_schema_version(path, &db)?;
+        if version == SCHEMA_VERSION {
+            return Ok(0);
+        }
+
+        info!("Migrating {:?} from schema version {} to {}.", path, version, SCHEMA_VERSION);
+        match legacy_namespace(&db)? {
+            // Caches are encrypted from the current schema on so no key is needed.
+            Some(legacy) => Self::from_db(db, legacy, None)?.migrate(),
+            None => {
+                db.insert(b"schema_version", &SCHEMA_VERSION.to_le_bytes())?;
+                db.flush()?;
+                Ok(0)
+            }
+        }
+    }
+
+    /// List the namespaces of an existing cache.
+    pub fn namespaces(path: impl AsRef<Path>) -> Result<Vec<CacheNamespace>, CustomError> {
+        let path = path.as_ref();
+        if !path.is_dir() {
+            return Err(CustomError::cache_error(format!("{:?} is not a cache directory.", path)));
+        }
+        list_namespaces(&sled::open(path)?)
+    }
+
+    fn from_db(db: Db, namespace: CacheNamespace, cipher: Option<CacheCipher>) -> Result<Self, CustomError> {
+        let (vectors, inputs) = if namespace.provider == LEGACY_PROVIDER {
+            ((*db).clone(), db.open_tree(INPUTS_TREE)?)
+        } else {
+            let name = namespace.name();
+            (
+                db.open_tree(format!("{VECTORS_PREFIX}{name}"))?,
+                db.open_tree(format!("{INPUTS_PREFIX}{name}"))?
+            )
+        };
+        Ok(Self {
+            db,
+            namespace,
+            vectors,
+            inputs,
+            cipher,
+            dtype: VectorDType::F32,
+            read_only: false
+        })
+    }
+
+    fn into_read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Is the cache encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// The cipher of an encrypted cache, to encrypt files kept with the cache.
+    pub fn cipher(&self) -> Option<&CacheCipher> {
+        self.cipher.as_ref()
+    }
+
+    /// Is the cache from an older schema, which is read but not written until it is migrated.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Refuse to write to a read-only cache.
+    fn check_writable(&self) -> Result<(), CustomError> {
+        match self.read_only {
+            true => Err(CustomError::cache_error(
+                "The cache is from an older schema and is read-only, migrate it with `cache migrate` first."
+            )),
+            false => Ok(())
+        }
+    }
+
+    /// Decrypt and decode a stored vector.
+    fn read_vector(&self, key: &[u8], value: &[u8]) -> Result<Vec<f32>, CustomError> {
+        match &self.cipher {
+            Some(cipher) => decode_value(&cipher.decrypt(&entry_aad(b"vector", key), value)?),
+            None if is_encrypted(value) => Err(CustomError::cache_error("Value is encrypted.")),
+            None => decode_value(value)
+        }
+    }
+
+    /// Encode and encrypt a vector for storage.
+    fn write_vector(&self, key: &[u8], vector: &[f32]) -> Result<Vec<u8>, CustomError> {
+        let bytes = encode_vector(vector, self.dtype);
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(&entry_aad(b"vector", key), &bytes),
+            None => Ok(bytes)
+        }
+    }
+
+    /// Decrypt a stored input.
+    fn read_input(&self, key: &[u8], value: &[u8]) -> Result<String, CustomError> {
+        let bytes = match &self.cipher {
+            Some(cipher) => cipher.decrypt(&entry_aad(b"input", key), value)?,
+            None => value.to_vec()
+        };
+        Ok(String::from_utf8_lossy(&bytes).to_string())
+    }
+
+    /// Encrypt an input for storage.
+    fn write_input(&self, key: &[u8], input: &str) -> Result<Vec<u8>, CustomError> {
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(&entry_aad(b"input", key), input.as_bytes()),
+            None => Ok(input.as_bytes().to_vec())
+        }
+    }
+
+    /// Check that the namespace was created for a model and dimensions.
+    pub fn validate(&self, model: &str, dimensions: Option<i32>) -> Result<(), CustomError> {
+        if self.namespace.model != model {
+            return Err(CustomError::cache_error(format!(
+                "saved model {} does not match current model {}.",
+                self.namespace.model,
+                model
+            )))
+        }
+
+        // Validate that dimesions are alligned.
+        if self.namespace.dimensions != dimensions {
+            return Err(CustomError::cache_error(format!(
+                "cached dimensions {:?} do not match current dimensions of {:?}.",
+                self.namespace.dimensions,
+                dimensions
+            )))
+        }
+        Ok(())
+    }
+
+    /// The namespace the cache was opened with.
+    pub fn namespace(&self) -> &CacheNamespace {
+        &self.namespace
+    }
+
+    /// The model the namespace was created for.
+    pub fn model(&self) -> &str {
+        &self.namespace.model
+    }
+
+    /// The dimensions the namespace was created for.
+    pub fn dimensions(&self) -> Option<i32> {
+        self.namespace.dimensions
+    }
+
+    /// Set how new vectors are stored.
+    pub fn with_dtype(mut self, dtype: VectorDType) -> Self {
+        self.dtype = dtype;
+        self
+    }
+
+    /// The schema version of the cache. Caches without a version are version 1.
+    pub fn schema_version(&self) -> Result<u32, CustomError> {
+        read_schema_version(&self.db)
+    }
+
+    /// Iterate the entry keys and values, skipping the metadata keys.
+    pub fn entries(&self) -> impl Iterator<Item = Result<(sled::IVec, sled::IVec), CustomError>> + '_ {
+        self.vectors.iter()
+            .filter(|r| r.as_ref().map_or(true, |(key, _)| key.len() == blake3::OUT_LEN))
+            .map(|r| r.map_err(|e| e.into()))
+    }
+
+    /// Convert JSON encoded entries to encoded vectors in place and record the schema version.
+    /// Returns the number of converted entries.
+    pub fn migrate(&self) -> Result<usize, CustomError> {
+        let mut migrated = 0;
+        for entry in self.entries() {
+            let (key, value) = entry?;
+            if value.starts_with(VECTOR_MAGIC) {
+                continue;
+            }
+            let vector = decode_value(&value)?;
+            self.vectors.insert(&key, self.write_vector(&key, &vector)?)?;
+            migrated += 1;
+        }
+        self.db.insert(b"schema_version", &SCHEMA_VERSION.to_le_bytes())?;
+        self.db.flush()?;
+        Ok(migrated)
+    }
+
+    /// Get a cached vector.
+    pub fn get(&self, key: &blake3::Hash) -> Result<Option<Vec<f32>>, CustomError> {
+        match self.vectors.get(key.as_bytes())? {
+            Some(value) => self.read_vector(key.as_bytes(), &value).map(Some),
+            None => Ok(None)
+        }
+    }
+
+    /// Cache a vector and the input it was created from.
+    pub fn insert(&self, key: &blake3::Hash, input: impl AsRef<str>, vector: &[f32]) -> Result<(), CustomError> {
+        self.check_writable()?;
+        self.vectors.insert(key.as_bytes(), self.write_vector(key.as_bytes(), vector)?)?;
+        self.inputs.insert(key.as_bytes(), self.write_input(key.as_bytes(), input.as_ref())?)?;
+        Ok(())
+    }
+
+    /// Get the input text of an entry. Entries cached before inputs were stored have none.
+    pub fn get_input(&self, key: &[u8]) -> Result<Option<String>, CustomError> {
+        match self.inputs.get(key)? {
+            Some(value) => self.read_input(key, &value).map(Some),
+            None => Ok(None)
+        }
+    }
+
+    /// Remove an entry.
+    pub fn remove(&self, key: &[u8]) -> Result<(), CustomError> {
+        self.check_writable()?;
+        self.vectors.remove(key)?;
+        self.inputs.remove(key)?;
+        Ok(())
+    }
+
+    pub fn flush(&self) {
+        self.db.flush().expect("Error flushing DB to disk!");
+        self.vectors.flush().expect("Error flushing DB to disk!");
+        self.inputs.flush().expect("Error flushing DB to disk!");
+    }
+
+    /// Get the counts, size and metadata of the cache.
+    pub fn stats(&self) -> Result<CacheStats, CustomError> {
+        let mut entries = 0;
+        let mut with_input = 0;
+        let mut namespace_bytes = 0;
+        for entry in self.entries() {
+            let (key, value) = entry?;
+            entries += 1;
+            namespace_bytes += (key.len() + value.len()) as u64;
+            if let Some(input) = self.inputs.get(&key)? {
+                with_input += 1;
+                namespace_bytes += (key.len() + input.len()) as u64;
+            }
+        }
+
+        Ok(CacheStats {
+            entries,
+            with_input,
+            namespace_bytes,
+            database_size_on_disk: self.db.size_on_disk()?,
+            namespace: self.namespace.name(),
+            model: self.namespace.model.clone(),
+            dimensions: self.namespace.dimensions,
+            template: self.namespace.template.clone(),
+            schema_version: self.schema_version()?,
+            encrypted: self.is_encrypted()
+        })
+    }
+
+    /// Check that every value decodes and has the cached dimensions. Without cached
+    /// dimensions every vector must have the dimensions of the first.
+    pub fn verify(&self) -> Result<VerifyReport, CustomError> {
+        let mut expected = self.dimensions().map(|d| d as usize);
+        let mut report = VerifyReport::default();
+        for entry in self.entries() {
+            let (key, value) = entry?;
+            report.checked += 1;
+            let key_hex = hex_key(&key);
+            match self.read_vector(&key, &value) {
+                Ok(vector) => {
+                    let expected = *expected.get_or_insert(vector.len());
+                    if vector.len() != expected {
+                        report.invalid.push((key_hex, format!(
+                            "has {} dimensions, expected {}", vector.len(), expected
+                        )));
+                    }
+                },
+                Err(e) => report.invalid.push((key_hex, e.message))
+            }
+        }
+        Ok(report)
+    }
+
+    /// Get every entry as a CacheRecord.
+    pub fn records(&self) -> Result<Vec<CacheRecord>, CustomError> {
+        let mut records = Vec::new();
+        for entry in self.entries() {
+            let (key, value) = entry?;
+            records.push(CacheRecord {
+                input: self.get_input(&key)?,
+                hash: hex_key(&key),
+                vector: self.read_vector(&key, &value)?
+            });
+        }
+        Ok(records)
+    }
+
+    /// Add records, keyed by their hash or the hash of their input. Returns the number of
+    /// records added.
+    pub fn import_records(&self, records: Vec<CacheRecord>) -> Result<usize, CustomError> {
+        self.check_writable()?;
+        let expected = self.dimensions().map(|d| d as usize);
+        let mut imported = 0;
+        for record in records {
+            let key = record.key()?;
+            if let Some(expected) = expected {
+                if record.vector.len() != expected {
+                    return Err(CustomError::cache_error(format!(
+                        "{} has {} dimensions, expected {}.", key.to_hex(), record.vector.len(), expected
+                    )));
+                }
+            }
+            self.vectors.insert(key.as_bytes(), self.write_vector(key.as_bytes(), &record.vector)?)?;
+            if let Some(input) = &record.input {
+                self.inputs.insert(key.as_bytes(), self.write_input(key.as_bytes(), input)?)?;
+            }
+            imported += 1;
+        }
+        self.flush();
+        Ok(imported)
+    }
+
+    /// Write every entry to a JSONL file or, if the path ends in `.parquet`, a Parquet file.
+    pub fn export(&self, path: impl AsRef<Path>) -> Result<usize, CustomError> {
+        let path = path.as_ref();
+        let records = self.records()?;
+        let count = records.len();
+        let mut file = File::create(path)
+            .map_err(|e| CustomError::general_error(format!("Error creating {:?}: {e:?}", path)))?;
+
+        if is_parquet(path) {
+            let mut df = records_to_dataframe(&records)?;
+            ParquetWriter::new(&mut file).finish(&mut df)?;
+        } else {
+            let mut writer = BufWriter::new(file);
+            for record in records {
+                writeln!(writer, "{}", json!(record))
+                    .map_err(|e| CustomError::general_error(format!("Error writing {:?}: {e:?}", path)))?;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Read entries from a JSONL or Parquet file created by `export`.
+    pub fn import(&self, path: impl AsRef<Path>) -> Result<usize, CustomError> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .map_err(|e| CustomError::general_error(format!("Error opening {:?}: {e:?}", path)))?;
+
+        let records = if is_parquet(path) {
+            let df = ParquetReader::new(file).finish()?;
+            dataframe_to_records(&df)?
+        } else {
+            let mut records = Vec::new();
+            for line in BufReader::new(file).lines() {
+                let line = line
+                    .map_err(|e| CustomError::general_error(format!("Error reading {:?}: {e:?}", path)))?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let record: CacheRecord = serde_json::from_str(&line)
+                    .map_err(|e| CustomError::cache_error(format!("Error parsing record: {e:?}")))?;
+                records.push(record);
+            }
+            records
+        };
+        self.import_records(records)
+    }
+
+    /// Copy the entries of another cache namespace with a compatible provider, model and
+    /// dimensions. Existing entries are kept. Returns the number of entries added.
+    pub fn merge(&self, other: &EmbeddingCache) -> Result<usize, CustomError> {
+        if !self.namespace.is_compatible(&other.namespace) {
+            return Err(CustomError::cache_error(format!(
+                "namespace {} cannot be merged into {}.", other.namespace.name(), self.namespace.name()
+            )));
+        }
+        self.check_writable()?;
+
+        let mut merged = 0;
+        for entry in other.entries() {
+            let (key, value) = entry?;
+            if self.vectors.contains_key(&key)? {
+                continue;
+            }
+            let vector = other.read_vector(&key, &value)?;
+            self.vectors.insert(&key, self.write_vector(&key, &vector)?)?;
+            if let Some(input) = other.get_input(&key)? {
+                self.inputs.insert(&key, self.write_input(&key, &input)?)?;
+            }
+            merged += 1;
+        }
+        self.flush();
+        Ok(merged)
+    }
+
+    /// Remove entries that do not belong to one of the inputs. An entry belongs to an input if
+    /// its stored input text matches or its key is the hash of the input. Returns the number of
+    /// removed entries.
+    pub fn prune(&self, inputs: &HashSet<String>) -> Result<usize, CustomError> {
+        let keys: HashSet<blake3::Hash> = inputs.iter()
+            .map(|input| blake3::hash(input.as_bytes()))
+            .collect();
+
+        let mut remove = Vec::new();
+        for entry in self.entries() {
+            let (key, _) = entry?;
+            let hash_match = <[u8; blake3::OUT_LEN]>::try_from(key.as_ref())
+                .is_ok_and(|bytes| keys.contains(&blake3::Hash::from(bytes)));
+            let input_match = self.get_input(&key)?
+                .is_some_and(|input| inputs.contains(&input));
+            if !hash_match && !input_match {
+                remove.push(key);
+            }
+        }
+
+        for key in &remove {
+            self.remove(key)?;
+        }
+        self.flush();
+        Ok(remove.len())
+    }
+}
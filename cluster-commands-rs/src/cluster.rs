@@ -1,52 +1,125 @@
+use std::collections::HashMap;
 use serde_json::json;
-use ndarray::Array2;
-use linfa_clustering::Dbscan;
-use linfa::prelude::Transformer;
+use ndarray::{Array2, concatenate, Axis};
 use polars::prelude::*;
+use crate::clusterer::{Clusterer, DbscanClusterer};
+use crate::distance::DistanceMetric;
+use crate::metrics::ClusterMetrics;
+use crate::anomaly::AnomalyScorer;
+use crate::exemplars::{DEFAULT_EXEMPLARS, summarize_clusters};
 use crate::embedding::ValueEmbedding;
+use crate::features::get_feature_matrix;
 use crate::errors::CustomError;
 
 
+/// The result of clustering the embeddings.
+#[derive(Debug, Clone)]
+pub struct ClusterMapping {
+    /// A row per value with its cluster, where -1 is noise, its silhouette, whether it is an
+    /// exemplar of its cluster and its anomaly score.
+    pub df: DataFrame,
+    /// A row per cluster with its size, medoid value, exemplar values and centroid.
+    pub summary: DataFrame,
+    pub metrics: ClusterMetrics
+}
+
+
+/// How the embeddings are clustered, summarized and scored.
+pub struct ClusterOptions {
+    pub clusterer: Box<dyn Clusterer>,
+    /// Distances between the vectors, including the appended features, are measured in this metric.
+    pub metric: DistanceMetric,
+    /// Scales the standardized lexical features that are appended to each embedding when set.
+    pub feature_weight: Option<f32>,
+    /// The command line each value was rendered from, which its lexical features are computed
+    /// from. Values that are not in it are their own command line.
+    pub commands: Option<HashMap<String, String>>,
+    /// The exemplars picked for each cluster.
+    pub n_exemplars: usize,
+    pub anomaly: AnomalyScorer
+}
+impl ClusterOptions {
+    pub fn new(clusterer: Box<dyn Clusterer>) -> Self {
+        Self {
+            clusterer,
+            metric: DistanceMetric::default(),
+            feature_weight: None,
+            commands: None,
+            n_exemplars: DEFAULT_EXEMPLARS,
+            anomaly: AnomalyScorer::default()
+        }
+    }
+
+    /// Cluster with DBSCAN.
+    pub fn dbscan(min_points: usize, tolerance: f32) -> Self {
+        Self::new(Box::new(DbscanClusterer::new(min_points, tolerance)))
+    }
+
+    /// Measure distances in a metric.
+    pub fn with_metric(mut self, metric: DistanceMetric) -> Self {
+        self.metric = metric;
+        self
+    }
+
+    /// Append the lexical features of each value, scaled by `feature_weight`, to its embedding.
+    pub fn with_feature_weight(mut self, feature_weight: Option<f32>) -> Self {
+        self.feature_weight = feature_weight;
+        self
+    }
+
+    /// Compute the lexical features of each value from the command line it was rendered from.
+    pub fn with_commands(mut self, commands: HashMap<String, String>) -> Self {
+        self.commands = Some(commands);
+        self
+    }
+
+    /// Pick `n_exemplars` exemplars for each cluster.
+    pub fn with_exemplars(mut self, n_exemplars: usize) -> Self {
+        self.n_exemplars = n_exemplars;
+        self
+    }
+
+    /// Score how anomalous each value is with a scorer.
+    pub fn with_anomaly(mut self, anomaly: AnomalyScorer) -> Self {
+        self.anomaly = anomaly;
+        self
+    }
+}
+
+
+/// Cluster the embeddings, summarize each cluster with its medoid and exemplars and score how
+/// anomalous each value is.
 pub fn get_cluster_mapping(
     value_embeddings: Vec<ValueEmbedding>,
-    min_points: usize,
-    tolerance: f32
-) -> Result<DataFrame, CustomError>{
+    options: &ClusterOptions
+) -> Result<ClusterMapping, CustomError>{
     // Get embeddings for command line values
     let mut _vec_values = Vec::new();
-    let mut _vec_embeddings_flat: Vec<f32> = Vec::new();
     let mut _vec_embeddings_string_vec: Vec<String> = Vec::new();
-    let mut dimentions: Option<usize> = None;
+    let mut _vec_tokens: Vec<u32> = Vec::new();
+    let mut _vec_handling: Vec<String> = Vec::new();
 
     value_embeddings.iter()
         .for_each(|value_embedding| {
             _vec_values.push(value_embedding.value.clone());
-            // Get the embeddings from the response
-            let embedding = value_embedding.response.data
-                .first()
-                .expect("No embedding data!")
-                .embedding
-                .clone();
-
-            // Set dimentions used
-            if None == dimentions {
-                dimentions = Some(embedding.len());
-            }
-
-            _vec_embeddings_flat.extend(&embedding);
-            _vec_embeddings_string_vec.push(format!("{}", json!(&embedding)));
+            _vec_tokens.push(value_embedding.tokens as u32);
+            _vec_handling.push(value_embedding.handling.to_string());
+            _vec_embeddings_string_vec.push(format!("{}", json!(embedding_vector(value_embedding))));
         });
-    
-    let dimentions = dimentions.unwrap();
-    let dataset = Array2::<f32>::from_shape_vec(
-        (_vec_values.len(), dimentions),
-        _vec_embeddings_flat
-    ).unwrap();
-
-    let clusters = Dbscan::params(min_points)
-        .tolerance(tolerance)
-        .transform(&dataset)
-        .unwrap();
+
+    let dataset = get_embedding_matrix(&value_embeddings, options.feature_weight, options.commands.as_ref())?;
+
+    let metric = options.metric;
+    let clusters = options.clusterer.fit_predict(&dataset, metric)?;
+    let metrics = ClusterMetrics::new(&dataset, &clusters, metric);
+    let summaries = summarize_clusters(&dataset, &clusters, metric, options.n_exemplars);
+    let medoids: Vec<usize> = summaries.iter().map(|s| s.medoid).collect();
+    let anomaly_scores = options.anomaly.score(&dataset, metric, &medoids)?;
+
+    let mut is_exemplar = vec![false; clusters.len()];
+    for row in summaries.iter().flat_map(|s| s.exemplars.iter()) {
+        is_exemplar[*row] = true;
+    }
 
     // Map to i64 where -1 is no cluster
     let clusters: Vec::<i64> = clusters.iter()
@@ -56,7 +129,67 @@ pub fn get_cluster_mapping(
     let s1 = Series::new("value", &_vec_values);
     let s2 = Series::new("cluster", clusters);
     let s3 = Series::new("embedding", _vec_embeddings_string_vec);
-    let df = DataFrame::new(vec![s1, s2, s3])?;
+    let s4 = Series::new("input_tokens", _vec_tokens);
+    let s5 = Series::new("input_handling", _vec_handling);
+    let s6 = Series::new("silhouette", &metrics.silhouettes);
+    let s7 = Series::new("is_exemplar", is_exemplar);
+    let s8 = Series::new("anomaly_score", anomaly_scores);
+    let df = DataFrame::new(vec![s1, s2, s3, s4, s5, s6, s7, s8])?;
+
+    let summary = DataFrame::new(vec![
+        Series::new("cluster", summaries.iter().map(|s| s.cluster as i64).collect::<Vec<i64>>()),
+        Series::new("size", summaries.iter().map(|s| s.size as u32).collect::<Vec<u32>>()),
+        Series::new("medoid", summaries.iter().map(|s| _vec_values[s.medoid].clone()).collect::<Vec<String>>()),
+        Series::new("exemplars", summaries.iter()
+            .map(|s| format!("{}", json!(s.exemplars.iter().map(|e| &_vec_values[*e]).collect::<Vec<_>>())))
+            .collect::<Vec<String>>()),
+        Series::new("centroid", summaries.iter()
+            .map(|s| format!("{}", json!(s.centroid)))
+            .collect::<Vec<String>>())
+    ])?;
+
+    Ok(ClusterMapping {
+        df,
+        summary,
+        metrics
+    })
+}
+
+
+/// The embedding vector of a value.
+fn embedding_vector(value_embedding: &ValueEmbedding) -> &[f32] {
+    &value_embedding.response.data
+        .first()
+        .expect("No embedding data!")
+        .embedding
+}
+
+/// The matrix that is clustered, one row per embedding. The standardized lexical features of
+/// each value, scaled by `feature_weight`, are appended when set. The features are computed
+/// from the command line of each value in `commands`, or the value itself.
+pub fn get_embedding_matrix(
+    value_embeddings: &[ValueEmbedding],
+    feature_weight: Option<f32>,
+    commands: Option<&HashMap<String, String>>
+) -> Result<Array2<f32>, CustomError> {
+    let dimentions = value_embeddings.first()
+        .map(|v| embedding_vector(v).len())
+        .ok_or_else(|| CustomError::general_error("No embeddings to cluster."))?;
+    let flat: Vec<f32> = value_embeddings.iter()
+        .flat_map(|v| embedding_vector(v).iter().copied())
+        .collect();
+    let mut dataset = Array2::<f32>::from_shape_vec((value_embeddings.len(), dimentions), flat)
+        .map_err(|e| CustomError::general_error(format!("Embeddings have different dimensions: {e:?}")))?;
+
+    // Append the weighted lexical features to each embedding
+    if let Some(weight) = feature_weight {
+        let values: Vec<String> = value_embeddings.iter()
+            .map(|v| commands.and_then(|c| c.get(&v.value)).unwrap_or(&v.value).clone())
+            .collect();
+        let features = get_feature_matrix(&values) * weight;
+        dataset = concatenate(Axis(1), &[dataset.view(), features.view()])
+            .map_err(|e| CustomError::general_error(format!("Error appending features: {e:?}")))?;
+    }
 
-    Ok(df)
-}
\ No newline at end of file
+    Ok(dataset)
+}
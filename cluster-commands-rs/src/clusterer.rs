@@ -0,0 +1,408 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+use ndarray::Array2;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use linfa::DatasetBase;
+use linfa::prelude::{Fit, Predict, Transformer};
+use linfa_clustering::{Dbscan, GaussianMixtureModel, KMeans, Optics};
+use crate::distance::{DistanceMetric, NeighborSearch};
+use crate::hierarchy::{Linkage, agglomerative_merges, cut_merges, hdbscan};
+use crate::errors::CustomError;
+
+
+/// A clustering algorithm that assigns each row of a dataset to a cluster.
+pub trait Clusterer: Send + Sync {
+    /// The name of the algorithm.
+    fn name(&self) -> &str;
+
+    /// Assign each row to a cluster, or None when the row is noise. Tolerances and thresholds
+    /// are distances in `metric`.
+    fn fit_predict(&self, dataset: &Array2<f32>, metric: DistanceMetric) -> Result<Vec<Option<usize>>, CustomError>;
+}
+
+
+/// The clustering algorithms that can be selected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClusterAlgorithm {
+    Dbscan,
+    Hdbscan,
+    Optics,
+    KMeans,
+    GaussianMixture,
+    Agglomerative
+}
+impl std::str::FromStr for ClusterAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "dbscan" => Ok(Self::Dbscan),
+            "hdbscan" => Ok(Self::Hdbscan),
+            "optics" => Ok(Self::Optics),
+            "kmeans" => Ok(Self::KMeans),
+            "gmm" => Ok(Self::GaussianMixture),
+            "agglomerative" => Ok(Self::Agglomerative),
+            _ => Err(format!("invalid cluster algorithm: {s}"))
+        }
+    }
+}
+impl fmt::Display for ClusterAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Dbscan => write!(f, "dbscan"),
+            Self::Hdbscan => write!(f, "hdbscan"),
+            Self::Optics => write!(f, "optics"),
+            Self::KMeans => write!(f, "kmeans"),
+            Self::GaussianMixture => write!(f, "gmm"),
+            Self::Agglomerative => write!(f, "agglomerative")
+        }
+    }
+}
+
+
+/// Check that a dataset has at least `n_clusters` rows.
+fn check_cluster_count(dataset: &Array2<f32>, n_clusters: usize) -> Result<(), CustomError> {
+    if n_clusters == 0 || n_clusters > dataset.nrows() {
+        return Err(CustomError::general_error(format!(
+            "Cannot find {n_clusters} clusters in {} embeddings.", dataset.nrows()
+        )));
+    }
+    Ok(())
+}
+
+
+/// DBSCAN with a single neighborhood radius. A cosine tolerance is applied as the equivalent
+/// L2 radius of the normalized vectors.
+#[derive(Debug, Clone)]
+pub struct DbscanClusterer {
+    pub min_points: usize,
+    pub tolerance: f32
+}
+impl DbscanClusterer {
+    pub fn new(min_points: usize, tolerance: f32) -> Self {
+        Self {
+            min_points,
+            tolerance
+        }
+    }
+}
+impl Clusterer for DbscanClusterer {
+    fn name(&self) -> &str {
+        "dbscan"
+    }
+
+    fn fit_predict(&self, dataset: &Array2<f32>, metric: DistanceMetric) -> Result<Vec<Option<usize>>, CustomError> {
+        let dataset = metric.prepare(dataset);
+        let clusters = Dbscan::params(self.min_points)
+            .tolerance(metric.l2_radius(self.tolerance))
+            .transform(dataset.as_ref())
+            .map_err(|e| CustomError::general_error(format!("Error running DBSCAN: {e:?}")))?;
+        Ok(clusters.to_vec())
+    }
+}
+
+
+/// DBSCAN with the neighborhoods of a `NeighborSearch` over the rows of the dataset, such as an
+/// approximate nearest-neighbor index, so rows are never compared one by one. The tolerance is
+/// a distance in the metric of the search.
+#[derive(Debug, Clone)]
+pub struct NeighborDbscanClusterer {
+    pub min_points: usize,
+    pub tolerance: f32,
+    pub search: Arc<dyn NeighborSearch>
+}
+impl NeighborDbscanClusterer {
+    pub fn new(min_points: usize, tolerance: f32, search: Arc<dyn NeighborSearch>) -> Self {
+        Self {
+            min_points,
+            tolerance,
+            search
+        }
+    }
+}
+impl Clusterer for NeighborDbscanClusterer {
+    fn name(&self) -> &str {
+        "dbscan"
+    }
+
+    fn fit_predict(&self, dataset: &Array2<f32>, metric: DistanceMetric) -> Result<Vec<Option<usize>>, CustomError> {
+        if self.search.rows() != dataset.nrows() || self.search.metric() != metric {
+            return Err(CustomError::general_error(format!(
+                "The neighbor search has {} {} rows, the dataset has {} {} rows.",
+                self.search.rows(), self.search.metric(), dataset.nrows(), metric
+            )));
+        }
+        Ok(dbscan_w_neighbors(self.search.as_ref(), self.min_points, self.tolerance))
+    }
+}
+
+
+/// DBSCAN over the neighborhoods of a `NeighborSearch`, with the same labels as the linfa
+/// DBSCAN of `DbscanClusterer` for exact searches. A row is a core row when `min_points` rows,
+/// counting itself, are within `tolerance`.
+pub fn dbscan_w_neighbors(search: &(impl NeighborSearch + ?Sized), min_points: usize, tolerance: f32) -> Vec<Option<usize>> {
+    let rows = search.rows();
+    let mut labels: Vec<Option<usize>> = vec![None; rows];
+    let mut queued = vec![false; rows];
+    let mut queue = VecDeque::new();
+    let mut cluster = 0;
+
+    for row in 0..rows {
+        if labels[row].is_some() || search.range(row, tolerance).len() < min_points {
+            continue;
+        }
+        labels[row] = Some(cluster);
+        queue.push_back(row);
+        queued[row] = true;
+
+        while let Some(candidate) = queue.pop_front() {
+            labels[candidate] = Some(cluster);
+            let neighbors = search.range(candidate, tolerance);
+            if neighbors.len() < min_points {
+                continue;
+            }
+            for (neighbor, _) in neighbors {
+                if labels[neighbor].is_none() && !queued[neighbor] {
+                    queue.push_back(neighbor);
+                    queued[neighbor] = true;
+                }
+            }
+        }
+        cluster += 1;
+    }
+    labels
+}
+
+
+/// OPTICS ordering with clusters extracted at `tolerance`, like DBSCAN at that radius. The
+/// ordering is computed up to `max_tolerance`, which is unbounded by default. Cosine
+/// tolerances are applied as the equivalent L2 radius of the normalized vectors.
+#[derive(Debug, Clone)]
+pub struct OpticsClusterer {
+    pub min_points: usize,
+    pub tolerance: f32,
+    pub max_tolerance: f32
+}
+impl OpticsClusterer {
+    pub fn new(min_points: usize, tolerance: f32) -> Self {
+        Self {
+            min_points,
+            tolerance,
+            max_tolerance: f32::INFINITY
+        }
+    }
+
+    /// Set the largest neighborhood radius used for the ordering.
+    pub fn with_max_tolerance(mut self, max_tolerance: f32) -> Self {
+        self.max_tolerance = max_tolerance;
+        self
+    }
+}
+impl Clusterer for OpticsClusterer {
+    fn name(&self) -> &str {
+        "optics"
+    }
+
+    fn fit_predict(&self, dataset: &Array2<f32>, metric: DistanceMetric) -> Result<Vec<Option<usize>>, CustomError> {
+        let dataset = metric.prepare(dataset);
+        let tolerance = metric.l2_radius(self.tolerance);
+        let analysis = Optics::params(self.min_points)
+            .tolerance(metric.l2_radius(self.max_tolerance))
+            .transform(dataset.view())
+            .map_err(|e| CustomError::general_error(format!("Error running OPTICS: {e:?}")))?;
+
+        // A sample that is not reachable within the tolerance starts a cluster when it is a
+        // core point at the tolerance, and is noise otherwise. The first sample of the ordering
+        // can have a reachability distance, so only samples after a cluster is started join it.
+        let mut labels = vec![None; dataset.nrows()];
+        let mut current: Option<usize> = None;
+        let mut next = 0;
+        for sample in analysis.iter() {
+            let reachable = current.is_some() && sample.reachability_distance()
+                .is_some_and(|d| d <= tolerance);
+            if !reachable {
+                let core = sample.core_distance()
+                    .is_some_and(|d| d <= tolerance);
+                current = core.then(|| {
+                    next += 1;
+                    next - 1
+                });
+            }
+            labels[sample.index()] = current;
+        }
+        Ok(labels)
+    }
+}
+
+
+/// K-means with a fixed number of clusters. Every row is assigned to a cluster.
+#[derive(Debug, Clone)]
+pub struct KMeansClusterer {
+    pub n_clusters: usize,
+    pub max_iterations: u64,
+    pub seed: u64
+}
+impl KMeansClusterer {
+    pub fn new(n_clusters: usize) -> Self {
+        Self {
+            n_clusters,
+            max_iterations: 300,
+            seed: 42
+        }
+    }
+
+    pub fn with_max_iterations(mut self, max_iterations: u64) -> Self {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+impl Clusterer for KMeansClusterer {
+    fn name(&self) -> &str {
+        "kmeans"
+    }
+
+    fn fit_predict(&self, dataset: &Array2<f32>, metric: DistanceMetric) -> Result<Vec<Option<usize>>, CustomError> {
+        let dataset = metric.prepare(dataset);
+        check_cluster_count(&dataset, self.n_clusters)?;
+        let model = KMeans::params_with_rng(self.n_clusters, StdRng::seed_from_u64(self.seed))
+            .max_n_iterations(self.max_iterations)
+            .fit(&DatasetBase::from(dataset.view()))
+            .map_err(|e| CustomError::general_error(format!("Error running k-means: {e:?}")))?;
+        Ok(model.predict(dataset.as_ref()).iter().map(|c| Some(*c)).collect())
+    }
+}
+
+
+/// A Gaussian mixture with full covariances. Every row is assigned to its most likely
+/// component. The covariances grow with the square of the dimensions.
+#[derive(Debug, Clone)]
+pub struct GaussianMixtureClusterer {
+    pub n_clusters: usize,
+    pub max_iterations: u64,
+    pub seed: u64
+}
+impl GaussianMixtureClusterer {
+    pub fn new(n_clusters: usize) -> Self {
+        Self {
+            n_clusters,
+            max_iterations: 100,
+            seed: 42
+        }
+    }
+
+    pub fn with_max_iterations(mut self, max_iterations: u64) -> Self {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+impl Clusterer for GaussianMixtureClusterer {
+    fn name(&self) -> &str {
+        "gmm"
+    }
+
+    fn fit_predict(&self, dataset: &Array2<f32>, metric: DistanceMetric) -> Result<Vec<Option<usize>>, CustomError> {
+        let dataset = metric.prepare(dataset);
+        check_cluster_count(&dataset, self.n_clusters)?;
+        let model = GaussianMixtureModel::params_with_rng(self.n_clusters, StdRng::seed_from_u64(self.seed))
+            .max_n_iterations(self.max_iterations)
+            .fit(&DatasetBase::from(dataset.view()))
+            .map_err(|e| CustomError::general_error(format!("Error fitting Gaussian mixture: {e:?}")))?;
+        Ok(model.predict(dataset.as_ref()).iter().map(|c| Some(*c)).collect())
+    }
+}
+
+
+/// HDBSCAN, which finds clusters of varying density. `min_samples` sets the neighborhood used
+/// for density and defaults to `min_cluster_size`.
+#[derive(Debug, Clone)]
+pub struct HdbscanClusterer {
+    pub min_cluster_size: usize,
+    pub min_samples: Option<usize>
+}
+impl HdbscanClusterer {
+    pub fn new(min_cluster_size: usize) -> Self {
+        Self {
+            min_cluster_size,
+            min_samples: None
+        }
+    }
+
+    pub fn with_min_samples(mut self, min_samples: usize) -> Self {
+        self.min_samples = Some(min_samples);
+        self
+    }
+}
+impl Clusterer for HdbscanClusterer {
+    fn name(&self) -> &str {
+        "hdbscan"
+    }
+
+    fn fit_predict(&self, dataset: &Array2<f32>, metric: DistanceMetric) -> Result<Vec<Option<usize>>, CustomError> {
+        let dataset = metric.prepare(dataset);
+        let min_samples = self.min_samples.unwrap_or(self.min_cluster_size);
+        hdbscan(&dataset, self.min_cluster_size, min_samples, metric)
+    }
+}
+
+
+/// Agglomerative clustering cut into `n_clusters` clusters or at a distance threshold. Every
+/// row is assigned to a cluster. Needs the pairwise distances of all rows in memory.
+#[derive(Debug, Clone)]
+pub struct AgglomerativeClusterer {
+    pub linkage: Linkage,
+    pub n_clusters: Option<usize>,
+    pub distance_threshold: Option<f32>
+}
+impl AgglomerativeClusterer {
+    pub fn new(linkage: Linkage) -> Self {
+        Self {
+            linkage,
+            n_clusters: None,
+            distance_threshold: None
+        }
+    }
+
+    pub fn with_n_clusters(mut self, n_clusters: usize) -> Self {
+        self.n_clusters = Some(n_clusters);
+        self
+    }
+
+    /// Stop merging clusters that are farther apart than the threshold.
+    pub fn with_distance_threshold(mut self, distance_threshold: f32) -> Self {
+        self.distance_threshold = Some(distance_threshold);
+        self
+    }
+}
+impl Clusterer for AgglomerativeClusterer {
+    fn name(&self) -> &str {
+        "agglomerative"
+    }
+
+    fn fit_predict(&self, dataset: &Array2<f32>, metric: DistanceMetric) -> Result<Vec<Option<usize>>, CustomError> {
+        let dataset = metric.prepare(dataset);
+        if self.linkage == Linkage::Ward && metric == DistanceMetric::Cosine {
+            return Err(CustomError::general_error("Ward linkage needs the l2 or normalized-l2 metric."));
+        }
+        match self.n_clusters {
+            Some(n_clusters) => check_cluster_count(&dataset, n_clusters)?,
+            None if self.distance_threshold.is_none() => return Err(CustomError::general_error(
+                "Agglomerative clustering needs a number of clusters or a distance threshold."
+            )),
+            None => {}
+        }
+        let merges = agglomerative_merges(&dataset, self.linkage, metric);
+        Ok(cut_merges(&merges, dataset.nrows(), self.n_clusters, self.distance_threshold.map(|t| t as f64)))
+    }
+}
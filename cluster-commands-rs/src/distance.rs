@@ -0,0 +1,178 @@
+use std::borrow::Cow;
+use std::fmt;
+use ndarray::{Array2, ArrayView1, Axis};
+
+
+/// How the distance between two embedding vectors is measured.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DistanceMetric {
+    /// One minus the cosine similarity, from 0 to 2.
+    Cosine,
+    /// The Euclidean distance of the raw vectors.
+    #[default]
+    L2,
+    /// The Euclidean distance of the vectors scaled to unit length, from 0 to 2.
+    NormalizedL2
+}
+impl std::str::FromStr for DistanceMetric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "cosine" => Ok(Self::Cosine),
+            "l2" => Ok(Self::L2),
+            "normalized-l2" => Ok(Self::NormalizedL2),
+            _ => Err(format!("invalid distance metric: {s}"))
+        }
+    }
+}
+impl fmt::Display for DistanceMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cosine => write!(f, "cosine"),
+            Self::L2 => write!(f, "l2"),
+            Self::NormalizedL2 => write!(f, "normalized-l2")
+        }
+    }
+}
+impl DistanceMetric {
+    /// Does the metric compare the vectors scaled to unit length.
+    pub fn is_normalized(&self) -> bool {
+        matches!(self, Self::Cosine | Self::NormalizedL2)
+    }
+
+    /// Prepare the rows of a dataset for the metric. Rows are scaled to unit length for
+    /// cosine and normalized-l2, rows of zeros are left as is.
+    pub fn prepare<'a>(&self, dataset: &'a Array2<f32>) -> Cow<'a, Array2<f32>> {
+        if !self.is_normalized() {
+            return Cow::Borrowed(dataset);
+        }
+        let mut dataset = dataset.clone();
+        for mut row in dataset.axis_iter_mut(Axis(0)) {
+            let norm = row.dot(&row).sqrt();
+            if norm > 0.0 {
+                row /= norm;
+            }
+        }
+        Cow::Owned(dataset)
+    }
+
+    /// The distance between two rows prepared with `prepare`.
+    pub fn distance(&self, a: ArrayView1<f32>, b: ArrayView1<f32>) -> f32 {
+        match self {
+            Self::Cosine => (1.0 - a.dot(&b)).clamp(0.0, 2.0),
+            Self::L2 | Self::NormalizedL2 => a.iter()
+                .zip(b.iter())
+                .map(|(a, b)| (a - b).powi(2))
+                .sum::<f32>()
+                .sqrt()
+        }
+    }
+
+    /// The Euclidean distance between prepared rows that is the same as `distance` in this
+    /// metric. Unit vectors with a cosine distance `d` are `sqrt(2d)` apart, so L2 based
+    /// algorithms can use a cosine tolerance.
+    pub fn l2_radius(&self, distance: f32) -> f32 {
+        match self {
+            Self::Cosine => (2.0 * distance).sqrt(),
+            Self::L2 | Self::NormalizedL2 => distance
+        }
+    }
+}
+
+
+/// The distance from each row of a dataset to every row, sorted from the nearest. Computed
+/// once so many clusterings of the same rows can reuse it. Holds the square of the number of
+/// rows in memory.
+#[derive(Debug, Clone)]
+pub struct PairwiseDistances {
+    metric: DistanceMetric,
+    neighbors: Vec<Vec<(u32, f32)>>
+}
+impl PairwiseDistances {
+    /// Compute the distances between the rows of a dataset in `metric`.
+    pub fn new(dataset: &Array2<f32>, metric: DistanceMetric) -> Self {
+        let dataset = metric.prepare(dataset);
+        let rows = dataset.nrows();
+        let mut neighbors: Vec<Vec<(u32, f32)>> = (0..rows)
+            .map(|i| {
+                let mut row = Vec::with_capacity(rows);
+                row.push((i as u32, 0.0));
+                row
+            })
+            .collect();
+        for i in 0..rows {
+            for j in (i + 1)..rows {
+                let distance = metric.distance(dataset.row(i), dataset.row(j));
+                neighbors[i].push((j as u32, distance));
+                neighbors[j].push((i as u32, distance));
+            }
+        }
+        // The stable sort keeps each row ahead of duplicates at a distance of 0.
+        for row in neighbors.iter_mut() {
+            row.sort_by(|a, b| a.1.total_cmp(&b.1));
+        }
+        Self { metric, neighbors }
+    }
+
+    /// The number of rows.
+    pub fn len(&self) -> usize {
+        self.neighbors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.neighbors.is_empty()
+    }
+
+    /// Every row and its distance to `row`, from the nearest, starting with `row` itself.
+    pub fn neighbors(&self, row: usize) -> &[(u32, f32)] {
+        &self.neighbors[row]
+    }
+
+    /// The rows within `radius` of `row`, counting `row` itself.
+    pub fn within(&self, row: usize, radius: f32) -> &[(u32, f32)] {
+        let neighbors = &self.neighbors[row];
+        &neighbors[..neighbors.partition_point(|(_, d)| *d <= radius)]
+    }
+}
+impl NeighborSearch for PairwiseDistances {
+    fn rows(&self) -> usize {
+        self.len()
+    }
+
+    fn metric(&self) -> DistanceMetric {
+        self.metric
+    }
+
+    fn knn(&self, row: usize, k: usize) -> Vec<(usize, f32)> {
+        self.neighbors(row)
+            .iter()
+            .filter(|(other, _)| *other as usize != row)
+            .take(k)
+            .map(|(other, d)| (*other as usize, *d))
+            .collect()
+    }
+
+    fn range(&self, row: usize, radius: f32) -> Vec<(usize, f32)> {
+        self.within(row, radius)
+            .iter()
+            .map(|(other, d)| (*other as usize, *d))
+            .collect()
+    }
+}
+
+
+/// Nearest-neighbor queries between the rows of a dataset, in a `DistanceMetric`.
+pub trait NeighborSearch: fmt::Debug + Send + Sync {
+    /// The number of rows.
+    fn rows(&self) -> usize;
+
+    /// The metric of the distances.
+    fn metric(&self) -> DistanceMetric;
+
+    /// The `k` nearest rows to `row` other than itself and their distances, from the nearest.
+    fn knn(&self, row: usize, k: usize) -> Vec<(usize, f32)>;
+
+    /// The rows within `radius` of `row` and their distances, starting with `row` itself.
+    fn range(&self, row: usize, radius: f32) -> Vec<(usize, f32)>;
+}
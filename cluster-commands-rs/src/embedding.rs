@@ -1,275 +1,904 @@
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::sync::Arc;
-use openai_api_rs::v1::api::OpenAIClient;
-use openai_api_rs::v1::embedding::{EmbeddingResponse, EmbeddingRequest};
-use sled::Db;
-use sled::transaction::TransactionResult;
-use tokio::sync::Semaphore;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::fmt;
+use std::time::Duration;
+use serde::Serialize;
+use openai_api_rs::v1::embedding::EmbeddingResponse;
+use crate::cache::{CacheNamespace, EmbeddingCache, VectorDType};
+use crate::encryption::{CacheCipher, KeySource};
 use crate::errors::CustomError;
-
-
-/// Get an embedding response
-async fn get_embedding(
-    client: Arc<OpenAIClient>, 
-    model: String, 
-    dimensions: Option<i32>, 
-    input: impl AsRef<str>
-) -> Result<EmbeddingResponse, CustomError> {
-    let mut request = EmbeddingRequest::new(
-        model.clone(), 
-        input.as_ref().to_string()
-    );
-    request.dimensions = dimensions;
-    client.embedding(request).await.map_err(|e|e.into())
-}
+use crate::provider::{EmbeddingProvider, OpenAIProvider, response_from_vectors};
+use crate::retry::{AdaptiveLimiter, RetryPolicy};
+use crate::template::InputTemplate;
+use crate::usage::{Budget, PriceTable, UsageCounters, UsageReport, estimate_cost};
+use crate::tokens::{
+    DEFAULT_MAX_INPUT_TOKENS, InputHandling, LongInputStrategy, PreparedInput,
+    combine_chunk_embeddings, count_tokens
+};
 
 
 #[derive(Debug)]
 pub struct ValueEmbedding{
     pub value: String, 
-    pub response: EmbeddingResponse
+    pub response: EmbeddingResponse,
+    /// The number of tokens in the value.
+    pub tokens: usize,
+    /// Whether the value was embedded whole, truncated or chunked.
+    pub handling: InputHandling,
+    /// The key of the value's cache entry.
+    pub key: blake3::Hash,
+    /// Whether the value was embedded by the uncached offline fallback, so its vector is not
+    /// from the embedding space of the cache.
+    pub fallback: bool
 }
 impl ValueEmbedding {
     pub fn new(value: String, response: EmbeddingResponse) -> Self {
         Self {
+            tokens: count_tokens(&value),
+            key: blake3::hash(value.as_bytes()),
             value, 
-            response
+            response,
+            handling: InputHandling::Full,
+            fallback: false
+        }
+    }
+
+    /// Create a ValueEmbedding recording how the input was prepared.
+    pub fn from_prepared(input: &PreparedInput, response: EmbeddingResponse) -> Self {
+        Self {
+            value: input.value.clone(),
+            response,
+            tokens: input.tokens,
+            handling: input.handling,
+            key: input.cache_key(),
+            fallback: false
+        }
+    }
+}
+
+
+/// The request latency assumed when estimating the runtime of a plan.
+pub const PLAN_REQUEST_LATENCY: Duration = Duration::from_secs(1);
+
+
+/// The result of checking the cache for inputs.
+struct CacheLookup<'a> {
+    /// The cached responses of unique inputs.
+    responses: HashMap<String, (PreparedInput, EmbeddingResponse)>,
+    /// The unique inputs that are not cached.
+    misses: Vec<PreparedInput>,
+    /// The number of times each input occurs.
+    occurrences: HashMap<&'a String, usize>
+}
+
+
+/// The work needed to embed inputs, estimated without sending requests.
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbeddingPlan {
+    pub model: String,
+    pub inputs: usize,
+    pub unique_inputs: usize,
+    pub cache_hits: usize,
+    pub cache_misses: usize,
+    /// Cache misses that are longer than the token limit and will be truncated.
+    pub truncated: usize,
+    /// The texts sent to the API, including each chunk of chunked inputs.
+    pub chunks: usize,
+    /// The tokens of the cache misses.
+    pub tokens: u64,
+    pub requests: usize,
+    /// The estimated cost in USD if the model has a price.
+    pub estimated_cost: Option<f64>,
+    /// The runtime assuming PLAN_REQUEST_LATENCY per request and no throttling.
+    pub estimated_runtime: Duration,
+    /// The tokens or cost of the cache misses exceed the budget.
+    pub exceeds_budget: bool
+}
+impl fmt::Display for EmbeddingPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Model: {}", self.model)?;
+        writeln!(f, "Inputs: {} ({} unique)", self.inputs, self.unique_inputs)?;
+        writeln!(f, "Cache hits: {}", self.cache_hits)?;
+        writeln!(f, "Cache misses: {} ({} truncated, {} texts)", self.cache_misses, self.truncated, self.chunks)?;
+        writeln!(f, "Tokens: {}", self.tokens)?;
+        writeln!(f, "Requests: {}", self.requests)?;
+        match self.estimated_cost {
+            Some(cost) => writeln!(f, "Estimated cost: ${cost:.4}")?,
+            None => writeln!(f, "Estimated cost: no price for model")?
+        }
+        write!(f, "Estimated runtime: {:?}", self.estimated_runtime)?;
+        if self.exceeds_budget {
+            write!(f, "\nThe cache misses exceed the budget.")?;
+        }
+        Ok(())
+    }
+}
+
+
+/// How an offline EmbeddingsHandler handles inputs that are not cached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OfflineMissPolicy {
+    /// Fail the whole call.
+    Fail,
+    /// Return a failure for the input.
+    Skip,
+    /// Embed the input with the fallback provider.
+    Fallback
+}
+impl std::str::FromStr for OfflineMissPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fail" => Ok(Self::Fail),
+            "skip" => Ok(Self::Skip),
+            "fallback" => Ok(Self::Fallback),
+            _ => Err(format!("invalid offline miss policy: {s}"))
+        }
+    }
+}
+impl fmt::Display for OfflineMissPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fail => write!(f, "fail"),
+            Self::Skip => write!(f, "skip"),
+            Self::Fallback => write!(f, "fallback")
+        }
+    }
+}
+
+
+/// An input that could not be embedded.
+#[derive(Debug, Clone)]
+pub struct EmbeddingFailure {
+    pub value: String,
+    pub reason: String
+}
+
+
+/// The embedding of an input or why it failed.
+pub type EmbeddingResult = Result<ValueEmbedding, EmbeddingFailure>;
+
+
+/// The number of unique inputs of a `get_embeddings` call that are done.
+#[derive(Debug, Clone, Copy)]
+pub struct EmbeddingProgress {
+    /// Inputs that were embedded, failed or found in the cache.
+    pub completed: usize,
+    pub total: usize,
+    pub cache_hits: usize,
+    pub failed: usize
+}
+
+
+/// Called with the progress of `get_embeddings`.
+pub type ProgressCallback = Arc<dyn Fn(EmbeddingProgress) + Send + Sync>;
+
+
+/// The provider that embeds cache misses and the cache its vectors are written to.
+type MissTarget = (Arc<dyn EmbeddingProvider>, Option<EmbeddingCache>);
+
+
+/// Counts the inputs done by the embedding tasks and reports them to the callback.
+struct ProgressTracker {
+    total: usize,
+    cache_hits: usize,
+    completed: AtomicUsize,
+    failed: AtomicUsize,
+    callback: Option<ProgressCallback>
+}
+impl ProgressTracker {
+    fn new(total: usize, cache_hits: usize, callback: Option<ProgressCallback>) -> Self {
+        Self {
+            total,
+            cache_hits,
+            completed: AtomicUsize::new(cache_hits),
+            failed: AtomicUsize::new(0),
+            callback
+        }
+    }
+
+    fn add_completed(&self, inputs: usize) {
+        self.completed.fetch_add(inputs, Ordering::SeqCst);
+        self.report();
+    }
+
+    fn add_failed(&self, inputs: usize) {
+        self.failed.fetch_add(inputs, Ordering::SeqCst);
+        self.add_completed(inputs);
+    }
+
+    fn report(&self) {
+        if let Some(callback) = &self.callback {
+            callback(EmbeddingProgress {
+                completed: self.completed.load(Ordering::SeqCst),
+                total: self.total,
+                cache_hits: self.cache_hits,
+                failed: self.failed.load(Ordering::SeqCst)
+            });
+        }
+    }
+}
+
+
+/// Split a batch response into a single-input response for each input. Chunked inputs are
+/// combined with a token weighted average and the batch usage is divided by token counts.
+fn split_batch_response(batch: &[PreparedInput], response: EmbeddingResponse) -> Result<Vec<EmbeddingResponse>, CustomError> {
+    let chunk_count: usize = batch.iter().map(|input| input.chunks.len()).sum();
+    let mut vectors: Vec<Option<Vec<f32>>> = vec![None; chunk_count];
+    for data in response.data {
+        let index = data.index as usize;
+        match vectors.get_mut(index) {
+            Some(slot) => *slot = Some(data.embedding),
+            None => return Err(CustomError::general_error(format!(
+                "Embedding index {index} is out of range for a batch of {chunk_count}."
+            )))
+        }
+    }
+
+    let batch_tokens: usize = batch.iter().map(|input| input.chunk_tokens.iter().sum::<usize>()).sum();
+    let prompt_tokens = response.usage.prompt_tokens as usize;
+
+    let mut vectors = vectors.into_iter();
+    let mut responses = Vec::with_capacity(batch.len());
+    for input in batch {
+        let chunk_vectors = vectors.by_ref()
+            .take(input.chunks.len())
+            .collect::<Option<Vec<Vec<f32>>>>()
+            .ok_or_else(|| CustomError::general_error(format!(
+                "Batch response had no embedding for {}", input.value
+            )))?;
+        let vector = if chunk_vectors.len() == 1 {
+            chunk_vectors.into_iter().next().expect("One vector.")
+        } else {
+            combine_chunk_embeddings(&chunk_vectors, &input.chunk_tokens)
+        };
+
+        let input_tokens: usize = input.chunk_tokens.iter().sum();
+        let tokens = prompt_tokens * input_tokens / batch_tokens.max(1);
+        responses.push(response_from_vectors(&response.model, vec![vector], tokens as i32)?);
+    }
+    Ok(responses)
+}
+
+
+/// The token usage and budget of the requests of an EmbeddingsHandler.
+#[derive(Clone)]
+struct UsageLimit {
+    counters: Arc<UsageCounters>,
+    budget: Budget,
+    price: Option<f64>
+}
+impl UsageLimit {
+    fn is_exceeded(&self) -> bool {
+        self.budget.is_exceeded(self.counters.prompt_tokens(), self.price)
+    }
+}
+
+
+/// Send a batch, retrying transient errors. Rate-limit responses and exhausted rate-limit
+/// headers pause all requests and lower the number of parallel requests. No request is sent
+/// once the budget is exceeded.
+async fn embed_batch_with_retry(
+    provider: Arc<dyn EmbeddingProvider>,
+    limiter: &Arc<AdaptiveLimiter>,
+    retry_policy: &RetryPolicy,
+    usage: &UsageLimit,
+    batch: Vec<String>
+) -> Result<EmbeddingResponse, CustomError> {
+    let mut attempt = 0;
+    loop {
+        // Acquire permit before sending request.
+        let permit = limiter.acquire().await;
+        if usage.is_exceeded() {
+            return Err(CustomError::budget_error("Embedding budget exceeded."));
+        }
+        usage.counters.add_request();
+        let result = provider.embed_batch(batch.clone()).await;
+        if let Ok(response) = &result {
+            // Count the tokens sent when the provider does not report usage.
+            let prompt_tokens = match response.usage.prompt_tokens {
+                t if t > 0 => t as u64,
+                _ => batch.iter().map(|input| count_tokens(input) as u64).sum()
+            };
+            usage.counters.add_prompt_tokens(prompt_tokens);
+        }
+        // Drop the permit after the request has been sent.
+        drop(permit);
+
+        match result {
+            Ok(response) => {
+                limiter.on_success();
+                if let Some(wait) = provider.rate_limit().and_then(|r| r.wait()) {
+                    debug!("Rate limit exhausted, pausing requests for {:?}.", wait);
+                    limiter.pause(wait);
+                }
+                return Ok(response);
+            },
+            Err(e) if e.is_retryable() && attempt < retry_policy.max_retries => {
+                let delay = retry_policy.delay(attempt, e.retry_after());
+                warn!(
+                    "Embedding request failed (attempt {} of {}), retrying in {:?}: {}",
+                    attempt + 1,
+                    retry_policy.max_retries + 1,
+                    delay,
+                    e.message
+                );
+                if e.is_rate_limited() {
+                    limiter.on_throttle();
+                    limiter.pause(delay);
+                } else {
+                    tokio::time::sleep(delay).await;
+                }
+                attempt += 1;
+            },
+            Err(e) => return Err(e)
         }
     }
 }
 
 
 pub struct EmbeddingsHandler {
-    client: Arc<OpenAIClient>,
+    provider: Arc<dyn EmbeddingProvider>,
     model: String,
     dimensions: Option<i32>,
     parallel_requests: usize,
-    cache: Option<Db>
+    min_parallel_requests: usize,
+    retry_policy: RetryPolicy,
+    max_batch_inputs: usize,
+    max_batch_tokens: usize,
+    max_input_tokens: usize,
+    long_input_strategy: LongInputStrategy,
+    vector_dtype: VectorDType,
+    cache: Option<EmbeddingCache>,
+    price_table: PriceTable,
+    budget: Budget,
+    usage: Arc<UsageCounters>,
+    progress: Option<ProgressCallback>,
+    offline: Option<OfflineMissPolicy>,
+    fallback_provider: Option<Arc<dyn EmbeddingProvider>>,
+    input_template: Option<String>
 }
 impl EmbeddingsHandler {
-    /// Create Embeddings struct for handling embedding operations.
+    /// Create Embeddings struct for handling embedding operations with the OpenAI API.
     pub fn new(api_key: String, model: impl AsRef<str>, dimensions: Option<i32>, parallel_requests: usize) -> Self {
-        let client = Arc::new(OpenAIClient::new(api_key));
+        let provider = OpenAIProvider::new(api_key, model, dimensions);
+        Self::from_provider(Arc::new(provider), parallel_requests)
+    }
+
+    /// Create Embeddings struct for handling embedding operations with any EmbeddingProvider.
+    pub fn from_provider(provider: Arc<dyn EmbeddingProvider>, parallel_requests: usize) -> Self {
         Self {
-            client,
-            model: model.as_ref().to_string(),
-            dimensions,
+            model: provider.model().to_string(),
+            dimensions: provider.dimensions(),
+            provider,
             parallel_requests,
-            cache: None
+            min_parallel_requests: 1,
+            retry_policy: RetryPolicy::default(),
+            max_batch_inputs: 256,
+            max_batch_tokens: 100_000,
+            max_input_tokens: DEFAULT_MAX_INPUT_TOKENS,
+            long_input_strategy: LongInputStrategy::Truncate,
+            vector_dtype: VectorDType::F32,
+            cache: None,
+            price_table: PriceTable::default(),
+            budget: Budget::default(),
+            usage: Arc::new(UsageCounters::default()),
+            progress: None,
+            offline: None,
+            fallback_provider: None,
+            input_template: None
         }
     }
 
-    pub fn with_cache(mut self, path: impl AsRef<Path>) -> Result<Self, CustomError> {
-        let path = path.as_ref();
-        if path.is_dir() {
-            // Cache already exists so we need to check meta is applicable to embedding options.
-            let db = sled::open(&path)?;
-            match db.get(b"model") {
-                // Validate that model is correct
-                Ok(Some(value_bytes)) => {
-                    if value_bytes != self.model.as_bytes() {
-                        return Err(CustomError::cache_error(format!(
-                            "{:?} saved model {} does not match current model {}.",
-                            path,
-                            String::from_utf8_lossy(&value_bytes),
-                            self.model
-                        )))
-                    }
-                }
-                Ok(None) => return Err(CustomError::cache_error(format!("{:?} had no model key.", path))),
-                Err(e) => return Err(e.into()),
-            }
+    /// Serve embeddings only from the cache. The provider is never called and cache misses are
+    /// handled by `policy`.
+    pub fn with_offline(mut self, policy: OfflineMissPolicy) -> Self {
+        self.offline = Some(policy);
+        self
+    }
 
-            if let Some(current_dimensions) = self.dimensions {
-                // Validate that dimensions are correct.
-                match db.get(b"dimensions") {
-                    // Validate that dimesions are alligned.
-                    Ok(Some(value_bytes)) => {
-                        let bytes = match <[u8; 4]>::try_from(value_bytes.to_vec()){
-                            Ok(b) => b,
-                            Err(_) => return Err(CustomError::cache_error(format!("dimensions did not have a 4 byte value.")))
-                        };
-                        let cached_dimensions = i32::from_le_bytes(bytes);
-                        if cached_dimensions != current_dimensions {
-                            return Err(CustomError::cache_error(format!(
-                                "{:?} cached dimensions {} do not match current dimensions of {}.",
-                                path,
-                                cached_dimensions,
-                                current_dimensions
-                            )))
-                        }
-                    }
-                    Ok(None) => return Err(CustomError::cache_error(format!("{:?} had no model key.", path))),
-                    Err(e) => return Err(e.into()),
-                }
-            } else {
-                // Ensure there are no dimensions used in cache.
-                if let Some(_) = db.get(b"dimensions").expect("Error reading cache.") {
-                    return Err(CustomError::cache_error(format!(
-                        "{:?} cache contains dimensions and does not match current dimensions of None.",
-                        path
-                    )))
-                }
-            }
+    /// Set the provider that embeds cache misses with the offline fallback policy. Its vectors
+    /// are not cached and must have the dimensions of the cached vectors.
+    pub fn with_fallback_provider(mut self, provider: Arc<dyn EmbeddingProvider>) -> Self {
+        self.fallback_provider = Some(provider);
+        self
+    }
 
-            // Set cache.
-            self.cache = Some(db);
-            Ok(self)
-        } else {
-            // The cache does not exist and we need to add the model and dimensions to it.
-            let db = sled::open(path)?;
-            // Set meta for the cache.
-            let result: TransactionResult<()> = db.transaction(|tx_db| {
-                tx_db.insert(b"model", self.model.as_bytes())?;
-                if let Some(d) = self.dimensions {
-                    let dimention_bytes = d.to_le_bytes();
-                    tx_db.insert(b"dimensions", &dimention_bytes)?;
-                }
-                Ok(())
-            });
-            
-            // Return error if something in the transaction failed.
-            if let Err(e) = result {
-                return Err(CustomError::sled_error(format!("{e:?}")));
-            }
-            
-            // Set cache.
-            self.cache = Some(db);
-            Ok(self)
+    /// Call `progress` as inputs of `get_embeddings` are completed.
+    pub fn with_progress(mut self, progress: ProgressCallback) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Set the prices used to estimate the cost of requests.
+    pub fn with_price_table(mut self, price_table: PriceTable) -> Self {
+        self.price_table = price_table;
+        self
+    }
+
+    /// Stop sending requests once the tokens or estimated cost exceed a budget. Inputs that
+    /// were not embedded are failures in the results.
+    pub fn with_budget(mut self, budget: Budget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Get the tokens, requests, cache use and estimated cost so far.
+    pub fn usage_report(&self) -> UsageReport {
+        self.usage.report(&self.model, self.price(), &self.budget)
+    }
+
+    fn price(&self) -> Option<f64> {
+        self.price_table.price(&self.model)
+    }
+
+    fn usage_limit(&self) -> UsageLimit {
+        UsageLimit {
+            counters: self.usage.clone(),
+            budget: self.budget.clone(),
+            price: self.price()
         }
     }
 
-    pub fn flush(&self) {
-        if let Some(db) = &self.cache {
-            db.flush().expect("Error flushing DB to disk!");
+    /// Set how failed requests are retried.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Set the lowest number of parallel requests used when being throttled.
+    pub fn with_min_parallel_requests(mut self, min_parallel_requests: usize) -> Self {
+        self.min_parallel_requests = min_parallel_requests;
+        self
+    }
+
+    /// Set how inputs longer than `max_input_tokens` are handled.
+    pub fn with_long_input_strategy(mut self, strategy: LongInputStrategy, max_input_tokens: usize) -> Self {
+        self.long_input_strategy = strategy;
+        self.max_input_tokens = max_input_tokens.max(1);
+        self
+    }
+
+    /// Set the maximum number of inputs and tokens sent in a single request.
+    pub fn with_batch_size(mut self, max_batch_inputs: usize, max_batch_tokens: usize) -> Self {
+        self.max_batch_inputs = max_batch_inputs.max(1);
+        self.max_batch_tokens = max_batch_tokens.max(1);
+        self
+    }
+
+    /// Set the template the inputs were rendered with so each template has its own cache
+    /// namespace. Must be set before the cache. The default template uses the namespace of the
+    /// provider, model and dimensions.
+    pub fn with_input_template(mut self, template: &InputTemplate) -> Self {
+        self.input_template = (!template.is_default()).then(|| template.to_string());
+        self
+    }
+
+    /// Use the namespace of the provider, model, dimensions and input template in a cache
+    /// directory.
+    pub fn with_cache(self, path: impl AsRef<Path>) -> Result<Self, CustomError> {
+        self.with_cache_w_key(path, None)
+    }
+
+    /// Use a cache that is encrypted with a key from a passphrase or keyfile. A new cache is
+    /// encrypted when a key is given and an encrypted cache is refused without its key. No cache
+    /// is used when the provider is not cacheable.
+    pub fn with_cache_w_key(mut self, path: impl AsRef<Path>, key: Option<&KeySource>) -> Result<Self, CustomError> {
+        if !self.provider.is_cacheable() {
+            info!("Not caching the {} vectors, they depend on the inputs of the run.", self.model);
+            return Ok(self);
+        }
+        let mut namespace = CacheNamespace::new(self.provider.name(), &self.model, self.dimensions);
+        if let Some(template) = &self.input_template {
+            namespace = namespace.with_template(template);
+        }
+        let cache = EmbeddingCache::open_w_key(path, &namespace, key)?
+            .with_dtype(self.vector_dtype);
+        if cache.is_read_only() {
+            warn!("The cache is from an older schema, new vectors are not cached until it is migrated with `cache migrate`.");
         }
+        // Set cache.
+        self.cache = Some(cache);
+        Ok(self)
     }
 
-    pub async fn get_embeddings(&self, input: Vec<impl AsRef<str>>) -> Result<Vec<ValueEmbedding>, CustomError> {
-        // Define maximum number of parallel requests.
-        let semaphore = Arc::new(Semaphore::new(self.parallel_requests));
+    /// The cache namespace the embeddings are read from and written to, if a cache is set.
+    pub fn cache_namespace(&self) -> Option<&CacheNamespace> {
+        self.cache.as_ref().map(|cache| cache.namespace())
+    }
 
-        // Spawn many tasks that will send requests.
-        let mut task_list = Vec::new();
-        for string_to_vectorize in input {
-            let string_to_vectorize = string_to_vectorize.as_ref().to_string();
-            let semaphore = semaphore.clone();
+    /// The cipher of the cache, if it is encrypted.
+    pub fn cache_cipher(&self) -> Option<&CacheCipher> {
+        self.cache.as_ref().and_then(|cache| cache.cipher())
+    }
 
-            let client = self.client.clone();
-            let model = self.model.clone();
-            let dimensions = self.dimensions.clone();
-            let cache = self.cache.clone();
+    /// Whether cache misses are embedded by the uncached offline fallback.
+    fn embeds_with_fallback(&self) -> bool {
+        self.offline == Some(OfflineMissPolicy::Fallback)
+    }
 
-            let handle = tokio::spawn(async move {
-                // Acquire permit before sending request.
-                let _permit = semaphore.acquire().await.unwrap();
+    /// Set how vectors are stored in the cache.
+    pub fn with_vector_dtype(mut self, dtype: VectorDType) -> Self {
+        self.vector_dtype = dtype;
+        self.cache = self.cache.take().map(|cache| cache.with_dtype(dtype));
+        self
+    }
 
-                let key = blake3::hash(string_to_vectorize.as_bytes());
+    pub fn flush(&self) {
+        if let Some(cache) = &self.cache {
+            cache.flush();
+        }
+    }
 
-                // Check cache first
-                if let Some(cache) = &cache {
-                    // Check if input embedding is in cache.
-                    if let Some(value) = cache.get(key.as_bytes())? {
-                        // Parse cached response into string.
-                        let json_string = String::from_utf8_lossy(&value);
-                        // Create response from cached data.
-                        let response: EmbeddingResponse = match serde_json::from_str(&json_string) {
-                            Err(e) => return Err(CustomError::cache_error(format!("Error parsing cached value! {e:?}"))),
-                            Ok(r) => r
-                        };
-                        // Return cached response.
-                        return Ok(ValueEmbedding::new(
-                            string_to_vectorize,
-                            response
-                        ));
-                    }
+    /// Get the embedding of every input in input order. An input that could not be embedded
+    /// gets an EmbeddingFailure with the reason while the other inputs are still embedded and
+    /// cached. Errors reading the cache fail the whole call.
+    pub async fn get_embeddings(&self, input: Vec<impl AsRef<str>>) -> Result<Vec<EmbeddingResult>, CustomError> {
+        let input: Vec<String> = input.iter()
+            .map(|s| s.as_ref().to_string())
+            .collect();
+
+        // Check the cache first and keep the unique values that need to be embedded.
+        let CacheLookup { mut responses, mut misses, mut occurrences } = self.lookup_cache(&input)?;
+        let mut failures: HashMap<String, EmbeddingFailure> = HashMap::new();
+        self.usage.add_cache_hits(responses.len() as u64);
+        self.usage.add_cache_misses(misses.len() as u64);
+
+        let progress = Arc::new(ProgressTracker::new(
+            occurrences.len(),
+            responses.len(),
+            self.progress.clone()
+        ));
+
+        // Choose who embeds the misses. Offline misses can be skipped or embedded by the fallback.
+        let (provider, cache) = match self.miss_target(misses.len())? {
+            Some(target) => target,
+            None => {
+                progress.add_failed(misses.len());
+                for prepared in misses.drain(..) {
+                    failures.insert(prepared.value.clone(), EmbeddingFailure {
+                        value: prepared.value,
+                        reason: "Not cached and offline.".to_string()
+                    });
                 }
+                (self.provider.clone(), None)
+            }
+        };
+        progress.report();
+
+        // Fallback vectors must have the dimensions of the cached vectors.
+        let expected_dimensions = match self.offline {
+            Some(OfflineMissPolicy::Fallback) => responses.values()
+                .next()
+                .and_then(|(_, response)| response.data.first())
+                .map(|data| data.embedding.len()),
+            _ => None
+        };
+
+        // Limit the number of parallel requests, lowering it when throttled.
+        let limiter = AdaptiveLimiter::new(self.parallel_requests, self.min_parallel_requests);
 
-                // Get response that contains embedding
-                let response = get_embedding(
-                    client,
-                    model,
-                    dimensions,
-                    &string_to_vectorize
-                ).await?;
+        // Spawn a task for each batch of inputs.
+        let mut task_list = Vec::new();
+        for batch in self.get_batches(misses) {
+            let values: Vec<String> = batch.iter().map(|input| input.value.clone()).collect();
+            let limiter = limiter.clone();
+            let retry_policy = self.retry_policy.clone();
+            let provider = provider.clone();
+            let cache = cache.clone();
+            let usage = self.usage_limit();
+            let progress = progress.clone();
+
+            let handle = tokio::spawn(async move {
+                // Get response that contains the embedding of every chunk in the batch
+                let chunks: Vec<String> = batch.iter()
+                    .flat_map(|input| input.chunks.iter().cloned())
+                    .collect();
+                let result = embed_batch_with_retry(
+                    provider,
+                    &limiter,
+                    &retry_policy,
+                    &usage,
+                    chunks
+                ).await
+                    .and_then(|response| split_batch_response(&batch, response));
+                let batch_responses = match result {
+                    Ok(batch_responses) => batch_responses,
+                    Err(e) => {
+                        if e.is_budget_exceeded() {
+                            usage.counters.add_skipped(batch.len() as u64);
+                        }
+                        progress.add_failed(batch.len());
+                        return Err(e);
+                    }
+                };
 
-                // Set cache
+                // Set cache for each input
                 if let Some(cache) = &cache {
-                    cache.insert(
-                        key.as_bytes(), 
-                        serde_json::json!(response)
-                            .to_string()
-                            .as_bytes()
-                    )?;
+                    for (prepared, response) in batch.iter().zip(&batch_responses) {
+                        if let Some(data) = response.data.first() {
+                            if let Err(e) = cache.insert(&prepared.cache_key(), &prepared.value, &data.embedding) {
+                                warn!("Error caching embedding: {}", e.message);
+                            }
+                        }
+                    }
                 }
 
-                // Drop the permit after the request has been sent.
-                drop(_permit);
-
-                Ok( ValueEmbedding::new(
-                    string_to_vectorize,
-                    response
-                ))
+                progress.add_completed(batch.len());
+                Ok::<_, CustomError>(batch.into_iter().zip(batch_responses).collect::<Vec<_>>())
             });
 
-            task_list.push(handle);
+            task_list.push((values, handle));
         }
 
         // Collect responses from tasks.
-        let mut responses = Vec::new();
-        for handle in task_list {
-            let response = match handle.await.expect("Join error!") {
-                Ok(r) => r,
-                Err(e) => return Err(e)
+        let mut fallback_values: HashSet<String> = HashSet::new();
+        for (values, handle) in task_list {
+            let result = handle.await
+                .unwrap_or_else(|e| Err(CustomError::general_error(format!("Join error! {e:?}"))));
+            match result {
+                Ok(batch_responses) => {
+                    for (prepared, response) in batch_responses {
+                        let dimensions = response.data.first().map(|data| data.embedding.len());
+                        match (expected_dimensions, dimensions) {
+                            (Some(expected), Some(dimensions)) if expected != dimensions => {
+                                failures.insert(prepared.value.clone(), EmbeddingFailure {
+                                    reason: format!(
+                                        "Fallback embedding has {dimensions} dimensions, cached embeddings have {expected}."
+                                    ),
+                                    value: prepared.value
+                                });
+                            },
+                            _ => {
+                                if self.embeds_with_fallback() {
+                                    fallback_values.insert(prepared.value.clone());
+                                }
+                                responses.insert(prepared.value.clone(), (prepared, response));
+                            }
+                        }
+                    }
+                },
+                Err(e) => {
+                    for value in values {
+                        failures.insert(value.clone(), EmbeddingFailure {
+                            value,
+                            reason: e.message.clone()
+                        });
+                    }
+                }
+            }
+        }
+
+        if !failures.is_empty() {
+            warn!("{} of {} inputs could not be embedded.", failures.len(), occurrences.len());
+        }
+
+        // Return the results in the order of the input.
+        let mut results = Vec::with_capacity(input.len());
+        for string_to_vectorize in &input {
+            let count = occurrences.get_mut(string_to_vectorize)
+                .expect("Every input is counted.");
+            *count -= 1;
+            if let Some(failure) = failures.get(string_to_vectorize) {
+                results.push(Err(failure.clone()));
+                continue;
+            }
+            // The last occurrence of a value takes the response, earlier ones get a copy.
+            let value_embedding = if *count == 0 {
+                responses.remove(string_to_vectorize)
+                    .map(|(prepared, response)| Ok(ValueEmbedding::from_prepared(&prepared, response)))
+            } else {
+                responses.get(string_to_vectorize)
+                    .map(|(prepared, r)| response_from_vectors(
+                        &r.model,
+                        r.data.iter().map(|d| d.embedding.clone()).collect(),
+                        0
+                    ).map(|response| ValueEmbedding::from_prepared(prepared, response)))
             };
-            responses.push(response);
+            let mut value_embedding = value_embedding.ok_or_else(|| CustomError::general_error(
+                format!("No embedding for {string_to_vectorize}")
+            ))??;
+            value_embedding.fallback = fallback_values.contains(string_to_vectorize);
+            results.push(Ok(value_embedding));
         }
 
-        Ok(responses)
+        Ok(results)
     }
 
-    pub async fn get_embedding(&self, input: impl AsRef<str>) -> Result<ValueEmbedding, CustomError> {
-        let key = blake3::hash(input.as_ref().as_bytes());
+    /// Check the cache for the unique inputs without sending any requests.
+    fn lookup_cache<'a>(&self, input: &'a [String]) -> Result<CacheLookup<'a>, CustomError> {
+        let mut lookup = CacheLookup {
+            responses: HashMap::new(),
+            misses: Vec::new(),
+            occurrences: HashMap::new()
+        };
+        for string_to_vectorize in input {
+            let count = lookup.occurrences.entry(string_to_vectorize).or_insert(0);
+            *count += 1;
+            if *count > 1 {
+                continue;
+            }
+            let prepared = self.prepare_input(string_to_vectorize);
+            match self.get_cached(&prepared.cache_key())? {
+                Some(response) => {
+                    lookup.responses.insert(string_to_vectorize.clone(), (prepared, response));
+                },
+                None => lookup.misses.push(prepared)
+            }
+        }
+        Ok(lookup)
+    }
 
-        if let Some(cache) = &self.cache {
-            // Check if input embedding is in cache.
-            if let Some(value) = cache.get(key.as_bytes())? {
-                // Parse cached response into string.
-                let json_string = String::from_utf8_lossy(&value);
-                // Create response from cached data.
-                let response: EmbeddingResponse = match serde_json::from_str(&json_string) {
-                    Err(e) => return Err(CustomError::cache_error(format!("Error parsing cached value! {e:?}"))),
-                    Ok(r) => r
-                };
-                // Return cached response.
-                return Ok(
-                    ValueEmbedding::new(
-                        input.as_ref().to_string(),
-                        response
-                    )
-                );
+    /// Estimate the requests, tokens, cost and runtime of embedding the inputs using only the
+    /// cache. No requests are sent.
+    pub fn plan(&self, input: Vec<impl AsRef<str>>) -> Result<EmbeddingPlan, CustomError> {
+        let input: Vec<String> = input.iter()
+            .map(|s| s.as_ref().to_string())
+            .collect();
+        let lookup = self.lookup_cache(&input)?;
+
+        let unique_inputs = lookup.occurrences.len();
+        let cache_hits = lookup.responses.len();
+        let cache_misses = lookup.misses.len();
+        let truncated = lookup.misses.iter()
+            .filter(|m| m.handling == InputHandling::Truncated)
+            .count();
+        let chunks: usize = lookup.misses.iter().map(|m| m.chunks.len()).sum();
+        let tokens: u64 = lookup.misses.iter()
+            .map(|m| m.chunk_tokens.iter().sum::<usize>() as u64)
+            .sum();
+        let requests = self.get_batches(lookup.misses).len();
+
+        // Requests are sent in waves of parallel requests.
+        let waves = requests.div_ceil(self.parallel_requests.max(1));
+        let price = self.price();
+
+        Ok(EmbeddingPlan {
+            model: self.model.clone(),
+            inputs: input.len(),
+            unique_inputs,
+            cache_hits,
+            cache_misses,
+            truncated,
+            chunks,
+            tokens,
+            requests,
+            estimated_cost: price.map(|price| estimate_cost(tokens, price)),
+            estimated_runtime: PLAN_REQUEST_LATENCY * waves as u32,
+            exceeds_budget: self.budget.is_exceeded(tokens, price)
+        })
+    }
+
+    /// Count the tokens of an input and truncate or chunk it if it is too long.
+    fn prepare_input(&self, input: impl AsRef<str>) -> PreparedInput {
+        let max_tokens = match self.provider.has_token_limit() {
+            true => self.max_input_tokens,
+            false => usize::MAX
+        };
+        let prepared = PreparedInput::new(input, max_tokens, self.long_input_strategy);
+        if prepared.handling != InputHandling::Full {
+            debug!("Input of {} tokens was {}.", prepared.tokens, prepared.handling);
+        }
+        prepared
+    }
+
+    /// Group inputs into batches capped by the number of chunks and tokens.
+    fn get_batches(&self, inputs: Vec<PreparedInput>) -> Vec<Vec<PreparedInput>> {
+        let mut batches = Vec::new();
+        let mut batch: Vec<PreparedInput> = Vec::new();
+        let mut batch_chunks = 0;
+        let mut batch_tokens = 0;
+        for input in inputs {
+            let chunks = input.chunks.len();
+            let tokens: usize = input.chunk_tokens.iter().sum();
+            if !batch.is_empty() && (
+                batch_chunks + chunks > self.max_batch_inputs ||
+                batch_tokens + tokens > self.max_batch_tokens
+            ) {
+                batches.push(std::mem::take(&mut batch));
+                batch_chunks = 0;
+                batch_tokens = 0;
             }
+            batch_chunks += chunks;
+            batch_tokens += tokens;
+            batch.push(input);
+        }
+        if !batch.is_empty() {
+            batches.push(batch);
         }
+        batches
+    }
+
+    /// Get a response from the cache.
+    fn get_cached(&self, key: &blake3::Hash) -> Result<Option<EmbeddingResponse>, CustomError> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return Ok(None)
+        };
+
+        // Check if input embedding is in cache.
+        match cache.get(key)? {
+            Some(vector) => response_from_vectors(&self.model, vec![vector], 0).map(Some),
+            None => Ok(None)
+        }
+    }
 
-        let mut request = EmbeddingRequest::new(
-            self.model.clone(),
-            input.as_ref().to_string()
-        );
-        request.dimensions = self.dimensions;
-        let response = self.client.embedding(request)
-            .await?;
+    pub async fn get_embedding(&self, input: impl AsRef<str>) -> Result<ValueEmbedding, CustomError> {
+        let prepared = self.prepare_input(input);
+        let key = prepared.cache_key();
+
+        if let Some(response) = self.get_cached(&key)? {
+            // Return cached response.
+            self.usage.add_cache_hits(1);
+            return Ok(ValueEmbedding::from_prepared(&prepared, response));
+        }
+        self.usage.add_cache_misses(1);
+
+        let (provider, cache) = self.miss_target(1)?
+            .ok_or_else(|| CustomError::cache_error(format!(
+                "{} is not cached and the handler is offline.", prepared.value
+            )))?;
+
+        let response = match embed_batch_with_retry(
+            provider,
+            &AdaptiveLimiter::new(1, 1),
+            &self.retry_policy,
+            &self.usage_limit(),
+            prepared.chunks.clone()
+        ).await {
+            Ok(response) => response,
+            Err(e) if e.is_budget_exceeded() => {
+                self.usage.add_skipped(1);
+                return Err(CustomError::budget_error(format!(
+                    "Embedding budget exceeded: {}", self.usage_report()
+                )));
+            },
+            Err(e) => return Err(e)
+        };
+        let response = split_batch_response(std::slice::from_ref(&prepared), response)?
+            .into_iter()
+            .next()
+            .expect("One response per input.");
         
-        if let Some(cache) = &self.cache {
-            cache.insert(
-                key.as_bytes(), 
-                serde_json::json!(response).to_string().as_bytes()
-            )?;
+        if let (Some(cache), Some(data)) = (&cache, response.data.first()) {
+            cache.insert(&key, &prepared.value, &data.embedding)?;
+        }
+
+        let mut value_embedding = ValueEmbedding::from_prepared(&prepared, response);
+        value_embedding.fallback = self.embeds_with_fallback();
+        Ok(value_embedding)
+    }
+
+    /// Get the provider and cache used for cache misses. Offline handlers never use the
+    /// provider: misses fail, are skipped (None) or are embedded by the uncached fallback.
+    fn miss_target(&self, misses: usize) -> Result<Option<MissTarget>, CustomError> {
+        let policy = match self.offline {
+            Some(policy) => policy,
+            None => {
+                let cache = self.cache.clone().filter(|cache| !cache.is_read_only());
+                return Ok(Some((self.provider.clone(), cache)));
+            }
+        };
+        if misses > 0 {
+            info!("{} inputs are not cached, handling them offline with the {} policy.", misses, policy);
         }
 
-        Ok( ValueEmbedding::new(
-            input.as_ref().to_string(),
-            response
-        ))
+        match policy {
+            OfflineMissPolicy::Fail if misses > 0 => Err(CustomError::cache_error(format!(
+                "{misses} inputs are not cached and the handler is offline."
+            ))),
+            // There is nothing to embed.
+            OfflineMissPolicy::Fail => Ok(None),
+            OfflineMissPolicy::Skip => Ok(None),
+            OfflineMissPolicy::Fallback => {
+                let fallback = self.fallback_provider.clone()
+                    .ok_or_else(|| CustomError::general_error("The fallback policy needs a fallback provider."))?;
+                Ok(Some((fallback, None)))
+            }
+        }
     }
 }
 impl Drop for EmbeddingsHandler {
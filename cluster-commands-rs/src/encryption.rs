@@ -0,0 +1,144 @@
+use std::path::PathBuf;
+use argon2::Argon2;
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use chacha20poly1305::aead::{Aead, Payload};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use crate::errors::CustomError;
+
+
+/// Marks a value as encrypted.
+const ENCRYPTED_MAGIC: &[u8; 2] = b"EC";
+const NONCE_LEN: usize = 12;
+const SALT_LEN: usize = 16;
+/// The value encrypted into the cache metadata to check the key when opening.
+const KEY_CHECK: &[u8] = b"evtx_clustering cache key check";
+/// The blake3 context used to derive a key from a keyfile.
+const KEYFILE_CONTEXT: &str = "evtx_clustering 2024 cache keyfile";
+
+
+/// Where the cache encryption key comes from.
+#[derive(Debug, Clone)]
+pub enum KeySource {
+    /// Derive the key from a passphrase with Argon2id.
+    Passphrase(String),
+    /// Derive the key from the contents of a file.
+    Keyfile(PathBuf)
+}
+impl KeySource {
+    fn kdf(&self) -> &'static str {
+        match self {
+            Self::Passphrase(_) => "argon2id",
+            Self::Keyfile(_) => "keyfile-blake3"
+        }
+    }
+
+    fn derive_key(&self, salt: &[u8]) -> Result<[u8; 32], CustomError> {
+        let mut key = [0u8; 32];
+        match self {
+            Self::Passphrase(passphrase) => {
+                Argon2::default()
+                    .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+                    .map_err(|e| CustomError::cache_error(format!("Error deriving cache key: {e}")))?;
+            },
+            Self::Keyfile(path) => {
+                let mut material = salt.to_vec();
+                material.extend(std::fs::read(path)
+                    .map_err(|e| CustomError::cache_error(format!("Error reading keyfile {:?}: {e:?}", path)))?);
+                key = blake3::derive_key(KEYFILE_CONTEXT, &material);
+            }
+        }
+        Ok(key)
+    }
+}
+
+
+/// How a cache is encrypted. Stored in the cache metadata.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptionMeta {
+    pub algorithm: String,
+    pub kdf: String,
+    pub salt: Vec<u8>,
+    /// KEY_CHECK encrypted with the key.
+    pub key_check: Vec<u8>
+}
+
+
+/// Encrypts cache values with ChaCha20-Poly1305. The entry key and purpose are authenticated
+/// with each value so values cannot be swapped between entries.
+#[derive(Clone)]
+pub struct CacheCipher {
+    cipher: ChaCha20Poly1305
+}
+impl CacheCipher {
+    /// Create a cipher with a new salt and the metadata to store with the cache.
+    pub fn create(source: &KeySource) -> Result<(Self, EncryptionMeta), CustomError> {
+        let mut salt = vec![0u8; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let cipher = Self::from_key(source.derive_key(&salt)?);
+        let meta = EncryptionMeta {
+            algorithm: "chacha20poly1305".to_string(),
+            kdf: source.kdf().to_string(),
+            key_check: cipher.encrypt(b"key_check", KEY_CHECK)?,
+            salt
+        };
+        Ok((cipher, meta))
+    }
+
+    /// Create the cipher of an encrypted cache, checking that the key is correct.
+    pub fn open(source: &KeySource, meta: &EncryptionMeta) -> Result<Self, CustomError> {
+        if meta.algorithm != "chacha20poly1305" {
+            return Err(CustomError::cache_error(format!("Unsupported cache encryption {}.", meta.algorithm)));
+        }
+        if meta.kdf != source.kdf() {
+            return Err(CustomError::cache_error(format!(
+                "The cache key was derived with {} but {} was given.", meta.kdf, source.kdf()
+            )));
+        }
+
+        let cipher = Self::from_key(source.derive_key(&meta.salt)?);
+        match cipher.decrypt(b"key_check", &meta.key_check) {
+            Ok(check) if check == KEY_CHECK => Ok(cipher),
+            _ => Err(CustomError::cache_error("The cache passphrase or keyfile is wrong."))
+        }
+    }
+
+    fn from_key(key: [u8; 32]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key))
+        }
+    }
+
+    /// Encrypt a value as the magic, a random nonce and the ciphertext.
+    pub fn encrypt(&self, aad: &[u8], value: &[u8]) -> Result<Vec<u8>, CustomError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = self.cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: value, aad })
+            .map_err(|_| CustomError::cache_error("Error encrypting cache value."))?;
+
+        let mut bytes = Vec::with_capacity(ENCRYPTED_MAGIC.len() + NONCE_LEN + ciphertext.len());
+        bytes.extend_from_slice(ENCRYPTED_MAGIC);
+        bytes.extend_from_slice(&nonce);
+        bytes.extend(ciphertext);
+        Ok(bytes)
+    }
+
+    /// Decrypt a value created by `encrypt` with the same `aad`.
+    pub fn decrypt(&self, aad: &[u8], bytes: &[u8]) -> Result<Vec<u8>, CustomError> {
+        let header_len = ENCRYPTED_MAGIC.len() + NONCE_LEN;
+        if !is_encrypted(bytes) || bytes.len() < header_len {
+            return Err(CustomError::cache_error("Value is not encrypted."));
+        }
+        let nonce = Nonce::from_slice(&bytes[ENCRYPTED_MAGIC.len()..header_len]);
+        self.cipher
+            .decrypt(nonce, Payload { msg: &bytes[header_len..], aad })
+            .map_err(|_| CustomError::cache_error("Error decrypting cache value."))
+    }
+}
+
+
+/// Check if a value was created by `CacheCipher::encrypt`.
+pub fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.starts_with(ENCRYPTED_MAGIC)
+}
@@ -2,6 +2,8 @@ use jmespath::JmespathError;
 use sled::Error as SledError;
 use polars::error::PolarsError;
 use openai_api_rs::v1::error::APIError as OpenAIApiError;
+use std::time::Duration;
+use reqwest::Error as ReqwestError;
 
 
 #[derive(Debug)]
@@ -11,6 +13,14 @@ pub enum ErrorType {
     SledError,
     CacheError,
     OpenAIApiError,
+    HttpError,
+    HttpStatusError {
+        status: u16,
+        retry_after: Option<Duration>
+    },
+    ConnectionError,
+    ModelError,
+    BudgetExceeded,
     PolarsError
 }
 
@@ -41,6 +51,61 @@ impl CustomError {
             kind: ErrorType::SledError
         }
     }
+
+    pub fn model_error<S: AsRef<str>>(message: S) -> Self {
+        Self {
+            message: message.as_ref().to_string(),
+            kind: ErrorType::ModelError
+        }
+    }
+
+    pub fn budget_error<S: AsRef<str>>(message: S) -> Self {
+        Self {
+            message: message.as_ref().to_string(),
+            kind: ErrorType::BudgetExceeded
+        }
+    }
+
+    pub fn http_error<S: AsRef<str>>(message: S) -> Self {
+        Self {
+            message: message.as_ref().to_string(),
+            kind: ErrorType::HttpError
+        }
+    }
+
+    pub fn http_status_error<S: AsRef<str>>(status: u16, retry_after: Option<Duration>, message: S) -> Self {
+        Self {
+            message: message.as_ref().to_string(),
+            kind: ErrorType::HttpStatusError { status, retry_after }
+        }
+    }
+
+    /// Is the error transient so the request can be retried.
+    pub fn is_retryable(&self) -> bool {
+        match self.kind {
+            ErrorType::ConnectionError => true,
+            ErrorType::HttpStatusError { status, .. } => status == 429 || status == 408 || status >= 500,
+            _ => false
+        }
+    }
+
+    /// Was the request not sent because the token or cost budget was used.
+    pub fn is_budget_exceeded(&self) -> bool {
+        matches!(self.kind, ErrorType::BudgetExceeded)
+    }
+
+    /// Is the error a 429 rate-limit response.
+    pub fn is_rate_limited(&self) -> bool {
+        matches!(self.kind, ErrorType::HttpStatusError { status: 429, .. })
+    }
+
+    /// The delay the server asked for before retrying.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self.kind {
+            ErrorType::HttpStatusError { retry_after, .. } => retry_after,
+            _ => None
+        }
+    }
 }
 
 
@@ -68,6 +133,28 @@ impl From<OpenAIApiError> for CustomError {
         }
     }
 }
+impl From<ReqwestError> for CustomError {
+    fn from(err: ReqwestError) -> Self {
+        let kind = if err.is_timeout() || err.is_connect() {
+            ErrorType::ConnectionError
+        } else {
+            ErrorType::HttpError
+        };
+        Self {
+            message: format!("{}", err),
+            kind,
+        }
+    }
+}
+#[cfg(feature = "local-models")]
+impl From<candle_core::Error> for CustomError {
+    fn from(err: candle_core::Error) -> Self {
+        Self {
+            message: format!("{}", err),
+            kind: ErrorType::ModelError,
+        }
+    }
+}
 impl From<PolarsError> for CustomError {
     fn from(err: PolarsError) -> Self {
         Self {
@@ -6,7 +6,7 @@ use evtx::{EvtxParser, ParserSettings};
 use polars::prelude::{DataFrame, JsonReader, SerReader};
 use serde_json::{json, Map, Value};
 use crate::filter::{Filter, Matches};
-use crate::transformer::DocumentTransformer;
+use crate::transformer::{DocumentTransformer, EventFlattener, merge_record_schemas};
 use crate::errors::CustomError;
 
 pub struct EvtxHandler<'a> {
@@ -35,6 +35,12 @@ impl <'a> EvtxHandler<'a> {
         self
     }
 
+    /// Flatten all of EventData and the given System keys into output columns.
+    pub fn with_flattened_event_data(mut self, system_keys: Vec<String>) -> Self {
+        self.transformer = self.transformer.with_flattener(EventFlattener::new(system_keys));
+        self
+    }
+
     pub fn add_output_column(self, name: impl AsRef<str>, pattern: &'a str) -> Result<Self, CustomError> {
         self.add_transformer_field_from_pattern(name, pattern)
     }
@@ -134,14 +140,18 @@ impl <'a> EvtxHandler<'a> {
 
     /// Parse data into a dataframe
     pub fn parse_into_dataframe(&self) -> Result<DataFrame, CustomError> {
-        let transformed_records = if self.source.is_dir() {
+        let mut transformed_records = if self.source.is_dir() {
             self.process()?
         } else {
             self.process()?
         };
 
+        // Records can have different keys when flattening so align them into one schema.
+        merge_record_schemas(&mut transformed_records);
+
         let json_str: String = json!(&transformed_records).to_string();
         let df = JsonReader::new(Cursor::new(json_str))
+            .infer_schema_len(None)
             .finish()?;
         Ok(df)
     }
@@ -0,0 +1,133 @@
+use ndarray::{Array1, Array2, Axis};
+use crate::distance::DistanceMetric;
+use crate::metrics::cluster_members;
+
+
+/// The number of exemplars picked for each cluster by default.
+pub const DEFAULT_EXEMPLARS: usize = 5;
+/// The members nearest the centroid that the medoid is picked from, so finding it grows with
+/// the cluster size instead of its square.
+pub const MEDOID_CANDIDATES: usize = 32;
+
+
+/// The centroid, medoid and exemplars of one cluster. Rows are indexes into the clustered dataset.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClusterSummary {
+    pub cluster: usize,
+    pub size: usize,
+    /// The mean of the member vectors, after they are prepared for the metric.
+    pub centroid: Vec<f32>,
+    /// The member with the smallest total distance to the other members, of the
+    /// `MEDOID_CANDIDATES` members nearest the centroid.
+    pub medoid: usize,
+    /// The medoid and the members nearest the centroid, the most typical members, followed by
+    /// members picked by farthest-point sampling, which are the edge cases of the cluster.
+    pub exemplars: Vec<usize>
+}
+
+
+/// Find the centroid, medoid and up to `n_exemplars` exemplars of each cluster. Half of the
+/// exemplars, rounded up, are the medoid and the members nearest the centroid. The rest are
+/// picked one at a time as the member farthest from every exemplar picked so far.
+pub fn summarize_clusters(
+    dataset: &Array2<f32>,
+    labels: &[Option<usize>],
+    metric: DistanceMetric,
+    n_exemplars: usize
+) -> Vec<ClusterSummary> {
+    let dataset = metric.prepare(dataset);
+    let distance = |i: usize, j: usize| metric.distance(dataset.row(i), dataset.row(j));
+
+    cluster_members(labels).into_iter()
+        .enumerate()
+        .filter(|(_, rows)| !rows.is_empty())
+        .map(|(cluster, rows)| {
+            let centroid = dataset.select(Axis(0), &rows)
+                .mean_axis(Axis(0))
+                .expect("A non-empty cluster.");
+
+            // Cosine distances are only meaningful to a unit length centroid
+            let mut anchor = centroid.clone();
+            if metric.is_normalized() {
+                let norm = anchor.dot(&anchor).sqrt();
+                if norm > 0.0 {
+                    anchor /= norm;
+                }
+            }
+
+            let mut candidates: Vec<(f32, usize)> = rows.iter()
+                .map(|row| (metric.distance(anchor.view(), dataset.row(*row)), *row))
+                .collect();
+            if candidates.len() > MEDOID_CANDIDATES {
+                candidates.select_nth_unstable_by(MEDOID_CANDIDATES - 1, |a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+                candidates.truncate(MEDOID_CANDIDATES);
+                candidates.sort_by_key(|(_, row)| *row);
+            }
+            let medoid = candidates.iter()
+                .map(|(_, i)| (rows.iter().map(|j| distance(*i, *j) as f64).sum::<f64>(), *i))
+                .min_by(|a, b| a.0.total_cmp(&b.0))
+                .expect("A non-empty cluster.")
+                .1;
+
+            let exemplars = pick_exemplars(&rows, medoid, &anchor, n_exemplars, &dataset, metric);
+
+            ClusterSummary {
+                cluster,
+                size: rows.len(),
+                centroid: centroid.to_vec(),
+                medoid,
+                exemplars
+            }
+        })
+        .collect()
+}
+
+
+/// The medoid, the members nearest the anchor and members from farthest-point sampling.
+fn pick_exemplars(
+    rows: &[usize],
+    medoid: usize,
+    anchor: &Array1<f32>,
+    n_exemplars: usize,
+    dataset: &Array2<f32>,
+    metric: DistanceMetric
+) -> Vec<usize> {
+    let n_exemplars = n_exemplars.min(rows.len());
+    if n_exemplars == 0 {
+        return Vec::new();
+    }
+    let typical = n_exemplars.div_ceil(2);
+
+    let mut by_anchor: Vec<(f32, usize)> = rows.iter()
+        .filter(|row| **row != medoid)
+        .map(|row| (metric.distance(anchor.view(), dataset.row(*row)), *row))
+        .collect();
+    by_anchor.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+    let mut exemplars = vec![medoid];
+    exemplars.extend(by_anchor.iter().take(typical - 1).map(|(_, row)| *row));
+
+    // The distance of each member to its nearest exemplar, picked members are never picked again
+    let mut nearest: Vec<f32> = rows.iter()
+        .map(|row| match exemplars.contains(row) {
+            true => f32::NEG_INFINITY,
+            false => exemplars.iter()
+                .map(|e| metric.distance(dataset.row(*row), dataset.row(*e)))
+                .fold(f32::INFINITY, f32::min)
+        })
+        .collect();
+    while exemplars.len() < n_exemplars {
+        let (index, _) = nearest.iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1).then(b.0.cmp(&a.0)))
+            .expect("A non-empty cluster.");
+        let picked = rows[index];
+        exemplars.push(picked);
+        nearest[index] = f32::NEG_INFINITY;
+        for (i, row) in rows.iter().enumerate() {
+            if nearest[i] > f32::NEG_INFINITY {
+                nearest[i] = nearest[i].min(metric.distance(dataset.row(*row), dataset.row(picked)));
+            }
+        }
+    }
+    exemplars
+}
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use ndarray::Array2;
+use polars::prelude::*;
+use regex::Regex;
+use crate::errors::CustomError;
+
+
+/// Executables commonly abused as living-off-the-land binaries.
+pub const LOLBINS: &[&str] = &[
+    "bitsadmin.exe", "certutil.exe", "cmstp.exe", "cscript.exe", "forfiles.exe",
+    "installutil.exe", "msbuild.exe", "mshta.exe", "msiexec.exe", "odbcconf.exe",
+    "powershell.exe", "pwsh.exe", "regasm.exe", "regsvcs.exe", "regsvr32.exe",
+    "rundll32.exe", "schtasks.exe", "wmic.exe", "wscript.exe", "esentutl.exe",
+    "expand.exe", "extrac32.exe", "findstr.exe", "hh.exe", "ieexec.exe",
+    "makecab.exe", "mavinject.exe", "msdt.exe", "pcalua.exe", "presentationhost.exe",
+    "replace.exe", "sc.exe", "syncappvpublishingserver.exe", "bash.exe", "wsl.exe",
+];
+
+/// Named patterns for flags and keywords often seen in malicious command lines.
+pub const SUSPICIOUS_PATTERNS: &[(&str, &str)] = &[
+    ("nop_hidden", r"(?i)-nop\s+-w(?:indowstyle)?\s+hidden"),
+    ("noprofile", r"(?i)\s-nop(?:rofile)?\b"),
+    ("hidden_window", r"(?i)-w(?:indowstyle)?\s+hidden"),
+    ("bypass", r"(?i)bypass"),
+    ("encoded_command", r"(?i)\s-e(?:nc|ncodedcommand)?\s+[A-Za-z0-9+/=]{16,}"),
+    ("downloadstring", r"(?i)downloadstring"),
+    ("downloadfile", r"(?i)downloadfile"),
+    ("invoke_expression", r"(?i)\biex\b|invoke-expression"),
+    ("web_request", r"(?i)invoke-webrequest|net\.webclient|\biwr\b"),
+    ("frombase64string", r"(?i)frombase64string"),
+    ("noninteractive", r"(?i)-noni(?:nteractive)?\b"),
+    ("urlcache", r"(?i)-urlcache"),
+];
+
+
+fn base64_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"[A-Za-z0-9+/]{20,}={0,2}").expect("Invalid base64 regex."))
+}
+
+fn url_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"(?i)\b(?:https?|ftp)://[^\s'\x22<>]+").expect("Invalid url regex."))
+}
+
+fn ip_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(
+        r"\b(?:(?:25[0-5]|2[0-4]\d|1?\d?\d)\.){3}(?:25[0-5]|2[0-4]\d|1?\d?\d)\b"
+    ).expect("Invalid ip regex."))
+}
+
+fn suspicious_regexes() -> &'static Vec<(&'static str, Regex)> {
+    static RE: OnceLock<Vec<(&'static str, Regex)>> = OnceLock::new();
+    RE.get_or_init(|| {
+        SUSPICIOUS_PATTERNS.iter()
+            .map(|(name, pattern)| (*name, Regex::new(pattern).expect("Invalid suspicious pattern.")))
+            .collect()
+    })
+}
+
+
+/// Shannon entropy of the characters in a string, in bits.
+pub fn shannon_entropy(value: impl AsRef<str>) -> f64 {
+    let value = value.as_ref();
+    let mut counts: HashMap<char, usize> = HashMap::new();
+    let mut total = 0usize;
+    for c in value.chars() {
+        *counts.entry(c).or_insert(0) += 1;
+        total += 1;
+    }
+    if total == 0 {
+        return 0.0;
+    }
+
+    counts.values()
+        .map(|count| {
+            let p = *count as f64 / total as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Extensions of the images that start a command.
+const EXECUTABLE_EXTENSIONS: &[&str] = &[".exe", ".com", ".bat", ".cmd", ".ps1"];
+
+/// Get the lowercase file name of the executable that starts the command.
+pub fn executable_name(command: impl AsRef<str>) -> String {
+    let command = command.as_ref().trim_start();
+    let image = if let Some(stripped) = command.strip_prefix('"') {
+        stripped.split('"').next().unwrap_or("")
+    } else {
+        unquoted_image(command)
+    };
+
+    let name = image.rsplit(['\\', '/']).next().unwrap_or(image).to_lowercase();
+    if name.is_empty() || name.contains('.') {
+        name
+    } else {
+        format!("{name}.exe")
+    }
+}
+
+
+/// The image of a command that is not quoted. A path can contain spaces, as in
+/// `C:\Program Files\...`, so it is read up to the first word ending in an executable extension,
+/// stopping at the first argument that is an option or quoted. Otherwise it is the first word.
+fn unquoted_image(command: &str) -> &str {
+    let first = command.split_whitespace().next().unwrap_or("");
+    if !first.contains(['\\', '/']) {
+        return first;
+    }
+    let mut start = 0;
+    for piece in command.split_inclusive(char::is_whitespace) {
+        let word = piece.trim_end();
+        let word_start = start;
+        start += piece.len();
+        if word.is_empty() {
+            continue;
+        }
+        if word_start > 0 && word.starts_with(['-', '/', '"']) {
+            break;
+        }
+        let image = &command[..word_start + word.len()];
+        let lower = image.to_lowercase();
+        if EXECUTABLE_EXTENSIONS.iter().any(|extension| lower.ends_with(extension)) {
+            return image;
+        }
+    }
+    first
+}
+
+
+/// Lexical indicators computed from a single command line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandFeatures {
+    pub length: u32,
+    pub entropy: f64,
+    /// The share of the non-whitespace characters that are not alphanumeric, so long argument
+    /// lists do not look obfuscated.
+    pub non_alnum_ratio: f64,
+    pub base64_substrings: u32,
+    pub url_count: u32,
+    pub ip_count: u32,
+    pub is_lolbin: bool,
+    pub suspicious_flags: Vec<String>,
+}
+impl CommandFeatures {
+    /// The number of numeric values returned by `as_vector`.
+    pub const VECTOR_LEN: usize = 8;
+
+    /// Compute the features for a command line.
+    pub fn from_command(command: impl AsRef<str>) -> Self {
+        let command = command.as_ref();
+        let length = command.chars().count();
+        let visible = command.chars()
+            .filter(|c| !c.is_whitespace())
+            .count();
+        let non_alnum = command.chars()
+            .filter(|c| !c.is_alphanumeric() && !c.is_whitespace())
+            .count();
+        let non_alnum_ratio = if visible == 0 {
+            0.0
+        } else {
+            non_alnum as f64 / visible as f64
+        };
+
+        let suspicious_flags = suspicious_regexes().iter()
+            .filter(|(_, re)| re.is_match(command))
+            .map(|(name, _)| name.to_string())
+            .collect();
+
+        Self {
+            length: length as u32,
+            entropy: shannon_entropy(command),
+            non_alnum_ratio,
+            base64_substrings: base64_regex().find_iter(command).count() as u32,
+            url_count: url_regex().find_iter(command).count() as u32,
+            ip_count: ip_regex().find_iter(command).count() as u32,
+            is_lolbin: LOLBINS.contains(&executable_name(command).as_str()),
+            suspicious_flags
+        }
+    }
+
+    /// Numeric representation of the features. Counts are log scaled.
+    pub fn as_vector(&self) -> [f32; Self::VECTOR_LEN] {
+        [
+            (self.length as f32).ln_1p(),
+            self.entropy as f32,
+            self.non_alnum_ratio as f32,
+            (self.base64_substrings as f32).ln_1p(),
+            (self.url_count as f32).ln_1p(),
+            (self.ip_count as f32).ln_1p(),
+            if self.is_lolbin { 1.0 } else { 0.0 },
+            (self.suspicious_flags.len() as f32).ln_1p(),
+        ]
+    }
+}
+
+
+/// Create a DataFrame of lexical features where `value` is the command the features were
+/// computed from.
+pub fn get_feature_dataframe(values: &[String]) -> Result<DataFrame, CustomError> {
+    let features: Vec<CommandFeatures> = values.iter()
+        .map(CommandFeatures::from_command)
+        .collect();
+
+    let df = DataFrame::new(vec![
+        Series::new("value", values),
+        Series::new("length", features.iter().map(|f| f.length).collect::<Vec<u32>>()),
+        Series::new("entropy", features.iter().map(|f| f.entropy).collect::<Vec<f64>>()),
+        Series::new("non_alnum_ratio", features.iter().map(|f| f.non_alnum_ratio).collect::<Vec<f64>>()),
+        Series::new("base64_substrings", features.iter().map(|f| f.base64_substrings).collect::<Vec<u32>>()),
+        Series::new("url_count", features.iter().map(|f| f.url_count).collect::<Vec<u32>>()),
+        Series::new("ip_count", features.iter().map(|f| f.ip_count).collect::<Vec<u32>>()),
+        Series::new("is_lolbin", features.iter().map(|f| f.is_lolbin).collect::<Vec<bool>>()),
+        Series::new("suspicious_hits", features.iter().map(|f| f.suspicious_flags.len() as u32).collect::<Vec<u32>>()),
+        Series::new("suspicious_flags", features.iter().map(|f| f.suspicious_flags.join(";")).collect::<Vec<String>>()),
+    ])?;
+
+    Ok(df)
+}
+
+/// Create a matrix of standardized (z-score) feature vectors, one row per value.
+/// Columns that do not vary are set to zero.
+pub fn get_feature_matrix(values: &[String]) -> Array2<f32> {
+    let mut matrix = Array2::<f32>::zeros((values.len(), CommandFeatures::VECTOR_LEN));
+    for (i, value) in values.iter().enumerate() {
+        let vector = CommandFeatures::from_command(value).as_vector();
+        for (j, v) in vector.iter().enumerate() {
+            matrix[[i, j]] = *v;
+        }
+    }
+
+    if values.is_empty() {
+        return matrix;
+    }
+
+    for mut column in matrix.columns_mut() {
+        let n = column.len() as f32;
+        let mean = column.sum() / n;
+        let std = (column.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / n).sqrt();
+        column.mapv_inplace(|v| if std > f32::EPSILON { (v - mean) / std } else { 0.0 });
+    }
+
+    matrix
+}
@@ -0,0 +1,383 @@
+use std::fmt;
+use ndarray::Array2;
+use crate::distance::DistanceMetric;
+use crate::errors::CustomError;
+
+
+/// Merge two clusters in a union-find forest.
+struct UnionFind {
+    parent: Vec<usize>
+}
+impl UnionFind {
+    fn new(size: usize) -> Self {
+        Self {
+            parent: (0..size).collect()
+        }
+    }
+
+    fn find(&mut self, mut node: usize) -> usize {
+        while self.parent[node] != node {
+            self.parent[node] = self.parent[self.parent[node]];
+            node = self.parent[node];
+        }
+        node
+    }
+
+    /// Make `root` the root of the union of both sets.
+    fn union_into(&mut self, root: usize, other: usize) {
+        let (root, other) = (self.find(root), self.find(other));
+        self.parent[other] = root;
+    }
+}
+
+
+/// Number the root of each row's set in order of first appearance.
+fn labels_from_roots(union_find: &mut UnionFind, rows: usize) -> Vec<Option<usize>> {
+    let mut numbers = std::collections::HashMap::new();
+    (0..rows)
+        .map(|row| {
+            let root = union_find.find(row);
+            let next = numbers.len();
+            Some(*numbers.entry(root).or_insert(next))
+        })
+        .collect()
+}
+
+
+/// How the distance between two clusters is computed when merging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Linkage {
+    /// The closest pair of rows.
+    Single,
+    /// The farthest pair of rows.
+    Complete,
+    /// The average distance of all pairs of rows.
+    Average,
+    /// The increase in within-cluster variance.
+    Ward
+}
+impl std::str::FromStr for Linkage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "single" => Ok(Self::Single),
+            "complete" => Ok(Self::Complete),
+            "average" => Ok(Self::Average),
+            "ward" => Ok(Self::Ward),
+            _ => Err(format!("invalid linkage: {s}"))
+        }
+    }
+}
+impl fmt::Display for Linkage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Single => write!(f, "single"),
+            Self::Complete => write!(f, "complete"),
+            Self::Average => write!(f, "average"),
+            Self::Ward => write!(f, "ward")
+        }
+    }
+}
+impl Linkage {
+    /// The Lance-Williams distance from the merge of `i` and `j` to `k`.
+    fn update(&self, d_ik: f64, d_jk: f64, d_ij: f64, n_i: usize, n_j: usize, n_k: usize) -> f64 {
+        let (n_i, n_j, n_k) = (n_i as f64, n_j as f64, n_k as f64);
+        match self {
+            Self::Single => d_ik.min(d_jk),
+            Self::Complete => d_ik.max(d_jk),
+            Self::Average => (n_i * d_ik + n_j * d_jk) / (n_i + n_j),
+            Self::Ward => (
+                ((n_i + n_k) * d_ik.powi(2) + (n_j + n_k) * d_jk.powi(2) - n_k * d_ij.powi(2))
+                    / (n_i + n_j + n_k)
+            ).max(0.0).sqrt()
+        }
+    }
+}
+
+
+/// The pairwise distances of the rows stored as the upper triangle.
+struct CondensedMatrix {
+    rows: usize,
+    distances: Vec<f64>
+}
+impl CondensedMatrix {
+    fn new(dataset: &Array2<f32>, metric: DistanceMetric) -> Self {
+        let rows = dataset.nrows();
+        let mut distances = Vec::with_capacity(rows * rows.saturating_sub(1) / 2);
+        for i in 0..rows {
+            for j in (i + 1)..rows {
+                distances.push(metric.distance(dataset.row(i), dataset.row(j)) as f64);
+            }
+        }
+        Self { rows, distances }
+    }
+
+    fn index(&self, i: usize, j: usize) -> usize {
+        let (i, j) = if i < j { (i, j) } else { (j, i) };
+        self.rows * i - i * (i + 1) / 2 + j - i - 1
+    }
+
+    fn get(&self, i: usize, j: usize) -> f64 {
+        self.distances[self.index(i, j)]
+    }
+
+    fn set(&mut self, i: usize, j: usize, distance: f64) {
+        let index = self.index(i, j);
+        self.distances[index] = distance;
+    }
+}
+
+
+/// The merges of agglomerative clustering as the rows merged and their distance, sorted by
+/// distance. Uses the nearest-neighbor chain algorithm, which needs the pairwise distances
+/// of all rows in memory. The rows must be prepared for the metric.
+pub fn agglomerative_merges(dataset: &Array2<f32>, linkage: Linkage, metric: DistanceMetric) -> Vec<(usize, usize, f64)> {
+    let rows = dataset.nrows();
+    let mut matrix = CondensedMatrix::new(dataset, metric);
+    let mut active = vec![true; rows];
+    let mut sizes = vec![1usize; rows];
+    let mut chain: Vec<usize> = Vec::new();
+    let mut merges = Vec::with_capacity(rows.saturating_sub(1));
+
+    while merges.len() + 1 < rows {
+        if chain.is_empty() {
+            chain.push(active.iter().position(|a| *a).expect("An active cluster."));
+        }
+
+        // Follow nearest neighbors until two clusters are each other's nearest neighbor.
+        let (a, b) = loop {
+            let a = *chain.last().expect("A chain.");
+            let previous = chain.len().checked_sub(2).map(|i| chain[i]);
+            let mut nearest = previous;
+            let mut nearest_distance = previous.map_or(f64::INFINITY, |p| matrix.get(a, p));
+            for k in (0..rows).filter(|k| active[*k] && *k != a) {
+                let distance = matrix.get(a, k);
+                if distance < nearest_distance {
+                    nearest = Some(k);
+                    nearest_distance = distance;
+                }
+            }
+            let b = nearest.expect("A neighbor.");
+            if Some(b) == previous {
+                chain.truncate(chain.len() - 2);
+                break (a, b);
+            }
+            chain.push(b);
+        };
+
+        // Keep the merged cluster in the slot of `a`.
+        let d_ab = matrix.get(a, b);
+        for k in (0..rows).filter(|k| active[*k] && *k != a && *k != b) {
+            let distance = linkage.update(matrix.get(a, k), matrix.get(b, k), d_ab, sizes[a], sizes[b], sizes[k]);
+            matrix.set(a, k, distance);
+        }
+        sizes[a] += sizes[b];
+        active[b] = false;
+        merges.push((a, b, d_ab));
+    }
+
+    merges.sort_by(|x, y| x.2.total_cmp(&y.2));
+    merges
+}
+
+
+/// Cut agglomerative merges into `n_clusters` clusters, or at a distance threshold.
+pub fn cut_merges(
+    merges: &[(usize, usize, f64)],
+    rows: usize,
+    n_clusters: Option<usize>,
+    distance_threshold: Option<f64>
+) -> Vec<Option<usize>> {
+    let mut union_find = UnionFind::new(rows);
+    let max_merges = n_clusters.map_or(merges.len(), |n| rows.saturating_sub(n.max(1)));
+    for (a, b, distance) in merges.iter().take(max_merges) {
+        if distance_threshold.is_some_and(|t| *distance > t) {
+            break;
+        }
+        union_find.union_into(*a, *b);
+    }
+    labels_from_roots(&mut union_find, rows)
+}
+
+
+/// The distance of each row to its `k`th nearest row, counting the row itself.
+pub fn core_distances(dataset: &Array2<f32>, k: usize, metric: DistanceMetric) -> Vec<f64> {
+    let rows = dataset.nrows();
+    (0..rows)
+        .map(|i| {
+            if k <= 1 {
+                return 0.0;
+            }
+            let mut distances: Vec<f64> = (0..rows)
+                .map(|j| metric.distance(dataset.row(i), dataset.row(j)) as f64)
+                .collect();
+            let k = (k - 1).min(rows - 1);
+            distances.select_nth_unstable_by(k, |a, b| a.total_cmp(b));
+            distances[k]
+        })
+        .collect()
+}
+
+
+/// The minimum spanning tree of the mutual reachability distances, with Prim's algorithm.
+fn mutual_reachability_mst(dataset: &Array2<f32>, core: &[f64], metric: DistanceMetric) -> Vec<(usize, usize, f64)> {
+    let rows = dataset.nrows();
+    let mut in_tree = vec![false; rows];
+    let mut best = vec![(f64::INFINITY, 0usize); rows];
+    let mut edges = Vec::with_capacity(rows.saturating_sub(1));
+    let mut current = 0;
+    in_tree[0] = true;
+
+    for _ in 1..rows {
+        for k in 0..rows {
+            if !in_tree[k] {
+                let distance = (metric.distance(dataset.row(current), dataset.row(k)) as f64)
+                    .max(core[current])
+                    .max(core[k]);
+                if distance < best[k].0 {
+                    best[k] = (distance, current);
+                }
+            }
+        }
+        let next = (0..rows)
+            .filter(|k| !in_tree[*k])
+            .min_by(|a, b| best[*a].0.total_cmp(&best[*b].0))
+            .expect("A row outside the tree.");
+        edges.push((best[next].1, next, best[next].0));
+        in_tree[next] = true;
+        current = next;
+    }
+
+    edges.sort_by(|x, y| x.2.total_cmp(&y.2));
+    edges
+}
+
+
+/// A cluster of the condensed tree.
+struct CondensedCluster {
+    parent: Option<usize>,
+    /// The lambda (1 / distance) the cluster split from its parent.
+    birth: f64,
+    stability: f64,
+    children: Vec<usize>
+}
+
+
+/// Cluster with HDBSCAN. Clusters of the mutual reachability hierarchy with fewer than
+/// `min_cluster_size` rows are condensed into their parent and the most stable clusters are
+/// selected. Rows outside every selected cluster are noise. The rows must be prepared for the
+/// metric.
+pub fn hdbscan(
+    dataset: &Array2<f32>,
+    min_cluster_size: usize,
+    min_samples: usize,
+    metric: DistanceMetric
+) -> Result<Vec<Option<usize>>, CustomError> {
+    let rows = dataset.nrows();
+    let min_cluster_size = min_cluster_size.max(2);
+    if rows < min_cluster_size {
+        return Ok(vec![None; rows]);
+    }
+
+    // Build the single linkage tree of the mutual reachability distances. Node `rows + i`
+    // is the i'th merge.
+    let core = core_distances(dataset, min_samples, metric);
+    let mut union_find = UnionFind::new(2 * rows - 1);
+    let mut nodes: Vec<(usize, usize, f64)> = Vec::with_capacity(rows - 1);
+    let mut node_sizes = vec![1usize; 2 * rows - 1];
+    for (a, b, distance) in mutual_reachability_mst(dataset, &core, metric) {
+        let node = rows + nodes.len();
+        let (left, right) = (union_find.find(a), union_find.find(b));
+        node_sizes[node] = node_sizes[left] + node_sizes[right];
+        nodes.push((left, right, distance));
+        union_find.union_into(node, left);
+        union_find.union_into(node, right);
+    }
+
+    let lambda = |distance: f64| 1.0 / distance.max(1e-12);
+    let leaves = |node: usize| -> Vec<usize> {
+        let mut stack = vec![node];
+        let mut leaves = Vec::new();
+        while let Some(node) = stack.pop() {
+            if node < rows {
+                leaves.push(node);
+            } else {
+                let (left, right, _) = nodes[node - rows];
+                stack.push(left);
+                stack.push(right);
+            }
+        }
+        leaves
+    };
+
+    // Condense the tree from the root. Each row records the cluster it falls out of. Single
+    // rows are always smaller than min_cluster_size so only merges are pushed.
+    let mut clusters = vec![CondensedCluster { parent: None, birth: 0.0, stability: 0.0, children: Vec::new() }];
+    let mut row_clusters = vec![0usize; rows];
+    let mut stack = vec![(2 * rows - 2, 0usize)];
+    while let Some((node, cluster)) = stack.pop() {
+        let (left, right, distance) = nodes[node - rows];
+        let split = lambda(distance);
+        let birth = clusters[cluster].birth;
+        let big_left = node_sizes[left] >= min_cluster_size;
+        let big_right = node_sizes[right] >= min_cluster_size;
+
+        if big_left && big_right {
+            for child in [left, right] {
+                let id = clusters.len();
+                clusters.push(CondensedCluster { parent: Some(cluster), birth: split, stability: 0.0, children: Vec::new() });
+                clusters[cluster].children.push(id);
+                clusters[cluster].stability += (split - birth) * node_sizes[child] as f64;
+                stack.push((child, id));
+            }
+        } else {
+            for (child, big) in [(left, big_left), (right, big_right)] {
+                if big {
+                    stack.push((child, cluster));
+                } else {
+                    for row in leaves(child) {
+                        row_clusters[row] = cluster;
+                        clusters[cluster].stability += split - birth;
+                    }
+                }
+            }
+        }
+    }
+
+    // Select the most stable clusters, children are created after their parents. The root is
+    // never selected.
+    let mut selected = vec![false; clusters.len()];
+    let mut subtree_stability = vec![0f64; clusters.len()];
+    for id in (1..clusters.len()).rev() {
+        let children: f64 = clusters[id].children.iter().map(|c| subtree_stability[*c]).sum();
+        if clusters[id].children.is_empty() || clusters[id].stability >= children {
+            selected[id] = true;
+            subtree_stability[id] = clusters[id].stability;
+            let mut descendants = clusters[id].children.clone();
+            while let Some(descendant) = descendants.pop() {
+                selected[descendant] = false;
+                descendants.extend(clusters[descendant].children.iter().copied());
+            }
+        } else {
+            subtree_stability[id] = children;
+        }
+    }
+
+    // Label each row with its selected ancestor.
+    let mut numbers = std::collections::HashMap::new();
+    let labels = row_clusters.iter()
+        .map(|cluster| {
+            let mut ancestor = Some(*cluster);
+            while let Some(id) = ancestor {
+                if selected[id] {
+                    let next = numbers.len();
+                    return Some(*numbers.entry(id).or_insert(next));
+                }
+                ancestor = clusters[id].parent;
+            }
+            None
+        })
+        .collect();
+    Ok(labels)
+}
@@ -0,0 +1,140 @@
+use std::collections::{HashMap, HashSet};
+use async_trait::async_trait;
+use openai_api_rs::v1::embedding::EmbeddingResponse;
+use crate::errors::CustomError;
+use crate::provider::{EmbeddingProvider, response_from_vectors};
+
+
+/// Split a command into tokens on whitespace and punctuation that separates arguments.
+fn tokenize(value: &str) -> impl Iterator<Item = &str> {
+    value.split(|c: char| c.is_whitespace() || "\"'`,;|&()[]{}<>=\\/:".contains(c))
+        .filter(|t| !t.is_empty())
+}
+
+
+/// A pure-Rust embedder using hashed character n-grams and tokens with TF-IDF weighting. It
+/// needs no network access or model files.
+pub struct LexicalEmbedder {
+    model: String,
+    dimensions: usize,
+    min_ngram: usize,
+    max_ngram: usize,
+    idf: Option<Vec<f32>>
+}
+impl LexicalEmbedder {
+    /// Create an embedder that produces vectors of `dimensions` length. Without `fit` every
+    /// feature has an inverse document frequency of 1.
+    pub fn new(dimensions: usize) -> Self {
+        let dimensions = dimensions.max(1);
+        Self {
+            model: format!("lexical-v1-{dimensions}"),
+            dimensions,
+            min_ngram: 3,
+            max_ngram: 5,
+            idf: None
+        }
+    }
+
+    /// Set the range of character n-gram sizes. This must be called before `fit`.
+    pub fn with_ngram_range(mut self, min_ngram: usize, max_ngram: usize) -> Self {
+        self.min_ngram = min_ngram.max(1);
+        self.max_ngram = max_ngram.max(self.min_ngram);
+        self.model = format!("lexical-v1-{}-{}-{}", self.dimensions, self.min_ngram, self.max_ngram);
+        self
+    }
+
+    /// Compute the inverse document frequency of each feature bucket from a corpus. The vectors
+    /// of a fitted embedder depend on the corpus, so they are not cached.
+    pub fn fit(mut self, corpus: &[impl AsRef<str>]) -> Self {
+        let mut document_frequency = vec![0usize; self.dimensions];
+        for document in corpus {
+            let buckets: HashSet<usize> = self.features(document.as_ref())
+                .into_keys()
+                .map(|(bucket, _)| bucket)
+                .collect();
+            for bucket in buckets {
+                document_frequency[bucket] += 1;
+            }
+        }
+
+        let n = corpus.len() as f32;
+        let idf: Vec<f32> = document_frequency.iter()
+            .map(|df| ((1.0 + n) / (1.0 + *df as f32)).ln() + 1.0)
+            .collect();
+        self.idf = Some(idf);
+        self
+    }
+
+    /// Hash a feature into a bucket and a sign.
+    fn bucket(&self, feature: &str) -> (usize, bool) {
+        let hash = blake3::hash(feature.as_bytes());
+        let bytes = hash.as_bytes();
+        let value = u64::from_le_bytes(bytes[..8].try_into().expect("Hash is 32 bytes."));
+        ((value % self.dimensions as u64) as usize, bytes[8] & 1 == 1)
+    }
+
+    /// Count the hashed features of a value keyed by bucket and sign.
+    fn features(&self, value: &str) -> HashMap<(usize, bool), f32> {
+        let mut counts = HashMap::new();
+        let lowercase = value.to_lowercase();
+
+        // Character n-grams padded so the start and end of the command are distinct
+        let chars: Vec<char> = format!(" {lowercase} ").chars().collect();
+        for n in self.min_ngram..=self.max_ngram {
+            for window in chars.windows(n) {
+                let gram: String = window.iter().collect();
+                *counts.entry(self.bucket(&format!("c:{gram}"))).or_insert(0.0) += 1.0;
+            }
+        }
+
+        // Whole tokens
+        for token in tokenize(&lowercase) {
+            *counts.entry(self.bucket(&format!("t:{token}"))).or_insert(0.0) += 1.0;
+        }
+
+        counts
+    }
+
+    /// Create the L2 normalized vector for a value.
+    pub fn vectorize(&self, value: impl AsRef<str>) -> Vec<f32> {
+        let mut vector = vec![0f32; self.dimensions];
+        for ((bucket, negative), count) in self.features(value.as_ref()) {
+            let tf = 1.0 + count.ln();
+            let idf = self.idf.as_ref().map_or(1.0, |idf| idf[bucket]);
+            let weight = tf * idf;
+            vector[bucket] += if negative { -weight } else { weight };
+        }
+
+        let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            vector.iter_mut().for_each(|v| *v /= norm);
+        }
+        vector
+    }
+}
+#[async_trait]
+impl EmbeddingProvider for LexicalEmbedder {
+    fn name(&self) -> &str {
+        "lexical"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn dimensions(&self) -> Option<i32> {
+        Some(self.dimensions as i32)
+    }
+
+    async fn embed(&self, input: String) -> Result<EmbeddingResponse, CustomError> {
+        response_from_vectors(&self.model, vec![self.vectorize(input)], 0)
+    }
+
+    fn is_cacheable(&self) -> bool {
+        self.idf.is_none()
+    }
+
+    fn has_token_limit(&self) -> bool {
+        false
+    }
+}
@@ -1,8 +1,28 @@
-// #[macro_use] extern crate log;
+#[macro_use] extern crate log;
 
 pub mod errors;
 pub mod transformer;
 pub mod filter;
+pub mod encryption;
+pub mod cache;
 pub mod embedding;
+pub mod provider;
+pub mod retry;
+pub mod tokens;
+pub mod lexical;
+pub mod usage;
+pub mod template;
+#[cfg(feature = "local-models")]
+pub mod local;
+pub mod distance;
+pub mod hierarchy;
+pub mod clusterer;
+pub mod tolerance;
+pub mod metrics;
+pub mod sweep;
+pub mod exemplars;
+pub mod anomaly;
+pub mod ann;
 pub mod cluster;
+pub mod features;
 pub mod evtx;
\ No newline at end of file
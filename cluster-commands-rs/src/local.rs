@@ -0,0 +1,173 @@
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use async_trait::async_trait;
+use candle_core::{Device, DType, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert::{BertModel, Config, DTYPE};
+use openai_api_rs::v1::embedding::EmbeddingResponse;
+use tokenizers::{Tokenizer, TruncationParams};
+use crate::errors::CustomError;
+use crate::provider::{EmbeddingProvider, response_from_vectors};
+
+
+/// The files that make up a sentence-transformer model directory.
+struct ModelFiles {
+    config: PathBuf,
+    tokenizer: PathBuf,
+    weights: PathBuf
+}
+impl ModelFiles {
+    fn from_dir(model_dir: &Path) -> Result<Self, CustomError> {
+        let weights = ["model.safetensors", "pytorch_model.bin"].iter()
+            .map(|name| model_dir.join(name))
+            .find(|path| path.is_file())
+            .ok_or_else(|| CustomError::model_error(format!(
+                "{:?} has no model.safetensors or pytorch_model.bin.", model_dir
+            )))?;
+
+        let files = Self {
+            config: model_dir.join("config.json"),
+            tokenizer: model_dir.join("tokenizer.json"),
+            weights
+        };
+        for path in [&files.config, &files.tokenizer] {
+            if !path.is_file() {
+                return Err(CustomError::model_error(format!("{:?} does not exist.", path)));
+            }
+        }
+        Ok(files)
+    }
+
+    /// Hash the contents of the model files.
+    fn hash(&self) -> Result<String, CustomError> {
+        let mut hasher = blake3::Hasher::new();
+        for path in [&self.config, &self.tokenizer, &self.weights] {
+            let mut file = File::open(path)
+                .map_err(|e| CustomError::model_error(format!("Error opening {:?}: {e:?}", path)))?;
+            io::copy(&mut file, &mut hasher)
+                .map_err(|e| CustomError::model_error(format!("Error hashing {:?}: {e:?}", path)))?;
+        }
+        Ok(hasher.finalize().to_hex().to_string())
+    }
+}
+
+
+/// A BERT based sentence-transformer loaded into memory.
+struct LocalModel {
+    model: BertModel,
+    tokenizer: Tokenizer,
+    device: Device
+}
+impl LocalModel {
+    /// Embed a text using mean pooling over the last hidden state followed by L2 normalization.
+    fn embed(&self, text: &str) -> Result<(Vec<f32>, usize), CustomError> {
+        let encoding = self.tokenizer.encode(text, true)
+            .map_err(|e| CustomError::model_error(format!("Error tokenizing input: {e:?}")))?;
+        let token_count = encoding.get_ids().len();
+
+        let input_ids = Tensor::new(encoding.get_ids(), &self.device)?.unsqueeze(0)?;
+        let token_type_ids = Tensor::new(encoding.get_type_ids(), &self.device)?.unsqueeze(0)?;
+        let attention_mask = Tensor::new(encoding.get_attention_mask(), &self.device)?.unsqueeze(0)?;
+
+        let hidden_state = self.model.forward(&input_ids, &token_type_ids, Some(&attention_mask))?;
+
+        // Mean pooling of the token embeddings that are not padding
+        let mask = attention_mask.to_dtype(DType::F32)?.unsqueeze(2)?;
+        let summed = hidden_state.broadcast_mul(&mask)?.sum(1)?;
+        let pooled = summed.broadcast_div(&mask.sum(1)?)?;
+
+        let norm = pooled.sqr()?.sum_keepdim(1)?.sqrt()?;
+        let normalized = pooled.broadcast_div(&norm)?;
+        Ok((normalized.squeeze(0)?.to_vec1::<f32>()?, token_count))
+    }
+}
+
+
+/// Embeddings from a local sentence-transformer model (e.g. all-MiniLM or bge-small) run on
+/// the CPU. The directory must contain `config.json`, `tokenizer.json` and `model.safetensors`
+/// or `pytorch_model.bin`.
+pub struct LocalModelEmbedder {
+    model: String,
+    dimensions: usize,
+    inner: Arc<LocalModel>
+}
+impl LocalModelEmbedder {
+    /// Load the model from a directory. The model name is derived from the hash of the model
+    /// files so a cache is only reused for identical weights.
+    pub fn from_dir(model_dir: impl AsRef<Path>) -> Result<Self, CustomError> {
+        let model_dir = model_dir.as_ref();
+        let files = ModelFiles::from_dir(model_dir)?;
+
+        let config_str = std::fs::read_to_string(&files.config)
+            .map_err(|e| CustomError::model_error(format!("Error reading {:?}: {e:?}", files.config)))?;
+        let config: Config = serde_json::from_str(&config_str)
+            .map_err(|e| CustomError::model_error(format!("Error parsing {:?}: {e:?}", files.config)))?;
+
+        let mut tokenizer = Tokenizer::from_file(&files.tokenizer)
+            .map_err(|e| CustomError::model_error(format!("Error loading {:?}: {e:?}", files.tokenizer)))?;
+        // Inputs longer than the model supports are truncated
+        tokenizer.with_truncation(Some(TruncationParams {
+                max_length: config.max_position_embeddings,
+                ..Default::default()
+            }))
+            .map_err(|e| CustomError::model_error(format!("Error setting truncation: {e:?}")))?;
+        tokenizer.with_padding(None);
+
+        let device = Device::Cpu;
+        let vb = if files.weights.extension().is_some_and(|e| e == "safetensors") {
+            unsafe { VarBuilder::from_mmaped_safetensors(&[&files.weights], DTYPE, &device)? }
+        } else {
+            VarBuilder::from_pth(&files.weights, DTYPE, &device)?
+        };
+        let model = BertModel::load(vb, &config)?;
+
+        let name = model_dir.file_name()
+            .map_or(String::from("model"), |n| n.to_string_lossy().to_string());
+        let hash = files.hash()?;
+
+        Ok(Self {
+            model: format!("local-{}-{}", name, &hash[..16]),
+            dimensions: config.hidden_size,
+            inner: Arc::new(LocalModel {
+                model,
+                tokenizer,
+                device
+            })
+        })
+    }
+
+    /// Create the embedding vector for a value.
+    pub fn vectorize(&self, value: impl AsRef<str>) -> Result<Vec<f32>, CustomError> {
+        self.inner.embed(value.as_ref()).map(|(vector, _)| vector)
+    }
+}
+#[async_trait]
+impl EmbeddingProvider for LocalModelEmbedder {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn dimensions(&self) -> Option<i32> {
+        Some(self.dimensions as i32)
+    }
+
+    async fn embed(&self, input: String) -> Result<EmbeddingResponse, CustomError> {
+        // Inference is CPU bound so keep it off of the async workers
+        let inner = self.inner.clone();
+        let (vector, token_count) = tokio::task::spawn_blocking(move || inner.embed(&input))
+            .await
+            .map_err(|e| CustomError::model_error(format!("Join error: {e:?}")))??;
+        response_from_vectors(&self.model, vec![vector], token_count as i32)
+    }
+
+    fn has_token_limit(&self) -> bool {
+        // The model tokenizer truncates at the model's own limit
+        false
+    }
+}
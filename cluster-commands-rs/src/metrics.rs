@@ -0,0 +1,293 @@
+use std::fmt;
+use ndarray::{Array1, Array2, Axis};
+use polars::prelude::*;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use rand::seq::index::sample;
+use serde::Serialize;
+use crate::distance::DistanceMetric;
+use crate::errors::CustomError;
+
+
+/// Datasets with more rows than this have their silhouettes and cluster spreads measured on a
+/// random sample of this many rows, as comparing every pair of rows grows with the square of
+/// the rows.
+pub const METRICS_SAMPLE_ROWS: usize = 5000;
+/// The seed the metrics sample is drawn with.
+const SAMPLE_SEED: u64 = 42;
+
+
+/// The size and spread of one cluster. Distances are in the clustering metric.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClusterStats {
+    pub cluster: usize,
+    pub size: usize,
+    /// The mean distance between two members, 0 for a single member.
+    pub mean_distance: f32,
+    /// The largest distance between two members. Only the sampled members are compared when
+    /// the metrics are sampled.
+    pub diameter: f32
+}
+
+
+/// How well separated the clusters of a run are. Noise is left out of every score and the
+/// global scores are None with fewer than two clusters.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClusterMetrics {
+    pub metric: String,
+    pub rows: usize,
+    pub noise_fraction: f32,
+    /// The mean silhouette of the clustered rows, from -1 to 1, higher is better.
+    pub silhouette: Option<f32>,
+    /// The mean ratio of the spread of a cluster to its separation from the most similar
+    /// cluster, lower is better.
+    pub davies_bouldin: Option<f32>,
+    /// The ratio of the variance between clusters to the variance within them, higher is better.
+    pub calinski_harabasz: Option<f32>,
+    pub clusters: Vec<ClusterStats>,
+    /// The rows the silhouettes and cluster spreads were measured on, when there were too many
+    /// rows to compare every pair.
+    pub sampled_rows: Option<usize>,
+    /// The silhouette of each row, None for noise and rows outside of the sample.
+    #[serde(skip)]
+    pub silhouettes: Vec<Option<f32>>
+}
+impl ClusterMetrics {
+    /// Score the cluster labels of a dataset. Silhouettes and cluster spreads use distances
+    /// in `metric`, Davies-Bouldin and Calinski-Harabasz use the Euclidean distances of the
+    /// vectors prepared for the metric to the cluster centroids. Datasets with more than
+    /// `METRICS_SAMPLE_ROWS` rows are sampled.
+    pub fn new(dataset: &Array2<f32>, labels: &[Option<usize>], metric: DistanceMetric) -> Self {
+        Self::new_w_sample(dataset, labels, metric, METRICS_SAMPLE_ROWS)
+    }
+
+    /// Score the cluster labels of a dataset, measuring silhouettes and cluster spreads on a
+    /// random sample of `sample_rows` rows when there are more. The silhouettes of the sampled
+    /// rows compare them to the other sampled rows and each cluster spread is measured between
+    /// a share of its members in proportion to its size, at least two.
+    pub fn new_w_sample(dataset: &Array2<f32>, labels: &[Option<usize>], metric: DistanceMetric, sample_rows: usize) -> Self {
+        let dataset = metric.prepare(dataset);
+        let members = cluster_members(labels);
+        let distance = |i: usize, j: usize| metric.distance(dataset.row(i), dataset.row(j));
+        let sampled = labels.len() > sample_rows;
+        let mut rng = StdRng::seed_from_u64(SAMPLE_SEED);
+
+        let clusters = members.iter()
+            .enumerate()
+            .filter(|(_, rows)| !rows.is_empty())
+            .map(|(cluster, rows)| {
+                let compared: Vec<usize> = match sampled {
+                    true => {
+                        let take = (rows.len() * sample_rows).div_ceil(labels.len()).max(2).min(rows.len());
+                        sample(&mut rng, rows.len(), take).into_iter().map(|i| rows[i]).collect()
+                    },
+                    false => rows.clone()
+                };
+                let mut total = 0f64;
+                let mut diameter = 0f32;
+                for (n, i) in compared.iter().enumerate() {
+                    for j in &compared[n + 1..] {
+                        let d = distance(*i, *j);
+                        total += d as f64;
+                        diameter = diameter.max(d);
+                    }
+                }
+                let pairs = compared.len() * (compared.len() - 1) / 2;
+                ClusterStats {
+                    cluster,
+                    size: rows.len(),
+                    mean_distance: if pairs > 0 { (total / pairs as f64) as f32 } else { 0.0 },
+                    diameter
+                }
+            })
+            .collect();
+
+        let silhouettes = match sampled {
+            true => {
+                let mut rows = sample(&mut rng, labels.len(), sample_rows).into_vec();
+                rows.sort_unstable();
+                let sample_labels: Vec<Option<usize>> = rows.iter().map(|row| labels[*row]).collect();
+                let scores = silhouette_samples(&sample_labels, |i| {
+                    let (rows, distance) = (&rows, &distance);
+                    rows.iter().enumerate().map(move |(j, row)| (j, distance(rows[i], *row)))
+                });
+                let mut silhouettes = vec![None; labels.len()];
+                for (row, score) in rows.into_iter().zip(scores) {
+                    silhouettes[row] = score;
+                }
+                silhouettes
+            },
+            false => silhouette_samples(labels, |row| {
+                let distance = &distance;
+                (0..labels.len()).map(move |other| (other, distance(row, other)))
+            })
+        };
+        let (davies_bouldin, calinski_harabasz) = centroid_scores(&dataset, &members);
+        let noise = labels.iter().filter(|l| l.is_none()).count();
+
+        Self {
+            metric: metric.to_string(),
+            rows: labels.len(),
+            noise_fraction: noise as f32 / labels.len().max(1) as f32,
+            silhouette: mean_silhouette(&silhouettes),
+            davies_bouldin,
+            calinski_harabasz,
+            clusters,
+            sampled_rows: sampled.then_some(sample_rows),
+            silhouettes
+        }
+    }
+
+    /// The cluster, size, mean_distance and diameter of each cluster.
+    pub fn cluster_dataframe(&self) -> Result<DataFrame, CustomError> {
+        let df = DataFrame::new(vec![
+            Series::new("cluster", self.clusters.iter().map(|c| c.cluster as i64).collect::<Vec<i64>>()),
+            Series::new("size", self.clusters.iter().map(|c| c.size as u32).collect::<Vec<u32>>()),
+            Series::new("mean_distance", self.clusters.iter().map(|c| c.mean_distance).collect::<Vec<f32>>()),
+            Series::new("diameter", self.clusters.iter().map(|c| c.diameter).collect::<Vec<f32>>())
+        ])?;
+        Ok(df)
+    }
+
+    /// The metrics as pretty printed JSON.
+    pub fn to_json(&self) -> Result<String, CustomError> {
+        serde_json::to_string_pretty(self)
+            .map_err(|e| CustomError::general_error(format!("Error serializing metrics: {e:?}")))
+    }
+}
+impl fmt::Display for ClusterMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let score = |s: Option<f32>| s.map_or("n/a".to_string(), |s| format!("{s:.4}"));
+        write!(
+            f,
+            "{} clusters of {} rows, noise fraction {:.4}, silhouette {}, Davies-Bouldin {}, Calinski-Harabasz {}",
+            self.clusters.len(),
+            self.rows,
+            self.noise_fraction,
+            score(self.silhouette),
+            score(self.davies_bouldin),
+            score(self.calinski_harabasz)
+        )
+    }
+}
+
+
+/// The rows of each cluster, indexed by cluster.
+pub(crate) fn cluster_members(labels: &[Option<usize>]) -> Vec<Vec<usize>> {
+    let n_clusters = labels.iter().flatten().max().map_or(0, |l| l + 1);
+    let mut members = vec![Vec::new(); n_clusters];
+    for (row, label) in labels.iter().enumerate() {
+        if let Some(label) = label {
+            members[*label].push(row);
+        }
+    }
+    members
+}
+
+
+/// The silhouette of each row from a function that gives the distance from a row to every
+/// row. A row's silhouette compares its mean distance to its own cluster with the mean distance
+/// to the nearest other cluster, from -1 to 1. Rows alone in a cluster score 0. Noise, and
+/// every row when there are fewer than two clusters, is None.
+pub fn silhouette_samples<I>(labels: &[Option<usize>], row_distances: impl Fn(usize) -> I) -> Vec<Option<f32>>
+where
+    I: Iterator<Item = (usize, f32)>
+{
+    let sizes: Vec<usize> = cluster_members(labels).iter().map(|m| m.len()).collect();
+    if sizes.iter().filter(|s| **s > 0).count() < 2 {
+        return vec![None; labels.len()];
+    }
+
+    let mut sums = vec![0f64; sizes.len()];
+    labels.iter()
+        .enumerate()
+        .map(|(row, label)| {
+            let label = (*label)?;
+            if sizes[label] == 1 {
+                return Some(0.0);
+            }
+            sums.iter_mut().for_each(|s| *s = 0.0);
+            for (other, distance) in row_distances(row) {
+                if let Some(other) = labels[other] {
+                    sums[other] += distance as f64;
+                }
+            }
+            let a = sums[label] / (sizes[label] - 1) as f64;
+            let b = (0..sizes.len())
+                .filter(|c| *c != label && sizes[*c] > 0)
+                .map(|c| sums[c] / sizes[c] as f64)
+                .fold(f64::INFINITY, f64::min);
+            let scale = a.max(b);
+            Some(if scale > 0.0 { ((b - a) / scale) as f32 } else { 0.0 })
+        })
+        .collect()
+}
+
+
+/// The mean of the silhouettes that are set.
+pub fn mean_silhouette(silhouettes: &[Option<f32>]) -> Option<f32> {
+    let scored: Vec<f32> = silhouettes.iter().flatten().copied().collect();
+    if scored.is_empty() {
+        return None;
+    }
+    Some((scored.iter().map(|s| *s as f64).sum::<f64>() / scored.len() as f64) as f32)
+}
+
+
+/// The Davies-Bouldin and Calinski-Harabasz indexes of the clusters.
+fn centroid_scores(dataset: &Array2<f32>, members: &[Vec<usize>]) -> (Option<f32>, Option<f32>) {
+    let members: Vec<&Vec<usize>> = members.iter().filter(|m| !m.is_empty()).collect();
+    let rows: usize = members.iter().map(|m| m.len()).sum();
+    if members.len() < 2 {
+        return (None, None);
+    }
+
+    let squared = |a: &Array1<f32>, b: ndarray::ArrayView1<f32>| -> f64 {
+        a.iter().zip(b.iter()).map(|(a, b)| ((a - b) as f64).powi(2)).sum()
+    };
+    let centroids: Vec<Array1<f32>> = members.iter()
+        .map(|m| dataset.select(Axis(0), m).mean_axis(Axis(0)).expect("A non-empty cluster."))
+        .collect();
+    // The mean distance of the members of each cluster to its centroid, and the squared sum
+    let mut scatter = Vec::with_capacity(members.len());
+    let mut within = 0f64;
+    for (rows, centroid) in members.iter().zip(&centroids) {
+        let mut total = 0f64;
+        for row in rows.iter() {
+            let d = squared(centroid, dataset.row(*row));
+            total += d.sqrt();
+            within += d;
+        }
+        scatter.push(total / rows.len() as f64);
+    }
+
+    let davies_bouldin = (0..members.len())
+        .map(|i| {
+            (0..members.len())
+                .filter(|j| *j != i)
+                .map(|j| {
+                    let separation = squared(&centroids[i], centroids[j].view()).sqrt();
+                    if separation > 0.0 { (scatter[i] + scatter[j]) / separation } else { f64::INFINITY }
+                })
+                .fold(0f64, f64::max)
+        })
+        .sum::<f64>() / members.len() as f64;
+
+    let calinski_harabasz = if rows > members.len() {
+        let clustered: Vec<usize> = members.iter().flat_map(|m| m.iter().copied()).collect();
+        let mean = dataset.select(Axis(0), &clustered).mean_axis(Axis(0)).expect("Clustered rows.");
+        let between: f64 = members.iter()
+            .zip(&centroids)
+            .map(|(m, c)| m.len() as f64 * squared(&mean, c.view()))
+            .sum();
+        let k = members.len() as f64;
+        if within > 0.0 {
+            Some(((between / (k - 1.0)) / (within / (rows as f64 - k))) as f32)
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+    (Some(davies_bouldin as f32), calinski_harabasz)
+}
@@ -0,0 +1,464 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use async_trait::async_trait;
+use openai_api_rs::v1::embedding::EmbeddingResponse;
+use reqwest::Url;
+use reqwest::header::HeaderMap;
+use serde_json::{json, Value};
+use crate::errors::CustomError;
+
+
+/// Create an EmbeddingResponse from raw vectors. Used by providers that do not return an
+/// OpenAI response.
+pub fn response_from_vectors(model: impl AsRef<str>, vectors: Vec<Vec<f32>>, prompt_tokens: i32) -> Result<EmbeddingResponse, CustomError> {
+    let data: Vec<Value> = vectors.into_iter()
+        .enumerate()
+        .map(|(index, embedding)| json!({
+            "object": "embedding",
+            "embedding": embedding,
+            "index": index
+        }))
+        .collect();
+
+    response_from_json(json!({
+        "object": "list",
+        "data": data,
+        "model": model.as_ref(),
+        "usage": {"prompt_tokens": prompt_tokens, "total_tokens": prompt_tokens}
+    }), model)
+}
+
+/// Parse an OpenAI style embeddings response. Fields that OpenAI-compatible servers commonly
+/// leave out are filled in with defaults.
+pub fn response_from_json(mut value: Value, model: impl AsRef<str>) -> Result<EmbeddingResponse, CustomError> {
+    if let Value::Object(object) = &mut value {
+        object.entry("object").or_insert(json!("list"));
+        object.entry("model").or_insert(json!(model.as_ref()));
+        object.entry("usage").or_insert(json!({"prompt_tokens": 0, "total_tokens": 0}));
+        if let Some(Value::Array(data)) = object.get_mut("data") {
+            for (i, item) in data.iter_mut().enumerate() {
+                if let Value::Object(item) = item {
+                    item.entry("object").or_insert(json!("embedding"));
+                    item.entry("index").or_insert(json!(i));
+                }
+            }
+        }
+    }
+
+    serde_json::from_value(value)
+        .map_err(|e| CustomError::general_error(format!("Error parsing embedding response: {e:?}")))
+}
+
+
+/// Parse rate-limit reset durations such as `1s`, `6m0s` or `20ms`.
+pub fn parse_reset_duration(value: &str) -> Option<Duration> {
+    let mut total = 0f64;
+    let mut number = String::new();
+    let mut chars = value.trim().chars().peekable();
+    let mut parsed = false;
+    while let Some(c) = chars.next() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+            continue;
+        }
+        let amount: f64 = number.parse().ok()?;
+        number.clear();
+        let seconds = match c {
+            'h' => amount * 3600.0,
+            'm' if chars.peek() == Some(&'s') => {
+                chars.next();
+                amount / 1000.0
+            },
+            'm' => amount * 60.0,
+            's' => amount,
+            _ => return None
+        };
+        total += seconds;
+        parsed = true;
+    }
+    if !number.is_empty() {
+        // A bare number is seconds
+        total += number.parse::<f64>().ok()?;
+        parsed = true;
+    }
+    if parsed { Some(Duration::from_secs_f64(total)) } else { None }
+}
+
+
+/// Rate-limit state reported in the response headers of OpenAI style APIs.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimitInfo {
+    pub remaining_requests: Option<u64>,
+    pub remaining_tokens: Option<u64>,
+    pub reset_requests: Option<Duration>,
+    pub reset_tokens: Option<Duration>,
+    pub retry_after: Option<Duration>
+}
+impl RateLimitInfo {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let get = |name: &str| headers.get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+
+        let retry_after = get("retry-after-ms")
+            .and_then(|v| v.parse::<f64>().ok())
+            .map(|ms| Duration::from_secs_f64(ms / 1000.0))
+            .or_else(|| get("retry-after").and_then(|v| parse_reset_duration(&v)));
+
+        Self {
+            remaining_requests: get("x-ratelimit-remaining-requests").and_then(|v| v.parse().ok()),
+            remaining_tokens: get("x-ratelimit-remaining-tokens").and_then(|v| v.parse().ok()),
+            reset_requests: get("x-ratelimit-reset-requests").and_then(|v| parse_reset_duration(&v)),
+            reset_tokens: get("x-ratelimit-reset-tokens").and_then(|v| parse_reset_duration(&v)),
+            retry_after
+        }
+    }
+
+    /// How long to wait before sending another request, if a limit is exhausted.
+    pub fn wait(&self) -> Option<Duration> {
+        let requests = match self.remaining_requests {
+            Some(0) => self.reset_requests,
+            _ => None
+        };
+        let tokens = match self.remaining_tokens {
+            Some(0) => self.reset_tokens,
+            _ => None
+        };
+        requests.max(tokens)
+    }
+}
+
+
+/// A service or library that can create embeddings.
+#[async_trait]
+pub trait EmbeddingProvider: Send + Sync {
+    /// The provider name used in the cache namespace.
+    fn name(&self) -> &str;
+
+    /// The model identifier stored in the cache metadata.
+    fn model(&self) -> &str;
+
+    /// The requested embedding dimensions.
+    fn dimensions(&self) -> Option<i32>;
+
+    /// Get the embedding response for an input.
+    async fn embed(&self, input: String) -> Result<EmbeddingResponse, CustomError>;
+
+    /// Can the vectors be cached for later runs. Providers whose vectors depend on the inputs
+    /// of a run are not cached.
+    fn is_cacheable(&self) -> bool {
+        true
+    }
+
+    /// Are long inputs truncated or chunked at the cl100k_base token limit. Providers that
+    /// tokenize inputs themselves, or have no limit, embed the whole input.
+    fn has_token_limit(&self) -> bool {
+        true
+    }
+
+    /// The rate-limit state from the last response, for providers that report one.
+    fn rate_limit(&self) -> Option<RateLimitInfo> {
+        None
+    }
+
+    /// Get the embedding response for many inputs where the `index` of each embedding is the
+    /// position of its input. By default each input is embedded on its own.
+    async fn embed_batch(&self, inputs: Vec<String>) -> Result<EmbeddingResponse, CustomError> {
+        let mut vectors = Vec::with_capacity(inputs.len());
+        let mut prompt_tokens = 0;
+        for input in inputs {
+            let response = self.embed(input).await?;
+            prompt_tokens += response.usage.prompt_tokens;
+            let data = response.data
+                .into_iter()
+                .next()
+                .ok_or_else(|| CustomError::general_error("No embedding data!"))?;
+            vectors.push(data.embedding);
+        }
+        response_from_vectors(self.model(), vectors, prompt_tokens)
+    }
+}
+
+
+/// The root of the OpenAI API.
+pub const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+
+
+/// Embeddings from the OpenAI API.
+pub struct OpenAIProvider {
+    inner: OpenAICompatibleProvider
+}
+impl OpenAIProvider {
+    pub fn new(api_key: String, model: impl AsRef<str>, dimensions: Option<i32>) -> Self {
+        Self {
+            inner: OpenAICompatibleProvider::new(OPENAI_BASE_URL, Some(api_key), model, dimensions)
+        }
+    }
+
+    /// Set the timeout of each request.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.inner = self.inner.with_timeout(timeout);
+        self
+    }
+
+    /// The URL and headers of the embeddings requests.
+    pub fn embedding_request(&self) -> Result<EmbeddingRequest, CustomError> {
+        self.inner.embedding_request()
+    }
+}
+#[async_trait]
+impl EmbeddingProvider for OpenAIProvider {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    fn model(&self) -> &str {
+        self.inner.model()
+    }
+
+    fn dimensions(&self) -> Option<i32> {
+        self.inner.dimensions()
+    }
+
+    async fn embed(&self, input: String) -> Result<EmbeddingResponse, CustomError> {
+        self.inner.embed(input).await
+    }
+
+    async fn embed_batch(&self, inputs: Vec<String>) -> Result<EmbeddingResponse, CustomError> {
+        self.inner.embed_batch(inputs).await
+    }
+
+    fn rate_limit(&self) -> Option<RateLimitInfo> {
+        self.inner.rate_limit()
+    }
+}
+
+
+/// The URL and headers of an embeddings request, without the body.
+pub struct EmbeddingRequest {
+    pub url: Url,
+    pub headers: Vec<(&'static str, String)>
+}
+impl EmbeddingRequest {
+    fn new(url: &str, params: &[(&str, &str)]) -> Result<Self, CustomError> {
+        let mut url = Url::parse(url)
+            .map_err(|e| CustomError::general_error(format!("Invalid embeddings URL {url}: {e}")))?;
+        if !params.is_empty() {
+            url.query_pairs_mut().extend_pairs(params);
+        }
+        Ok(Self {
+            url,
+            headers: Vec::new()
+        })
+    }
+
+    fn with_header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    /// A POST request with the URL and headers.
+    fn builder(self, client: &reqwest::Client) -> reqwest::RequestBuilder {
+        self.headers.into_iter()
+            .fold(client.post(self.url), |request, (name, value)| request.header(name, value))
+    }
+}
+
+
+/// Create an HTTP client with a request timeout.
+fn client_with_timeout(timeout: Duration) -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .expect("Error building HTTP client.")
+}
+
+
+/// Send an embeddings request and parse the response.
+async fn post_embedding_request(
+    request: reqwest::RequestBuilder,
+    rate_limit: &Mutex<Option<RateLimitInfo>>,
+    model: &str,
+    input: Value,
+    dimensions: Option<i32>
+) -> Result<EmbeddingResponse, CustomError> {
+    let mut body = json!({
+        "model": model,
+        "input": input
+    });
+    if let Some(d) = dimensions {
+        body["dimensions"] = json!(d);
+    }
+
+    let response = request.json(&body)
+        .send()
+        .await?;
+
+    let status = response.status();
+    let info = RateLimitInfo::from_headers(response.headers());
+    let retry_after = info.retry_after.or(info.wait());
+    *rate_limit.lock().expect("Lock poisoned.") = Some(info);
+
+    if !status.is_success() {
+        let text = response.text().await.unwrap_or_default();
+        return Err(CustomError::http_status_error(
+            status.as_u16(),
+            retry_after,
+            format!("{status}: {text}")
+        ));
+    }
+
+    let value: Value = response.json().await?;
+    response_from_json(value, model)
+}
+
+
+/// Embeddings from any server that implements the OpenAI embeddings API such as Ollama, vLLM,
+/// LocalAI or text-embeddings-inference.
+pub struct OpenAICompatibleProvider {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+    dimensions: Option<i32>,
+    rate_limit: Mutex<Option<RateLimitInfo>>
+}
+impl OpenAICompatibleProvider {
+    /// Create a provider where `base_url` is the API root, e.g. `http://localhost:11434/v1`.
+    pub fn new(base_url: impl AsRef<str>, api_key: Option<String>, model: impl AsRef<str>, dimensions: Option<i32>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: base_url.as_ref().trim_end_matches('/').to_string(),
+            api_key,
+            model: model.as_ref().to_string(),
+            dimensions,
+            rate_limit: Mutex::new(None)
+        }
+    }
+
+    /// Set the timeout of each request.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.client = client_with_timeout(timeout);
+        self
+    }
+
+    /// The URL and headers of the embeddings requests, `{base_url}/embeddings` with a bearer
+    /// token when there is a key.
+    pub fn embedding_request(&self) -> Result<EmbeddingRequest, CustomError> {
+        let request = EmbeddingRequest::new(&format!("{}/embeddings", self.base_url), &[])?;
+        Ok(match &self.api_key {
+            Some(api_key) => request.with_header("authorization", format!("Bearer {api_key}")),
+            None => request
+        })
+    }
+
+    fn request(&self) -> Result<reqwest::RequestBuilder, CustomError> {
+        Ok(self.embedding_request()?.builder(&self.client))
+    }
+}
+#[async_trait]
+impl EmbeddingProvider for OpenAICompatibleProvider {
+    fn name(&self) -> &str {
+        "openai-compatible"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn dimensions(&self) -> Option<i32> {
+        self.dimensions
+    }
+
+    async fn embed(&self, input: String) -> Result<EmbeddingResponse, CustomError> {
+        post_embedding_request(self.request()?, &self.rate_limit, &self.model, json!(input), self.dimensions).await
+    }
+
+    async fn embed_batch(&self, inputs: Vec<String>) -> Result<EmbeddingResponse, CustomError> {
+        post_embedding_request(self.request()?, &self.rate_limit, &self.model, json!(inputs), self.dimensions).await
+    }
+
+    fn rate_limit(&self) -> Option<RateLimitInfo> {
+        self.rate_limit.lock().expect("Lock poisoned.").clone()
+    }
+}
+
+
+/// Embeddings from an Azure OpenAI deployment.
+pub struct AzureOpenAIProvider {
+    client: reqwest::Client,
+    endpoint: String,
+    deployment: String,
+    api_version: String,
+    api_key: String,
+    model: String,
+    dimensions: Option<i32>,
+    rate_limit: Mutex<Option<RateLimitInfo>>
+}
+impl AzureOpenAIProvider {
+    /// Create a provider where `endpoint` is the resource endpoint, e.g.
+    /// `https://my-resource.openai.azure.com`. The `model` is the model behind the deployment.
+    pub fn new(
+        endpoint: impl AsRef<str>,
+        deployment: impl AsRef<str>,
+        api_version: impl AsRef<str>,
+        api_key: String,
+        model: impl AsRef<str>,
+        dimensions: Option<i32>
+    ) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            endpoint: endpoint.as_ref().trim_end_matches('/').to_string(),
+            deployment: deployment.as_ref().to_string(),
+            api_version: api_version.as_ref().to_string(),
+            api_key,
+            model: model.as_ref().to_string(),
+            dimensions,
+            rate_limit: Mutex::new(None)
+        }
+    }
+
+    /// Set the timeout of each request.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.client = client_with_timeout(timeout);
+        self
+    }
+
+    /// The URL and headers of the embeddings requests. Azure takes the deployment in the path,
+    /// the API version in the query and the key in an `api-key` header.
+    pub fn embedding_request(&self) -> Result<EmbeddingRequest, CustomError> {
+        let url = format!("{}/openai/deployments/{}/embeddings", self.endpoint, self.deployment);
+        Ok(EmbeddingRequest::new(&url, &[("api-version", &self.api_version)])?
+            .with_header("api-key", self.api_key.clone()))
+    }
+
+    fn request(&self) -> Result<reqwest::RequestBuilder, CustomError> {
+        Ok(self.embedding_request()?.builder(&self.client))
+    }
+}
+#[async_trait]
+impl EmbeddingProvider for AzureOpenAIProvider {
+    fn name(&self) -> &str {
+        "azure"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn dimensions(&self) -> Option<i32> {
+        self.dimensions
+    }
+
+    async fn embed(&self, input: String) -> Result<EmbeddingResponse, CustomError> {
+        post_embedding_request(self.request()?, &self.rate_limit, &self.model, json!(input), self.dimensions).await
+    }
+
+    async fn embed_batch(&self, inputs: Vec<String>) -> Result<EmbeddingResponse, CustomError> {
+        post_embedding_request(self.request()?, &self.rate_limit, &self.model, json!(inputs), self.dimensions).await
+    }
+
+    fn rate_limit(&self) -> Option<RateLimitInfo> {
+        self.rate_limit.lock().expect("Lock poisoned.").clone()
+    }
+}
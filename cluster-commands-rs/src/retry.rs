@@ -0,0 +1,169 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use rand::Rng;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+
+/// How failed requests are retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: usize,
+    pub base_delay: Duration,
+    pub max_delay: Duration
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(60)
+        }
+    }
+}
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    /// Get the delay before retry `attempt` (starting at 0) using exponential backoff with
+    /// jitter. A server provided `retry_after` is used as the minimum delay.
+    pub fn delay(&self, attempt: usize, retry_after: Option<Duration>) -> Duration {
+        let exponent = attempt.min(16) as u32;
+        let backoff = self.base_delay
+            .saturating_mul(2u32.saturating_pow(exponent))
+            .min(self.max_delay);
+
+        // Equal jitter keeps at least half of the backoff
+        let half = backoff / 2;
+        let jitter = rand::thread_rng().gen_range(0.0..=1.0);
+        let delay = half + half.mul_f64(jitter);
+
+        match retry_after {
+            Some(retry_after) => delay.max(retry_after),
+            None => delay
+        }
+    }
+}
+
+
+/// A permit from an AdaptiveLimiter that is released when dropped.
+pub struct LimiterPermit {
+    permit: Option<OwnedSemaphorePermit>,
+    limiter: Arc<AdaptiveLimiter>
+}
+impl Drop for LimiterPermit {
+    fn drop(&mut self) {
+        if let Some(permit) = self.permit.take() {
+            // Forget the permit if the limit was lowered while it was held
+            let reduced = self.limiter.pending_reduction
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+            if reduced {
+                permit.forget();
+            }
+        }
+    }
+}
+
+
+/// Limits the number of requests in flight. The limit is halved when requests are throttled
+/// and grows by one after a full window of successful requests.
+pub struct AdaptiveLimiter {
+    semaphore: Arc<Semaphore>,
+    limit: AtomicUsize,
+    min_limit: usize,
+    max_limit: usize,
+    successes: AtomicUsize,
+    pending_reduction: AtomicUsize,
+    pause_until: Mutex<Option<Instant>>
+}
+impl AdaptiveLimiter {
+    pub fn new(max_limit: usize, min_limit: usize) -> Arc<Self> {
+        let max_limit = max_limit.max(1);
+        Arc::new(Self {
+            semaphore: Arc::new(Semaphore::new(max_limit)),
+            limit: AtomicUsize::new(max_limit),
+            min_limit: min_limit.clamp(1, max_limit),
+            max_limit,
+            successes: AtomicUsize::new(0),
+            pending_reduction: AtomicUsize::new(0),
+            pause_until: Mutex::new(None)
+        })
+    }
+
+    /// The current number of requests allowed in flight.
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::SeqCst)
+    }
+
+    /// Wait until requests are not paused and a permit is available.
+    pub async fn acquire(self: &Arc<Self>) -> LimiterPermit {
+        self.wait_for_pause().await;
+        let permit = self.semaphore.clone()
+            .acquire_owned()
+            .await
+            .expect("Semaphore closed.");
+        // A pause could have started while waiting for the permit
+        self.wait_for_pause().await;
+        LimiterPermit {
+            permit: Some(permit),
+            limiter: self.clone()
+        }
+    }
+
+    async fn wait_for_pause(&self) {
+        loop {
+            let pause_until = *self.pause_until.lock().expect("Lock poisoned.");
+            match pause_until {
+                Some(until) if until > Instant::now() => tokio::time::sleep_until(until).await,
+                _ => break
+            }
+        }
+    }
+
+    /// Stop new requests for a duration.
+    pub fn pause(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        let mut pause_until = self.pause_until.lock().expect("Lock poisoned.");
+        if pause_until.is_none_or(|current| current < until) {
+            *pause_until = Some(until);
+        }
+    }
+
+    /// Lower the limit after being throttled.
+    pub fn on_throttle(&self) {
+        let previous = self.limit.load(Ordering::SeqCst);
+        let reduced = (previous / 2).max(self.min_limit);
+        if reduced < previous && self.limit.compare_exchange(previous, reduced, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            self.pending_reduction.fetch_add(previous - reduced, Ordering::SeqCst);
+            self.successes.store(0, Ordering::SeqCst);
+            warn!("Throttled, lowering parallel requests from {} to {}.", previous, reduced);
+        }
+    }
+
+    /// Raise the limit after a window of successful requests.
+    pub fn on_success(&self) {
+        let limit = self.limit.load(Ordering::SeqCst);
+        let successes = self.successes.fetch_add(1, Ordering::SeqCst) + 1;
+        if limit >= self.max_limit || successes < limit {
+            return;
+        }
+        if self.limit.compare_exchange(limit, limit + 1, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            self.successes.store(0, Ordering::SeqCst);
+            // Cancel a pending reduction before adding a new permit
+            let cancelled = self.pending_reduction
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+            if !cancelled {
+                self.semaphore.add_permits(1);
+            }
+            debug!("Raising parallel requests to {}.", limit + 1);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use polars::prelude::*;
+use crate::clusterer::dbscan_w_neighbors;
+use crate::distance::PairwiseDistances;
+use crate::metrics::{mean_silhouette, silhouette_samples};
+use crate::errors::CustomError;
+
+
+/// The cluster statistics of one tolerance and min_points combination.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SweepResult {
+    pub tolerance: f32,
+    pub min_points: usize,
+    pub clusters: usize,
+    /// The fraction of rows that are noise.
+    pub noise_ratio: f32,
+    pub largest_cluster: usize,
+    /// The mean silhouette of the clustered rows, None with fewer than two clusters.
+    pub silhouette: Option<f32>
+}
+impl SweepResult {
+    fn from_labels(tolerance: f32, min_points: usize, labels: &[Option<usize>], distances: &PairwiseDistances) -> Self {
+        let mut sizes: HashMap<usize, usize> = HashMap::new();
+        for label in labels.iter().flatten() {
+            *sizes.entry(*label).or_default() += 1;
+        }
+        let noise = labels.iter().filter(|l| l.is_none()).count();
+        Self {
+            tolerance,
+            min_points,
+            clusters: sizes.len(),
+            noise_ratio: noise as f32 / labels.len().max(1) as f32,
+            largest_cluster: sizes.values().copied().max().unwrap_or(0),
+            silhouette: mean_silhouette(&silhouette_samples(labels, |row| {
+                distances.neighbors(row).iter().map(|(other, d)| (*other as usize, *d))
+            }))
+        }
+    }
+}
+
+
+/// DBSCAN over precomputed distances, with the same labels as the linfa DBSCAN of
+/// `DbscanClusterer`.
+pub fn dbscan_w_distances(distances: &PairwiseDistances, min_points: usize, tolerance: f32) -> Vec<Option<usize>> {
+    dbscan_w_neighbors(distances, min_points, tolerance)
+}
+
+
+/// Run DBSCAN for every combination of `tolerances` and `min_points` over the same distances,
+/// ordered by tolerance and then min_points.
+pub fn sweep(distances: &PairwiseDistances, tolerances: &[f32], min_points: &[usize]) -> Vec<SweepResult> {
+    let mut results = Vec::with_capacity(tolerances.len() * min_points.len());
+    for tolerance in tolerances {
+        for points in min_points {
+            let labels = dbscan_w_distances(distances, *points, *tolerance);
+            results.push(SweepResult::from_labels(*tolerance, *points, &labels, distances));
+        }
+    }
+    results
+}
+
+
+/// The sweep results as a table with a row per combination.
+pub fn sweep_dataframe(results: &[SweepResult]) -> Result<DataFrame, CustomError> {
+    let df = DataFrame::new(vec![
+        Series::new("tolerance", results.iter().map(|r| r.tolerance).collect::<Vec<f32>>()),
+        Series::new("min_points", results.iter().map(|r| r.min_points as u32).collect::<Vec<u32>>()),
+        Series::new("clusters", results.iter().map(|r| r.clusters as u32).collect::<Vec<u32>>()),
+        Series::new("noise_ratio", results.iter().map(|r| r.noise_ratio).collect::<Vec<f32>>()),
+        Series::new("largest_cluster", results.iter().map(|r| r.largest_cluster as u32).collect::<Vec<u32>>()),
+        Series::new("silhouette", results.iter().map(|r| r.silhouette).collect::<Vec<Option<f32>>>())
+    ])?;
+    Ok(df)
+}
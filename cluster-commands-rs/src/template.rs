@@ -0,0 +1,166 @@
+use std::fmt;
+use polars::prelude::{DataFrame, DataType};
+use crate::errors::CustomError;
+
+
+/// The template that embeds only the command line.
+pub const DEFAULT_INPUT_TEMPLATE: &str = "{CommandLine}";
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TemplatePart {
+    Literal(String),
+    Field(String)
+}
+
+
+/// A template of the text to embed for each record, e.g.
+/// `{ParentImage} -> {Image}: {CommandLine} (user {User})`. Fields are the names of DataFrame
+/// columns in braces and `{{` or `}}` are literal braces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputTemplate {
+    template: String,
+    parts: Vec<TemplatePart>
+}
+impl Default for InputTemplate {
+    fn default() -> Self {
+        Self::parse(DEFAULT_INPUT_TEMPLATE).expect("Invalid default template.")
+    }
+}
+impl std::str::FromStr for InputTemplate {
+    type Err = CustomError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+impl fmt::Display for InputTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.template)
+    }
+}
+impl InputTemplate {
+    /// Parse a template. It must have at least one field and field names cannot be empty or
+    /// contain quotes.
+    pub fn parse(template: impl AsRef<str>) -> Result<Self, CustomError> {
+        let template = template.as_ref();
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                },
+                '{' => {
+                    let mut field = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some('{') | None => return Err(CustomError::general_error(
+                                format!("Unclosed field in template {:?}.", template)
+                            )),
+                            Some(c) => field.push(c)
+                        }
+                    }
+                    let field = field.trim().to_string();
+                    if field.is_empty() || field.contains('"') {
+                        return Err(CustomError::general_error(
+                            format!("Invalid field {:?} in template {:?}.", field, template)
+                        ));
+                    }
+                    if !literal.is_empty() {
+                        parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(TemplatePart::Field(field));
+                },
+                '}' => return Err(CustomError::general_error(
+                    format!("Unmatched }} in template {:?}, use }}}} for a literal brace.", template)
+                )),
+                c => literal.push(c)
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+
+        if !parts.iter().any(|p| matches!(p, TemplatePart::Field(_))) {
+            return Err(CustomError::general_error(
+                format!("Template {:?} has no fields.", template)
+            ));
+        }
+
+        Ok(Self {
+            template: template.to_string(),
+            parts
+        })
+    }
+
+    /// Is this the template that embeds only the command line.
+    pub fn is_default(&self) -> bool {
+        self.parts == [TemplatePart::Field("CommandLine".to_string())]
+    }
+
+    /// The unique field names in the order they appear.
+    pub fn fields(&self) -> Vec<&str> {
+        let mut fields: Vec<&str> = Vec::new();
+        for part in &self.parts {
+            if let TemplatePart::Field(field) = part {
+                if !fields.contains(&field.as_str()) {
+                    fields.push(field);
+                }
+            }
+        }
+        fields
+    }
+
+    /// Render the template with a function that gets the value of a field. Missing values are
+    /// rendered as empty strings.
+    pub fn render<'v>(&self, value: impl Fn(&str) -> Option<&'v str>) -> String {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                TemplatePart::Literal(literal) => rendered.push_str(literal),
+                TemplatePart::Field(field) => rendered.push_str(value(field).unwrap_or(""))
+            }
+        }
+        rendered
+    }
+
+    /// Render the template for each row of a DataFrame. Every field must be a column, columns
+    /// that are not strings are cast to strings.
+    pub fn render_dataframe(&self, df: &DataFrame) -> Result<Vec<String>, CustomError> {
+        let fields = self.fields();
+        let missing: Vec<&str> = fields.iter()
+            .filter(|f| df.column(f).is_err())
+            .copied()
+            .collect();
+        if !missing.is_empty() {
+            return Err(CustomError::general_error(
+                format!("Template fields are not columns: {}.", missing.join(", "))
+            ));
+        }
+
+        let columns = fields.iter()
+            .map(|f| df.column(f)?.cast(&DataType::String))
+            .collect::<Result<Vec<_>, _>>()?;
+        let columns = columns.iter()
+            .map(|c| c.str())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let rendered = (0..df.height())
+            .map(|row| self.render(|field| {
+                fields.iter()
+                    .position(|f| *f == field)
+                    .and_then(|i| columns[i].get(row))
+            }))
+            .collect();
+        Ok(rendered)
+    }
+}
@@ -0,0 +1,166 @@
+use std::fmt;
+use std::sync::OnceLock;
+use tiktoken_rs::CoreBPE;
+
+
+/// The token limit of the OpenAI embedding models.
+pub const DEFAULT_MAX_INPUT_TOKENS: usize = 8191;
+
+
+/// The cl100k_base tokenizer used by the OpenAI embedding models.
+pub fn tokenizer() -> &'static CoreBPE {
+    static BPE: OnceLock<CoreBPE> = OnceLock::new();
+    BPE.get_or_init(|| tiktoken_rs::cl100k_base().expect("Error loading cl100k_base."))
+}
+
+/// Count the cl100k_base tokens of an input.
+pub fn count_tokens(input: impl AsRef<str>) -> usize {
+    tokenizer().encode_ordinary(input.as_ref()).len()
+}
+
+
+/// How inputs longer than the token limit are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LongInputStrategy {
+    /// Embed only the first tokens of the input.
+    Truncate,
+    /// Embed chunks of the input and combine them with a token weighted average.
+    Chunk
+}
+impl std::str::FromStr for LongInputStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "truncate" => Ok(Self::Truncate),
+            "chunk" => Ok(Self::Chunk),
+            _ => Err(format!("invalid long input strategy: {s}"))
+        }
+    }
+}
+impl fmt::Display for LongInputStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncate => write!(f, "truncate"),
+            Self::Chunk => write!(f, "chunk")
+        }
+    }
+}
+
+
+/// How an input was prepared for embedding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputHandling {
+    /// The whole input was embedded.
+    Full,
+    /// Only the first tokens were embedded.
+    Truncated,
+    /// The input was embedded in this many chunks.
+    Chunked(usize)
+}
+impl fmt::Display for InputHandling {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full => write!(f, "full"),
+            Self::Truncated => write!(f, "truncated"),
+            Self::Chunked(n) => write!(f, "chunked({n})")
+        }
+    }
+}
+
+
+/// An input split into the texts that are sent to the embedding API.
+#[derive(Debug, Clone)]
+pub struct PreparedInput {
+    pub value: String,
+    pub tokens: usize,
+    pub handling: InputHandling,
+    pub chunks: Vec<String>,
+    pub chunk_tokens: Vec<usize>
+}
+impl PreparedInput {
+    /// Count the tokens of an input and truncate or chunk it when it exceeds `max_tokens`.
+    pub fn new(value: impl AsRef<str>, max_tokens: usize, strategy: LongInputStrategy) -> Self {
+        let value = value.as_ref().to_string();
+        let max_tokens = max_tokens.max(1);
+        let tokens = tokenizer().encode_ordinary(&value);
+
+        if tokens.len() <= max_tokens {
+            return Self {
+                tokens: tokens.len(),
+                handling: InputHandling::Full,
+                chunks: vec![value.clone()],
+                chunk_tokens: vec![tokens.len()],
+                value
+            };
+        }
+
+        let pieces = match strategy {
+            LongInputStrategy::Truncate => split_tokens(&tokens, max_tokens, 1),
+            LongInputStrategy::Chunk => split_tokens(&tokens, max_tokens, usize::MAX)
+        };
+        let (chunks, chunk_tokens): (Vec<String>, Vec<usize>) = pieces.into_iter().unzip();
+        let handling = match strategy {
+            LongInputStrategy::Truncate => InputHandling::Truncated,
+            LongInputStrategy::Chunk => InputHandling::Chunked(chunks.len())
+        };
+
+        Self {
+            value,
+            tokens: tokens.len(),
+            handling,
+            chunks,
+            chunk_tokens
+        }
+    }
+
+    /// The key used to cache the embedding. Inputs that were truncated or chunked include the
+    /// handling so different strategies do not share cache entries.
+    pub fn cache_key(&self) -> blake3::Hash {
+        match self.handling {
+            InputHandling::Full => blake3::hash(self.value.as_bytes()),
+            _ => {
+                let max_tokens = self.chunk_tokens.first().copied().unwrap_or(0);
+                blake3::hash(format!("{}:{}:{}", self.handling, max_tokens, self.value).as_bytes())
+            }
+        }
+    }
+}
+
+
+/// Split tokens into at most `limit` pieces of up to `max_tokens` tokens. A piece ends where its
+/// tokens decode to whole characters, so a character that spans several tokens is never split.
+/// Only a character with more tokens than `max_tokens` makes a piece longer than the limit.
+fn split_tokens(tokens: &[usize], max_tokens: usize, limit: usize) -> Vec<(String, usize)> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    while start < tokens.len() && pieces.len() < limit {
+        let end = (start + max_tokens).min(tokens.len());
+        let piece = (start + 1..=end).rev()
+            .chain(end + 1..=tokens.len())
+            .find_map(|end| tokenizer().decode(tokens[start..end].to_vec()).ok().map(|text| (text, end)));
+        // The pieces before start decoded, so the rest of the tokens of the input always does
+        let (text, end) = piece.expect("The remaining tokens of an input decode.");
+        pieces.push((text, end - start));
+        start = end;
+    }
+    pieces
+}
+
+
+/// Combine chunk embeddings using the token counts as weights. The result is L2 normalized.
+pub fn combine_chunk_embeddings(vectors: &[Vec<f32>], weights: &[usize]) -> Vec<f32> {
+    let dimensions = vectors.first().map_or(0, |v| v.len());
+    let mut combined = vec![0f32; dimensions];
+    for (vector, weight) in vectors.iter().zip(weights) {
+        for (c, v) in combined.iter_mut().zip(vector) {
+            *c += v * *weight as f32;
+        }
+    }
+
+    let norm = combined.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        combined.iter_mut().for_each(|v| *v /= norm);
+    }
+    combined
+}
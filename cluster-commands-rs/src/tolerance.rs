@@ -0,0 +1,113 @@
+use ndarray::Array2;
+use polars::prelude::*;
+use serde::Serialize;
+use crate::distance::{DistanceMetric, NeighborSearch};
+use crate::hierarchy::core_distances;
+use crate::errors::CustomError;
+
+
+/// The sorted k-nearest-neighbor distances of a dataset and the tolerance at their knee.
+#[derive(Debug, Clone, Serialize)]
+pub struct KDistanceAnalysis {
+    pub k: usize,
+    pub metric: String,
+    /// The distance of each row to its k'th nearest row, counting the row itself, ascending.
+    pub distances: Vec<f32>,
+    /// The index of the knee in `distances`.
+    pub knee_index: usize,
+    /// The distance at the knee. Rows at or below it have k rows within the tolerance.
+    pub tolerance: f32
+}
+impl KDistanceAnalysis {
+    /// Compute the k-distance curve with distances in `metric` and find its knee, the point
+    /// farthest below the line from the first to the last distance after both axes are scaled
+    /// to 0..1.
+    pub fn new(dataset: &Array2<f32>, k: usize, metric: DistanceMetric) -> Result<Self, CustomError> {
+        check_rows(dataset.nrows())?;
+        let k = k.max(2);
+        let prepared = metric.prepare(dataset);
+        let distances = core_distances(&prepared, k, metric)
+            .into_iter()
+            .map(|d| d as f32)
+            .collect();
+        Ok(Self::from_distances(k, metric, distances))
+    }
+
+    /// Compute the k-distance curve from the neighbors of a `NeighborSearch`, such as an
+    /// approximate nearest-neighbor index, instead of comparing every pair of rows.
+    pub fn from_neighbors(search: &dyn NeighborSearch, k: usize) -> Result<Self, CustomError> {
+        check_rows(search.rows())?;
+        let k = k.max(2);
+        let distances = (0..search.rows())
+            .map(|row| search.knn(row, k - 1).last().map_or(0.0, |(_, d)| *d))
+            .collect();
+        Ok(Self::from_distances(k, search.metric(), distances))
+    }
+
+    fn from_distances(k: usize, metric: DistanceMetric, mut distances: Vec<f32>) -> Self {
+        distances.sort_by(|a, b| a.total_cmp(b));
+        let knee_index = find_knee(&distances);
+        // linfa needs a positive tolerance when the knee is at duplicate vectors.
+        let tolerance = distances[knee_index].max(f32::EPSILON);
+        Self {
+            k,
+            metric: metric.to_string(),
+            distances,
+            knee_index,
+            tolerance
+        }
+    }
+
+    /// The curve with a rank, k_distance and is_knee column.
+    pub fn to_dataframe(&self) -> Result<DataFrame, CustomError> {
+        let ranks: Vec<u32> = (0..self.distances.len() as u32).collect();
+        let is_knee: Vec<bool> = (0..self.distances.len())
+            .map(|i| i == self.knee_index)
+            .collect();
+        let df = DataFrame::new(vec![
+            Series::new("rank", ranks),
+            Series::new("k_distance", &self.distances),
+            Series::new("is_knee", is_knee)
+        ])?;
+        Ok(df)
+    }
+}
+
+
+/// Check that there are enough rows for a k-distance curve.
+fn check_rows(rows: usize) -> Result<(), CustomError> {
+    if rows < 3 {
+        return Err(CustomError::general_error(format!(
+            "At least 3 embeddings are needed to select a tolerance, got {rows}."
+        )));
+    }
+    Ok(())
+}
+
+
+/// The index of the knee of an ascending curve. A straight or flat curve has its knee at the
+/// last point.
+pub fn find_knee(values: &[f32]) -> usize {
+    let last = values.len().saturating_sub(1);
+    if last == 0 {
+        return 0;
+    }
+    let (min, max) = (values[0], values[last]);
+    let range = max - min;
+    if range <= 0.0 {
+        return last;
+    }
+
+    let mut knee = last;
+    let mut best = 0f32;
+    for (i, value) in values.iter().enumerate() {
+        let x = i as f32 / last as f32;
+        let y = (value - min) / range;
+        let difference = x - y;
+        if difference > best {
+            best = difference;
+            knee = i;
+        }
+    }
+    knee
+}
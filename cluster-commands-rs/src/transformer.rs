@@ -1,4 +1,5 @@
 
+use std::collections::HashMap;
 use serde_json::{json, Map, Value};
 use jmespath;
 use jmespath::{Expression, JmespathError, ToJmespath, Rcvar, Runtime};
@@ -37,17 +38,181 @@ impl <'a>FieldRetriever<'a> {
 }
 
 
+/// Insert a value, adding a numeric suffix to the name if a different value already uses it.
+fn insert_unique(map: &mut Map<String, Value>, name: String, value: Value) {
+    match map.get(&name) {
+        None => {
+            map.insert(name, value);
+        },
+        Some(existing) if *existing == value => {},
+        Some(_) => {
+            let mut i = 2;
+            loop {
+                let candidate = format!("{name}_{i}");
+                if !map.contains_key(&candidate) {
+                    map.insert(candidate, value);
+                    break;
+                }
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Flatten a value into `out` where nested keys are joined with `_`. `#text` keys do not add to
+/// the name and arrays are kept as JSON strings.
+fn flatten_value(name: &str, value: &Value, out: &mut Vec<(String, Value)>) {
+    match value {
+        Value::Object(object) => {
+            for (key, child) in object {
+                let child_name = if key == "#text" {
+                    name.to_string()
+                } else if name.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{name}_{key}")
+                };
+                flatten_value(&child_name, child, out);
+            }
+        },
+        Value::Array(_) => out.push((name.to_string(), Value::String(value.to_string()))),
+        _ => out.push((name.to_string(), value.clone()))
+    }
+}
+
+
+/// Flattens all of `Event.EventData` and selected `Event.System` keys into columns. The
+/// `_attributes` objects that belong to a selected System key are included.
+pub struct EventFlattener {
+    pub system_keys: Vec<String>
+}
+impl EventFlattener {
+    /// Create an EventFlattener with the System keys to include.
+    pub fn new(system_keys: Vec<String>) -> Self {
+        Self {
+            system_keys
+        }
+    }
+
+    /// The System keys used when none are specified.
+    pub fn default_system_keys() -> Vec<String> {
+        ["EventID", "EventRecordID", "Computer", "Channel", "Provider", "TimeCreated"]
+            .iter()
+            .map(|k| k.to_string())
+            .collect()
+    }
+
+    /// Flatten a record into `map`. Keys already in the map take precedence. System keys that
+    /// are already in the map with another value are prefixed with `System_`, and skipped when
+    /// the value is the same.
+    pub fn flatten_into(&self, record: &Value, map: &mut Map<String, Value>) {
+        let mut flattened = Vec::new();
+        if let Some(event_data) = record.pointer("/Event/EventData") {
+            match event_data {
+                Value::Null => {},
+                Value::Object(_) => flatten_value("", event_data, &mut flattened),
+                _ => flatten_value("EventData", event_data, &mut flattened)
+            }
+        }
+        for (name, value) in flattened {
+            insert_unique(map, name, value);
+        }
+
+        if let Some(Value::Object(system)) = record.pointer("/Event/System") {
+            for key in &self.system_keys {
+                let mut flattened = Vec::new();
+                for system_key in [key.clone(), format!("{key}_attributes")] {
+                    if let Some(value) = system.get(&system_key).filter(|v| !v.is_null()) {
+                        flatten_value(&system_key, value, &mut flattened);
+                    }
+                }
+                for (name, value) in flattened {
+                    match map.get(&name) {
+                        // Already extracted with the same value, e.g. by a transformer field
+                        Some(existing) if *existing == value => {},
+                        Some(_) => insert_unique(map, format!("System_{name}"), value),
+                        None => {
+                            map.insert(name, value);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+/// Merge the schemas of heterogeneous records so every record has the same keys. Missing keys are
+/// set to null and columns with conflicting value types are converted to strings.
+pub fn merge_record_schemas(records: &mut [Map<String, Value>]) {
+    // Find the value kind of every column
+    let mut kinds: HashMap<String, Option<u8>> = HashMap::new();
+    let mut columns: Vec<String> = Vec::new();
+    for record in records.iter() {
+        for (key, value) in record {
+            let kind = match value {
+                Value::Null => None,
+                Value::Bool(_) => Some(0),
+                Value::Number(n) if n.is_f64() => Some(1),
+                Value::Number(_) => Some(2),
+                Value::String(_) => Some(3),
+                _ => Some(4)
+            };
+            match kinds.get_mut(key) {
+                None => {
+                    columns.push(key.clone());
+                    kinds.insert(key.clone(), kind);
+                },
+                Some(existing) => match (*existing, kind) {
+                    (_, None) => {},
+                    (None, k) => *existing = k,
+                    (Some(a), Some(b)) if a == b => {},
+                    // Mixed integers and floats are handled as floats
+                    (Some(1), Some(2)) | (Some(2), Some(1)) => *existing = Some(1),
+                    _ => *existing = Some(u8::MAX)
+                }
+            }
+        }
+    }
+
+    for record in records.iter_mut() {
+        for column in &columns {
+            match record.get_mut(column) {
+                None => {
+                    record.insert(column.clone(), Value::Null);
+                },
+                Some(value) => {
+                    let conflicting = kinds.get(column) == Some(&Some(u8::MAX));
+                    if conflicting && !value.is_null() && !value.is_string() {
+                        *value = Value::String(value.to_string());
+                    }
+                }
+            }
+        }
+    }
+}
+
+
 pub struct DocumentTransformer<'a> {
-    fields: Vec<FieldRetriever<'a>>
+    fields: Vec<FieldRetriever<'a>>,
+    flattener: Option<EventFlattener>
 }
 impl <'a>DocumentTransformer<'a> {
     /// Create an empty DocumentTransformer
     pub fn empty() -> Self {
         Self {
-            fields: Vec::new()
+            fields: Vec::new(),
+            flattener: None
         }
     }
 
+    /// Flatten all of EventData and the given System keys into the transformed document in
+    /// addition to the named fields.
+    pub fn with_flattener(mut self, flattener: EventFlattener) -> Self {
+        self.flattener = Some(flattener);
+        self
+    }
+
     /// Get a Map of the transformed document
     pub fn get_map<T: ToJmespath>(&self, data: T) -> Result<Map<String, Value>, JmespathError> {
         let data = data.to_jmespath()?;
@@ -56,6 +221,9 @@ impl <'a>DocumentTransformer<'a> {
             let m = field_retriever.get_map(&data)?;
             map.extend(m);
         }
+        if let Some(flattener) = &self.flattener {
+            flattener.flatten_into(&json!(data), &mut map);
+        }
         Ok(map)
     }
 
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::Serialize;
+use crate::errors::CustomError;
+
+
+/// Prices in USD per million input tokens.
+#[derive(Debug, Clone)]
+pub struct PriceTable {
+    prices: HashMap<String, f64>
+}
+impl Default for PriceTable {
+    /// The published prices of the OpenAI embedding models.
+    fn default() -> Self {
+        let prices = [
+            ("text-embedding-3-small", 0.02),
+            ("text-embedding-3-large", 0.13),
+            ("text-embedding-ada-002", 0.10),
+        ].into_iter()
+            .map(|(model, price)| (model.to_string(), price))
+            .collect();
+        Self { prices }
+    }
+}
+impl PriceTable {
+    /// Read prices from a JSON object of model names to USD per million tokens. The prices are
+    /// added to the default prices.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, CustomError> {
+        let path = path.as_ref();
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| CustomError::general_error(format!("Error reading {:?}: {e:?}", path)))?;
+        let prices: HashMap<String, f64> = serde_json::from_str(&data)
+            .map_err(|e| CustomError::general_error(format!("Error parsing price table {:?}: {e:?}", path)))?;
+
+        let mut table = Self::default();
+        table.prices.extend(prices);
+        Ok(table)
+    }
+
+    /// Set the price of a model.
+    pub fn with_price(mut self, model: impl AsRef<str>, usd_per_million_tokens: f64) -> Self {
+        self.prices.insert(model.as_ref().to_string(), usd_per_million_tokens);
+        self
+    }
+
+    /// The USD per million tokens of a model.
+    pub fn price(&self, model: &str) -> Option<f64> {
+        self.prices.get(model).copied()
+    }
+}
+
+
+/// Limits on the tokens sent or the estimated cost of a run.
+#[derive(Debug, Clone, Default)]
+pub struct Budget {
+    pub max_tokens: Option<u64>,
+    /// The maximum estimated cost in USD. Only used when the model has a price.
+    pub max_cost: Option<f64>
+}
+impl Budget {
+    /// Check if the tokens used, or their cost at `price` per million tokens, exceed the budget.
+    pub fn is_exceeded(&self, prompt_tokens: u64, price: Option<f64>) -> bool {
+        let tokens_exceeded = self.max_tokens
+            .is_some_and(|max| prompt_tokens >= max);
+        let cost_exceeded = match (self.max_cost, price) {
+            (Some(max), Some(price)) => estimate_cost(prompt_tokens, price) >= max,
+            _ => false
+        };
+        tokens_exceeded || cost_exceeded
+    }
+}
+
+
+/// The cost in USD of tokens at a price per million tokens.
+pub fn estimate_cost(prompt_tokens: u64, usd_per_million_tokens: f64) -> f64 {
+    prompt_tokens as f64 * usd_per_million_tokens / 1_000_000.0
+}
+
+
+/// Counters shared by the embedding tasks.
+#[derive(Debug, Default)]
+pub struct UsageCounters {
+    prompt_tokens: AtomicU64,
+    requests: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    skipped: AtomicU64
+}
+impl UsageCounters {
+    pub fn add_prompt_tokens(&self, tokens: u64) {
+        self.prompt_tokens.fetch_add(tokens, Ordering::SeqCst);
+    }
+
+    pub fn add_request(&self) {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn add_cache_hits(&self, inputs: u64) {
+        self.cache_hits.fetch_add(inputs, Ordering::SeqCst);
+    }
+
+    pub fn add_cache_misses(&self, inputs: u64) {
+        self.cache_misses.fetch_add(inputs, Ordering::SeqCst);
+    }
+
+    /// Count inputs that were not embedded because the budget was exceeded.
+    pub fn add_skipped(&self, inputs: u64) {
+        self.skipped.fetch_add(inputs, Ordering::SeqCst);
+    }
+
+    pub fn prompt_tokens(&self) -> u64 {
+        self.prompt_tokens.load(Ordering::SeqCst)
+    }
+
+    /// Get the counts with the cost estimated at `price` per million tokens.
+    pub fn report(&self, model: &str, price: Option<f64>, budget: &Budget) -> UsageReport {
+        let prompt_tokens = self.prompt_tokens();
+        UsageReport {
+            model: model.to_string(),
+            prompt_tokens,
+            requests: self.requests.load(Ordering::SeqCst),
+            cache_hits: self.cache_hits.load(Ordering::SeqCst),
+            cache_misses: self.cache_misses.load(Ordering::SeqCst),
+            skipped: self.skipped.load(Ordering::SeqCst),
+            estimated_cost: price.map(|price| estimate_cost(prompt_tokens, price)),
+            budget_exceeded: budget.is_exceeded(prompt_tokens, price)
+        }
+    }
+}
+
+
+/// The token usage, requests and cache use of an EmbeddingsHandler.
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageReport {
+    pub model: String,
+    pub prompt_tokens: u64,
+    /// Requests sent, including retries.
+    pub requests: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// Inputs that were not embedded because the budget was exceeded.
+    pub skipped: u64,
+    /// The estimated cost in USD if the model has a price.
+    pub estimated_cost: Option<f64>,
+    pub budget_exceeded: bool
+}
+impl fmt::Display for UsageReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} prompt tokens, {} requests, {} cache hits, {} cache misses",
+            self.model,
+            self.prompt_tokens,
+            self.requests,
+            self.cache_hits,
+            self.cache_misses
+        )?;
+        match self.estimated_cost {
+            Some(cost) => write!(f, ", estimated cost ${cost:.4}")?,
+            None => write!(f, ", no price for model")?
+        }
+        if self.budget_exceeded {
+            write!(f, ", budget exceeded with {} inputs skipped", self.skipped)?;
+        }
+        Ok(())
+    }
+}
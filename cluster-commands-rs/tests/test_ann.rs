@@ -0,0 +1,242 @@
+use std::sync::Arc;
+use ndarray::Array2;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use evtx_clustering::anomaly::{AnomalyMethod, AnomalyScorer};
+use evtx_clustering::ann::{HnswIndex, HnswParams, IndexedRows};
+use evtx_clustering::cache::{CacheNamespace, EmbeddingCache};
+use evtx_clustering::clusterer::{Clusterer, DbscanClusterer, NeighborDbscanClusterer};
+use evtx_clustering::distance::{DistanceMetric, NeighborSearch, PairwiseDistances};
+use evtx_clustering::encryption::KeySource;
+use evtx_clustering::tolerance::KDistanceAnalysis;
+
+
+/// Points scattered around a few random centers.
+fn blobs(rows: usize, dimensions: usize, seed: u64) -> Array2<f32> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let centers: Vec<Vec<f32>> = (0..8)
+        .map(|_| (0..dimensions).map(|_| rng.gen_range(-10.0..10.0)).collect())
+        .collect();
+    let values: Vec<f32> = (0..rows)
+        .flat_map(|row| {
+            let center = centers[row % centers.len()].clone();
+            center.into_iter().map(|c| c + rng.gen_range(-1.0f32..1.0)).collect::<Vec<f32>>()
+        })
+        .collect();
+    Array2::from_shape_vec((rows, dimensions), values).unwrap()
+}
+
+
+/// A key for each row of a dataset.
+fn keys(rows: usize) -> Vec<blake3::Hash> {
+    (0..rows).map(|row| blake3::hash(format!("input {row}").as_bytes())).collect()
+}
+
+
+fn indexed_rows(dataset: &Array2<f32>, metric: DistanceMetric) -> IndexedRows {
+    let keys = keys(dataset.nrows());
+    let mut index = HnswIndex::new(metric, dataset.ncols(), HnswParams::default());
+    assert_eq!(index.extend(&keys, dataset).unwrap(), dataset.nrows());
+    IndexedRows::new(Arc::new(index), &keys).unwrap()
+}
+
+
+#[test]
+fn test_hnsw_recall() {
+    let dataset = blobs(1000, 16, 7);
+    for metric in [DistanceMetric::L2, DistanceMetric::Cosine] {
+        let exact = PairwiseDistances::new(&dataset, metric);
+        let approximate = indexed_rows(&dataset, metric);
+        assert_eq!(approximate.rows(), 1000);
+
+        let mut found = 0;
+        for row in 0..dataset.nrows() {
+            let expected: Vec<usize> = exact.knn(row, 10).iter().map(|(r, _)| *r).collect();
+            let neighbors = approximate.knn(row, 10);
+            assert_eq!(neighbors.len(), 10);
+            assert!(neighbors.windows(2).all(|w| w[0].1 <= w[1].1));
+            found += neighbors.iter().filter(|(r, _)| expected.contains(r)).count();
+        }
+        let recall = found as f32 / 10_000.0;
+        assert!(recall > 0.95, "{metric} recall {recall}");
+    }
+}
+
+
+#[test]
+fn test_hnsw_duplicates_and_subsets() {
+    let dataset = blobs(200, 4, 3);
+    let keys = keys(200);
+    let mut index = HnswIndex::new(DistanceMetric::L2, 4, HnswParams::default());
+    assert_eq!(index.extend(&keys, &dataset).unwrap(), 200);
+    // Keys that are already indexed are not added again, even when their vectors were rounded
+    let rounded = dataset.mapv(|v| half::f16::from_f32(v).to_f32());
+    assert_eq!(index.extend(&keys, &rounded).unwrap(), 0);
+    assert!(index.extend(&keys[..1], &dataset).is_err());
+    let index = Arc::new(index);
+
+    // A subset of the indexed rows, with a duplicate of its first row
+    let mut subset = keys[..50].to_vec();
+    subset.push(keys[0]);
+    let rows = IndexedRows::new(index.clone(), &subset).unwrap();
+    let neighbors = rows.knn(0, 5);
+    assert_eq!(neighbors[0], (50, 0.0));
+    assert!(neighbors.iter().all(|(r, _)| *r < 51 && *r != 0));
+    assert_eq!(rows.range(0, 0.0), vec![(0, 0.0), (50, 0.0)]);
+    assert_eq!(rows.knn(0, 100).len(), 50);
+
+    let nearest = rows.search(&[dataset[[7, 0]], dataset[[7, 1]], dataset[[7, 2]], dataset[[7, 3]]], 1).unwrap();
+    assert_eq!(nearest, vec![(7, 0.0)]);
+    assert!(rows.search(&[0.0; 3], 1).is_err());
+    assert!(IndexedRows::new(index, &[blake3::hash(b"not indexed")]).is_err());
+}
+
+
+#[test]
+fn test_neighbor_dbscan() {
+    let dataset = blobs(400, 8, 11);
+    let search = Arc::new(indexed_rows(&dataset, DistanceMetric::L2));
+    for (min_points, tolerance) in [(3, 1.0), (5, 2.0), (10, 1.5)] {
+        let expected = DbscanClusterer::new(min_points, tolerance)
+            .fit_predict(&dataset, DistanceMetric::L2)
+            .unwrap();
+        let labels = NeighborDbscanClusterer::new(min_points, tolerance, search.clone())
+            .fit_predict(&dataset, DistanceMetric::L2)
+            .unwrap();
+        assert_eq!(labels, expected);
+    }
+    let clusterer = NeighborDbscanClusterer::new(3, 1.0, search);
+    assert!(clusterer.fit_predict(&dataset, DistanceMetric::Cosine).is_err());
+}
+
+
+#[test]
+fn test_hnsw_persistence() {
+    let dir = std::env::temp_dir().join(format!("evtx_clustering_ann_{}", std::process::id()));
+    let namespace = CacheNamespace::new("openai", "text-embedding-3-small", Some(16));
+    let path = HnswIndex::path_for(dir.join("cache"), &namespace, DistanceMetric::Cosine);
+    assert_eq!(path.parent().unwrap(), dir.join("cache.ann"));
+    assert!(path.to_string_lossy().ends_with("-cosine.hnsw"));
+
+    let dataset = blobs(300, 16, 5);
+    let keys = keys(300);
+    let params = HnswParams::default();
+    let mut index = HnswIndex::load_or_new(&path, DistanceMetric::Cosine, 16, params).unwrap();
+    assert!(index.is_empty());
+    index.extend(&keys[..200], &dataset.slice(ndarray::s![..200, ..]).to_owned()).unwrap();
+    index.save(&path).unwrap();
+
+    // The saved index finds the same neighbors and only the new rows are added to it
+    let mut loaded = HnswIndex::load_or_new(&path, DistanceMetric::Cosine, 16, params).unwrap();
+    assert_eq!(loaded.len(), 200);
+    assert_eq!(loaded.extend(&keys, &dataset).unwrap(), 100);
+    index.extend(&keys, &dataset).unwrap();
+    let (index, loaded) = (Arc::new(index), Arc::new(loaded));
+    let original = IndexedRows::new(index, &keys).unwrap();
+    let reloaded = IndexedRows::new(loaded.clone(), &keys).unwrap();
+    for row in (0..300).step_by(17) {
+        assert_eq!(original.knn(row, 5), reloaded.knn(row, 5));
+    }
+
+    // Another metric, dimensions or graph starts a new index
+    assert!(HnswIndex::load_or_new(&path, DistanceMetric::L2, 16, params).unwrap().is_empty());
+    let params = HnswParams { m: 8, ..params };
+    assert!(HnswIndex::load_or_new(&path, DistanceMetric::Cosine, 16, params).unwrap().is_empty());
+
+    // A header that declares more vectors than the file holds is rejected before reading them
+    loaded.save(&path).unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    let header_length = u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize;
+    let header = String::from_utf8(bytes[12..12 + header_length].to_vec()).unwrap();
+    let header = header.replace("\"nodes\":300", &format!("\"nodes\":{}", usize::MAX / 2));
+    let mut oversized = b"EVTXHNSW".to_vec();
+    oversized.extend((header.len() as u32).to_le_bytes());
+    oversized.extend(header.as_bytes());
+    oversized.extend(&bytes[12 + header_length..]);
+    std::fs::write(&path, oversized).unwrap();
+    assert!(HnswIndex::load(&path).unwrap_err().message.contains("too short"));
+    std::fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+    assert!(HnswIndex::load(&path).is_err());
+    std::fs::write(&path, b"not an index").unwrap();
+    assert!(HnswIndex::load(&path).is_err());
+    assert!(HnswIndex::load_or_new(&path, DistanceMetric::Cosine, 16, HnswParams::default()).unwrap().is_empty());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+
+#[test]
+fn test_hnsw_encryption() {
+    let dir = std::env::temp_dir().join(format!("evtx_clustering_ann_encryption_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let namespace = CacheNamespace::new("openai", "text-embedding-3-small", Some(16));
+    let passphrase = KeySource::Passphrase("correct horse".to_string());
+    let cache = EmbeddingCache::open_w_key(dir.join("cache"), &namespace, Some(&passphrase)).unwrap();
+    let cipher = cache.cipher();
+    let path = HnswIndex::path_for(dir.join("cache"), &namespace, DistanceMetric::L2);
+
+    let dataset = blobs(50, 16, 17);
+    let keys = keys(50);
+    let mut index = HnswIndex::new(DistanceMetric::L2, 16, HnswParams::default());
+    index.extend(&keys, &dataset).unwrap();
+    index.save_w_cipher(&path, cipher).unwrap();
+
+    // Neither the vectors nor the keys are stored in cleartext
+    let vector: Vec<u8> = dataset.row(0).iter().flat_map(|v| v.to_le_bytes()).collect();
+    let stored = std::fs::read_dir(dir.join("cache.ann")).unwrap()
+        .filter_map(|entry| std::fs::read(entry.unwrap().path()).ok())
+        .any(|bytes| {
+            bytes.windows(vector.len()).any(|w| w == vector.as_slice())
+                || bytes.windows(32).any(|w| w == keys[0].as_bytes())
+        });
+    assert!(!stored);
+
+    // The index needs the cipher, and an index that is not encrypted is rebuilt
+    assert!(HnswIndex::load(&path).is_err());
+    let loaded = HnswIndex::load_w_cipher(&path, cipher).unwrap();
+    assert_eq!(loaded.len(), 50);
+    index.save(&path).unwrap();
+    assert!(HnswIndex::load_w_cipher(&path, cipher).is_err());
+    let rebuilt = HnswIndex::load_or_new_w_cipher(&path, DistanceMetric::L2, 16, HnswParams::default(), cipher).unwrap();
+    assert!(rebuilt.is_empty());
+
+    drop(cache);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+
+#[test]
+fn test_neighbor_tolerance_and_anomaly_scores() {
+    let dataset = blobs(300, 8, 13);
+    let exact = Arc::new(PairwiseDistances::new(&dataset, DistanceMetric::Cosine));
+    let approximate = Arc::new(indexed_rows(&dataset, DistanceMetric::Cosine));
+
+    let expected = KDistanceAnalysis::new(&dataset, 4, DistanceMetric::Cosine).unwrap();
+    let analysis = KDistanceAnalysis::from_neighbors(exact.as_ref(), 4).unwrap();
+    assert_eq!(analysis.knee_index, expected.knee_index);
+    for (a, b) in analysis.distances.iter().zip(&expected.distances) {
+        assert!((a - b).abs() < 1e-5);
+    }
+    let analysis = KDistanceAnalysis::from_neighbors(approximate.as_ref(), 4).unwrap();
+    assert!((analysis.tolerance - expected.tolerance).abs() < 1e-3);
+
+    for method in [AnomalyMethod::Knn, AnomalyMethod::Lof] {
+        let expected = AnomalyScorer::new(method, 5).score(&dataset, DistanceMetric::Cosine, &[]).unwrap();
+        let scores = AnomalyScorer::new(method, 5)
+            .with_neighbors(approximate.clone())
+            .score(&dataset, DistanceMetric::Cosine, &[])
+            .unwrap();
+        let close = scores.iter().zip(&expected).filter(|(a, b)| (*a - *b).abs() < 1e-4).count();
+        assert!(close > 290, "{method} {close}");
+
+        // Datasets over the exact limit are indexed in memory
+        let scores = AnomalyScorer::new(method, 5)
+            .with_max_exact_rows(100)
+            .score(&dataset, DistanceMetric::Cosine, &[])
+            .unwrap();
+        let close = scores.iter().zip(&expected).filter(|(a, b)| (*a - *b).abs() < 1e-4).count();
+        assert!(close > 290, "{method} {close}");
+    }
+    let scorer = AnomalyScorer::new(AnomalyMethod::Knn, 5).with_neighbors(exact);
+    assert!(scorer.score(&dataset, DistanceMetric::L2, &[]).is_err());
+}
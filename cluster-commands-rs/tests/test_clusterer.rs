@@ -0,0 +1,124 @@
+use std::collections::HashSet;
+use ndarray::Array2;
+use evtx_clustering::clusterer::{
+    AgglomerativeClusterer, ClusterAlgorithm, Clusterer, DbscanClusterer, GaussianMixtureClusterer,
+    HdbscanClusterer, KMeansClusterer, OpticsClusterer
+};
+use evtx_clustering::distance::DistanceMetric;
+use evtx_clustering::hierarchy::Linkage;
+
+
+/// Three groups of six points around (0, 0), (10, 0) and (0, 10).
+fn blobs() -> Array2<f32> {
+    let offsets = [(0.0, 0.0), (0.3, 0.1), (-0.2, 0.3), (0.1, -0.3), (-0.3, -0.1), (0.2, 0.2)];
+    let mut values = Vec::new();
+    for (x, y) in [(0.0, 0.0), (10.0, 0.0), (0.0, 10.0)] {
+        for (dx, dy) in offsets {
+            values.push(x + dx);
+            values.push(y + dy);
+        }
+    }
+    Array2::from_shape_vec((18, 2), values).unwrap()
+}
+
+
+/// Check that the labels assign each group to its own cluster.
+fn assert_blob_clusters(name: &str, labels: &[Option<usize>]) {
+    assert_eq!(labels.len(), 18, "{name}");
+    let mut clusters = HashSet::new();
+    for group in labels.chunks(6) {
+        let first = group[0];
+        assert!(first.is_some(), "{name} labeled a point as noise: {labels:?}");
+        assert!(group.iter().all(|l| *l == first), "{name} split a group: {labels:?}");
+        clusters.insert(first);
+    }
+    assert_eq!(clusters.len(), 3, "{name} merged groups: {labels:?}");
+}
+
+
+#[test]
+fn test_clusterers() {
+    let dataset = blobs();
+    let clusterers: Vec<Box<dyn Clusterer>> = vec![
+        Box::new(DbscanClusterer::new(3, 1.0)),
+        Box::new(OpticsClusterer::new(3, 1.0)),
+        Box::new(HdbscanClusterer::new(4)),
+        Box::new(KMeansClusterer::new(3)),
+        Box::new(GaussianMixtureClusterer::new(3)),
+        Box::new(AgglomerativeClusterer::new(Linkage::Ward).with_n_clusters(3)),
+        Box::new(AgglomerativeClusterer::new(Linkage::Single).with_distance_threshold(2.0)),
+        Box::new(AgglomerativeClusterer::new(Linkage::Average).with_n_clusters(3)),
+        Box::new(AgglomerativeClusterer::new(Linkage::Complete).with_n_clusters(3)),
+    ];
+    for clusterer in clusterers {
+        let labels = clusterer.fit_predict(&dataset, DistanceMetric::L2).unwrap();
+        assert_blob_clusters(clusterer.name(), &labels);
+    }
+}
+
+
+#[test]
+fn test_clusterer_noise_and_errors() {
+    // An outlier far from every group is noise for the density based algorithms
+    let mut values: Vec<f32> = blobs().iter().copied().collect();
+    values.extend([50.0, 50.0]);
+    let dataset = Array2::from_shape_vec((19, 2), values).unwrap();
+    for clusterer in [
+        Box::new(DbscanClusterer::new(3, 1.0)) as Box<dyn Clusterer>,
+        Box::new(HdbscanClusterer::new(4)),
+    ] {
+        let labels = clusterer.fit_predict(&dataset, DistanceMetric::L2).unwrap();
+        assert_eq!(labels[18], None, "{}", clusterer.name());
+        assert_blob_clusters(clusterer.name(), &labels[..18]);
+    }
+
+    assert!(KMeansClusterer::new(20).fit_predict(&dataset, DistanceMetric::L2).is_err());
+    assert!(AgglomerativeClusterer::new(Linkage::Ward).fit_predict(&dataset, DistanceMetric::L2).is_err());
+    assert_eq!("gmm".parse::<ClusterAlgorithm>().unwrap(), ClusterAlgorithm::GaussianMixture);
+    assert!("spectral".parse::<ClusterAlgorithm>().is_err());
+}
+
+
+#[test]
+fn test_distance_metrics() {
+    let dataset = Array2::from_shape_vec((2, 2), vec![3.0f32, 0.0, 0.0, 0.5]).unwrap();
+    let prepared = DistanceMetric::Cosine.prepare(&dataset);
+    assert_eq!(prepared.row(0).to_vec(), vec![1.0, 0.0]);
+    assert_eq!(DistanceMetric::Cosine.distance(prepared.row(0), prepared.row(1)), 1.0);
+    assert!((DistanceMetric::NormalizedL2.distance(prepared.row(0), prepared.row(1)) - 2f32.sqrt()).abs() < 1e-6);
+    assert!((DistanceMetric::L2.distance(dataset.row(0), dataset.row(1)) - 9.25f32.sqrt()).abs() < 1e-6);
+    assert!((DistanceMetric::Cosine.l2_radius(1.0) - 2f32.sqrt()).abs() < 1e-6);
+    assert_eq!("normalized-l2".parse::<DistanceMetric>().unwrap(), DistanceMetric::NormalizedL2);
+}
+
+
+#[test]
+fn test_cosine_clustering() {
+    // Two directions with very different magnitudes. Cosine groups by direction, L2 does not.
+    let mut values = Vec::new();
+    for scale in [1.0f32, 5.0, 20.0] {
+        values.extend([scale, 0.05 * scale]);
+        values.extend([0.05 * scale, scale]);
+    }
+    let dataset = Array2::from_shape_vec((6, 2), values).unwrap();
+
+    let clusterers: Vec<Box<dyn Clusterer>> = vec![
+        Box::new(DbscanClusterer::new(2, 0.05)),
+        Box::new(OpticsClusterer::new(2, 0.05)),
+        Box::new(HdbscanClusterer::new(3)),
+        Box::new(AgglomerativeClusterer::new(Linkage::Average).with_n_clusters(2)),
+    ];
+    for clusterer in clusterers {
+        let labels = clusterer.fit_predict(&dataset, DistanceMetric::Cosine).unwrap();
+        assert!(labels[0].is_some(), "{}: {labels:?}", clusterer.name());
+        assert_eq!(labels[0], labels[2], "{}: {labels:?}", clusterer.name());
+        assert_eq!(labels[0], labels[4], "{}: {labels:?}", clusterer.name());
+        assert_eq!(labels[1], labels[3], "{}: {labels:?}", clusterer.name());
+        assert_eq!(labels[1], labels[5], "{}: {labels:?}", clusterer.name());
+        assert_ne!(labels[0], labels[1], "{}: {labels:?}", clusterer.name());
+    }
+
+    let labels = DbscanClusterer::new(2, 0.05).fit_predict(&dataset, DistanceMetric::L2).unwrap();
+    assert!(labels.iter().all(|l| l.is_none()));
+    assert!(AgglomerativeClusterer::new(Linkage::Ward).with_n_clusters(2).fit_predict(&dataset, DistanceMetric::Cosine).is_err());
+}
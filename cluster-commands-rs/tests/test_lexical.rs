@@ -0,0 +1,50 @@
+use evtx_clustering::lexical::LexicalEmbedder;
+use evtx_clustering::provider::EmbeddingProvider;
+
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+
+#[test]
+fn test_lexical_vectorize() {
+    let embedder = LexicalEmbedder::new(256);
+    let vector = embedder.vectorize("cmd.exe /c whoami");
+    assert_eq!(vector.len(), 256);
+
+    let norm: f32 = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    assert!((norm - 1.0).abs() < 1e-5);
+
+    // Vectors are deterministic
+    assert_eq!(vector, embedder.vectorize("cmd.exe /c whoami"));
+}
+
+
+#[test]
+fn test_lexical_similarity() {
+    let corpus = vec![
+        "cmd.exe /c whoami",
+        "cmd.exe /c whoami /all",
+        "powershell.exe -nop -w hidden -enc SQBFAFgA",
+    ];
+    let embedder = LexicalEmbedder::new(512).fit(&corpus);
+    // Fitted vectors depend on the corpus so they are not cached
+    assert_eq!(embedder.model(), "lexical-v1-512");
+    assert!(!embedder.is_cacheable());
+    assert!(LexicalEmbedder::new(512).is_cacheable());
+
+    let a = embedder.vectorize(corpus[0]);
+    let b = embedder.vectorize(corpus[1]);
+    let c = embedder.vectorize(corpus[2]);
+    assert!(cosine(&a, &b) > cosine(&a, &c));
+}
+
+
+#[tokio::test]
+async fn test_lexical_provider() {
+    let embedder = LexicalEmbedder::new(64);
+    let response = embedder.embed(String::from("whoami")).await.unwrap();
+    assert_eq!(response.data.first().unwrap().embedding.len(), 64);
+    assert_eq!(embedder.dimensions(), Some(64));
+}
@@ -0,0 +1,81 @@
+use ndarray::array;
+use evtx_clustering::distance::{DistanceMetric, PairwiseDistances};
+use evtx_clustering::metrics::{ClusterMetrics, ClusterStats, mean_silhouette, silhouette_samples};
+
+
+#[test]
+fn test_silhouette() {
+    let dataset = array![[0.0f32], [1.0], [10.0], [11.0], [50.0]];
+    let distances = PairwiseDistances::new(&dataset, DistanceMetric::L2);
+    let row_distances = |row: usize| distances.neighbors(row).iter().map(|(j, d)| (*j as usize, *d));
+
+    // Every clustered point has a = 1 and b = 10.5 or 9.5
+    let silhouettes = silhouette_samples(&[Some(0), Some(0), Some(1), Some(1), None], row_distances);
+    assert!((silhouettes[0].unwrap() - 9.5 / 10.5).abs() < 1e-6);
+    assert!((silhouettes[1].unwrap() - 8.5 / 9.5).abs() < 1e-6);
+    assert!(silhouettes[4].is_none());
+    let score = mean_silhouette(&silhouettes).unwrap();
+    assert!((score - (9.5 / 10.5 + 8.5 / 9.5) / 2.0).abs() < 1e-6, "{score}");
+
+    let one_cluster = silhouette_samples(&[Some(0), Some(0), Some(0), None, None], row_distances);
+    assert!(one_cluster.iter().all(|s| s.is_none()));
+    assert!(mean_silhouette(&one_cluster).is_none());
+    let singletons = silhouette_samples(&[Some(0), Some(1), None, None, None], row_distances);
+    assert_eq!(mean_silhouette(&singletons), Some(0.0));
+}
+
+
+#[test]
+fn test_cluster_metrics() {
+    let dataset = array![[0.0f32], [1.0], [10.0], [11.0], [50.0]];
+    let labels = [Some(0), Some(0), Some(1), Some(1), None];
+    let metrics = ClusterMetrics::new(&dataset, &labels, DistanceMetric::L2);
+
+    assert_eq!(metrics.rows, 5);
+    assert!((metrics.noise_fraction - 0.2).abs() < 1e-6);
+    assert_eq!(metrics.clusters, vec![
+        ClusterStats { cluster: 0, size: 2, mean_distance: 1.0, diameter: 1.0 },
+        ClusterStats { cluster: 1, size: 2, mean_distance: 1.0, diameter: 1.0 }
+    ]);
+    assert_eq!(metrics.silhouettes.len(), 5);
+    assert!((metrics.silhouette.unwrap() - (9.5 / 10.5 + 8.5 / 9.5) / 2.0).abs() < 1e-6);
+    // Centroids 0.5 and 10.5 with a mean distance of 0.5 to their members
+    assert!((metrics.davies_bouldin.unwrap() - 0.1).abs() < 1e-6);
+    // A between cluster sum of squares of 100 and within of 1 for 2 clusters of 4 rows
+    assert!((metrics.calinski_harabasz.unwrap() - 200.0).abs() < 1e-3);
+
+    let json: serde_json::Value = serde_json::from_str(&metrics.to_json().unwrap()).unwrap();
+    assert_eq!(json["metric"], "l2");
+    assert_eq!(json["clusters"].as_array().unwrap().len(), 2);
+    assert!(json.get("silhouettes").is_none());
+    assert_eq!(metrics.cluster_dataframe().unwrap().shape(), (2, 4));
+
+    let noise = ClusterMetrics::new(&dataset, &[None; 5], DistanceMetric::L2);
+    assert_eq!(noise.noise_fraction, 1.0);
+    assert!(noise.clusters.is_empty());
+    assert!(noise.silhouette.is_none() && noise.davies_bouldin.is_none() && noise.calinski_harabasz.is_none());
+}
+
+
+#[test]
+fn test_sampled_cluster_metrics() {
+    // Two tight groups far apart
+    let values: Vec<f32> = (0..400)
+        .map(|i| if i % 2 == 0 { (i % 10) as f32 * 0.01 } else { 100.0 + (i % 10) as f32 * 0.01 })
+        .collect();
+    let dataset = ndarray::Array2::from_shape_vec((400, 1), values).unwrap();
+    let labels: Vec<Option<usize>> = (0..400).map(|i| Some(i % 2)).collect();
+
+    let exact = ClusterMetrics::new(&dataset, &labels, DistanceMetric::L2);
+    assert!(exact.sampled_rows.is_none());
+    let sampled = ClusterMetrics::new_w_sample(&dataset, &labels, DistanceMetric::L2, 50);
+    assert_eq!(sampled.sampled_rows, Some(50));
+    assert_eq!(sampled.silhouettes.iter().flatten().count(), 50);
+    assert!((sampled.silhouette.unwrap() - exact.silhouette.unwrap()).abs() < 1e-3);
+    assert_eq!(sampled.clusters.iter().map(|c| c.size).collect::<Vec<_>>(), vec![200, 200]);
+    for (a, b) in sampled.clusters.iter().zip(&exact.clusters) {
+        assert!(a.diameter <= b.diameter);
+        assert!((a.mean_distance - b.mean_distance).abs() < 0.01);
+    }
+    assert_eq!(sampled.davies_bouldin, exact.davies_bouldin);
+}
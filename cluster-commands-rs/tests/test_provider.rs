@@ -0,0 +1,52 @@
+use evtx_clustering::provider::{AzureOpenAIProvider, OpenAICompatibleProvider, OpenAIProvider};
+
+
+#[test]
+fn test_openai_request() {
+    let provider = OpenAIProvider::new("sk-test".to_string(), "text-embedding-3-small", Some(256));
+    let request = provider.embedding_request().unwrap();
+    assert_eq!(request.url.as_str(), "https://api.openai.com/v1/embeddings");
+    assert_eq!(request.headers, vec![("authorization", "Bearer sk-test".to_string())]);
+}
+
+
+#[test]
+fn test_openai_compatible_request() {
+    // The base URL is joined the same way with or without a trailing slash
+    for base_url in ["http://localhost:11434/v1", "http://localhost:11434/v1/", "http://localhost:11434/v1//"] {
+        let provider = OpenAICompatibleProvider::new(base_url, None, "nomic-embed-text", None);
+        let request = provider.embedding_request().unwrap();
+        assert_eq!(request.url.as_str(), "http://localhost:11434/v1/embeddings");
+        assert!(request.headers.is_empty());
+    }
+
+    let provider = OpenAICompatibleProvider::new("http://tei:8080", Some("token".to_string()), "bge-small", None);
+    let request = provider.embedding_request().unwrap();
+    assert_eq!(request.url.as_str(), "http://tei:8080/embeddings");
+    assert_eq!(request.headers, vec![("authorization", "Bearer token".to_string())]);
+
+    let provider = OpenAICompatibleProvider::new("/v1", None, "nomic-embed-text", None);
+    assert!(provider.embedding_request().is_err());
+}
+
+
+#[test]
+fn test_azure_request() {
+    for endpoint in ["https://my-resource.openai.azure.com", "https://my-resource.openai.azure.com/"] {
+        let provider = AzureOpenAIProvider::new(
+            endpoint,
+            "embeddings-small",
+            "2024-02-01",
+            "azure-key".to_string(),
+            "text-embedding-3-small",
+            None
+        );
+        let request = provider.embedding_request().unwrap();
+        assert_eq!(
+            request.url.as_str(),
+            "https://my-resource.openai.azure.com/openai/deployments/embeddings-small/embeddings?api-version=2024-02-01"
+        );
+        // Azure takes the key in an api-key header instead of a bearer token
+        assert_eq!(request.headers, vec![("api-key", "azure-key".to_string())]);
+    }
+}
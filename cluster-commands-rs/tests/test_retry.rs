@@ -0,0 +1,51 @@
+use std::time::Duration;
+use evtx_clustering::provider::parse_reset_duration;
+use evtx_clustering::retry::{AdaptiveLimiter, RetryPolicy};
+
+
+#[test]
+fn test_parse_reset_duration() {
+    assert_eq!(parse_reset_duration("1s"), Some(Duration::from_secs(1)));
+    assert_eq!(parse_reset_duration("6m0s"), Some(Duration::from_secs(360)));
+    assert_eq!(parse_reset_duration("20ms"), Some(Duration::from_millis(20)));
+    assert_eq!(parse_reset_duration("30"), Some(Duration::from_secs(30)));
+    assert_eq!(parse_reset_duration("soon"), None);
+}
+
+
+#[test]
+fn test_retry_policy_delay() {
+    let policy = RetryPolicy {
+        max_retries: 5,
+        base_delay: Duration::from_millis(100),
+        max_delay: Duration::from_secs(1)
+    };
+
+    let delay = policy.delay(2, None);
+    assert!(delay >= Duration::from_millis(200) && delay <= Duration::from_millis(400));
+
+    // Capped by the max delay
+    assert!(policy.delay(10, None) <= Duration::from_secs(1));
+
+    // Retry-After is honored
+    assert!(policy.delay(0, Some(Duration::from_secs(5))) >= Duration::from_secs(5));
+}
+
+
+#[tokio::test]
+async fn test_adaptive_limiter() {
+    let limiter = AdaptiveLimiter::new(8, 2);
+    assert_eq!(limiter.limit(), 8);
+
+    limiter.on_throttle();
+    assert_eq!(limiter.limit(), 4);
+    limiter.on_throttle();
+    limiter.on_throttle();
+    assert_eq!(limiter.limit(), 2);
+
+    for _ in 0..2 {
+        let _permit = limiter.acquire().await;
+        limiter.on_success();
+    }
+    assert_eq!(limiter.limit(), 3);
+}
@@ -0,0 +1,71 @@
+use ndarray::{Array2, array};
+use evtx_clustering::clusterer::{Clusterer, DbscanClusterer};
+use evtx_clustering::distance::{DistanceMetric, PairwiseDistances};
+use evtx_clustering::sweep::{dbscan_w_distances, sweep, sweep_dataframe};
+
+
+/// Two groups of eight points and two outliers.
+fn groups() -> Array2<f32> {
+    let mut values = Vec::new();
+    for (x, y) in [(0.0f32, 0.0f32), (5.0, 5.0)] {
+        for i in 0..8 {
+            values.push(x + 0.2 * (i % 3) as f32);
+            values.push(y + 0.2 * (i / 3) as f32);
+        }
+    }
+    values.extend([10.0, -10.0, -10.0, 10.0]);
+    Array2::from_shape_vec((18, 2), values).unwrap()
+}
+
+
+#[test]
+fn test_pairwise_distances() {
+    let dataset = array![[0.0f32, 0.0], [3.0, 4.0], [0.0, 1.0], [0.0, 0.0]];
+    let distances = PairwiseDistances::new(&dataset, DistanceMetric::L2);
+    assert_eq!(distances.len(), 4);
+    assert_eq!(distances.neighbors(0), &[(0, 0.0), (3, 0.0), (2, 1.0), (1, 5.0)]);
+    assert_eq!(distances.neighbors(3)[0], (3, 0.0));
+    assert_eq!(distances.within(1, 4.0), &[(1, 0.0)]);
+    assert_eq!(distances.within(0, 1.0).len(), 3);
+}
+
+
+#[test]
+fn test_dbscan_w_distances() {
+    let dataset = groups();
+    let distances = PairwiseDistances::new(&dataset, DistanceMetric::L2);
+    for (min_points, tolerance) in [(2, 0.3), (3, 0.25), (5, 0.5), (20, 1.0), (2, 20.0)] {
+        let expected = DbscanClusterer::new(min_points, tolerance)
+            .fit_predict(&dataset, DistanceMetric::L2)
+            .unwrap();
+        assert_eq!(dbscan_w_distances(&distances, min_points, tolerance), expected);
+    }
+}
+
+
+#[test]
+fn test_sweep() {
+    let dataset = groups();
+    let distances = PairwiseDistances::new(&dataset, DistanceMetric::L2);
+    let results = sweep(&distances, &[0.3, 30.0], &[2, 10]);
+    assert_eq!(results.len(), 4);
+
+    let grouped = &results[0];
+    assert_eq!((grouped.tolerance, grouped.min_points), (0.3, 2));
+    assert_eq!(grouped.clusters, 2);
+    assert_eq!(grouped.largest_cluster, 8);
+    assert!((grouped.noise_ratio - 2.0 / 18.0).abs() < 1e-6);
+    assert!(grouped.silhouette.unwrap() > 0.8);
+
+    let too_dense = &results[1];
+    assert_eq!(too_dense.clusters, 0);
+    assert_eq!(too_dense.noise_ratio, 1.0);
+    assert!(too_dense.silhouette.is_none());
+
+    let merged = &results[2];
+    assert_eq!((merged.clusters, merged.largest_cluster), (1, 18));
+    assert!(merged.silhouette.is_none());
+
+    let df = sweep_dataframe(&results).unwrap();
+    assert_eq!(df.shape(), (4, 6));
+}
@@ -0,0 +1,53 @@
+use polars::prelude::*;
+use evtx_clustering::cache::CacheNamespace;
+use evtx_clustering::template::InputTemplate;
+
+
+#[test]
+fn test_template_parse() {
+    let template = InputTemplate::parse("{ParentImage} -> {Image}: {CommandLine} (user {User})").unwrap();
+    assert_eq!(template.fields(), vec!["ParentImage", "Image", "CommandLine", "User"]);
+    assert!(!template.is_default());
+    assert!(InputTemplate::default().is_default());
+
+    let template = InputTemplate::parse("{{{CommandLine}}}").unwrap();
+    assert_eq!(template.render(|_| Some("whoami")), "{whoami}");
+
+    assert!(InputTemplate::parse("no fields").is_err());
+    assert!(InputTemplate::parse("{CommandLine").is_err());
+    assert!(InputTemplate::parse("CommandLine}").is_err());
+    assert!(InputTemplate::parse("{}").is_err());
+}
+
+
+#[test]
+fn test_template_render_dataframe() {
+    let df = df!(
+        "Image" => [Some("cmd.exe"), None],
+        "CommandLine" => ["cmd /c whoami", "net user"],
+        "EventID" => [1i64, 4688]
+    ).unwrap();
+
+    let template = InputTemplate::parse("{Image}: {CommandLine} ({EventID})").unwrap();
+    assert_eq!(
+        template.render_dataframe(&df).unwrap(),
+        vec!["cmd.exe: cmd /c whoami (1)".to_string(), ": net user (4688)".to_string()]
+    );
+
+    let template = InputTemplate::parse("{User}: {CommandLine}").unwrap();
+    assert!(template.render_dataframe(&df).is_err());
+}
+
+
+#[test]
+fn test_template_namespace() {
+    let namespace = CacheNamespace::new("openai", "text-embedding-3-small", None);
+    let first = namespace.clone().with_template("{Image}: {CommandLine}");
+    let second = namespace.clone().with_template("{User}: {CommandLine}");
+
+    assert!(first.name().starts_with("openai/text-embedding-3-small/default/template-"));
+    assert_ne!(first.name(), second.name());
+    assert!(!first.is_compatible(&second));
+    assert!(!first.is_compatible(&namespace));
+    assert!(first.is_compatible(&first.clone()));
+}
@@ -0,0 +1,63 @@
+use evtx_clustering::tokens::{
+    InputHandling, LongInputStrategy, PreparedInput, combine_chunk_embeddings, count_tokens
+};
+
+
+#[test]
+fn test_prepared_input_full() {
+    let prepared = PreparedInput::new("cmd.exe /c whoami", 100, LongInputStrategy::Chunk);
+    assert_eq!(prepared.handling, InputHandling::Full);
+    assert_eq!(prepared.chunks, vec![String::from("cmd.exe /c whoami")]);
+    assert_eq!(prepared.tokens, count_tokens("cmd.exe /c whoami"));
+    assert_eq!(prepared.cache_key(), blake3::hash(b"cmd.exe /c whoami"));
+}
+
+
+#[test]
+fn test_prepared_input_long() {
+    let command = "powershell.exe -c Write-Host hello; ".repeat(50);
+    let tokens = count_tokens(&command);
+
+    let truncated = PreparedInput::new(&command, 16, LongInputStrategy::Truncate);
+    assert_eq!(truncated.handling, InputHandling::Truncated);
+    assert_eq!(truncated.chunk_tokens, vec![16]);
+    assert_eq!(truncated.tokens, tokens);
+
+    let chunked = PreparedInput::new(&command, 16, LongInputStrategy::Chunk);
+    assert_eq!(chunked.handling, InputHandling::Chunked(tokens.div_ceil(16)));
+    assert_eq!(chunked.chunk_tokens.iter().sum::<usize>(), tokens);
+    assert_eq!(chunked.chunks.concat(), command);
+
+    // Strategies are cached separately
+    assert_ne!(truncated.cache_key(), chunked.cache_key());
+}
+
+
+#[test]
+fn test_combine_chunk_embeddings() {
+    let combined = combine_chunk_embeddings(&[vec![1.0, 0.0], vec![0.0, 1.0]], &[3, 1]);
+    assert!((combined[0] - 0.9486833).abs() < 1e-5);
+    assert!((combined[1] - 0.3162278).abs() < 1e-5);
+}
+
+
+#[test]
+fn test_prepared_input_non_ascii() {
+    let command = "echo 日本語のコマンド héllo 🚀🔥 ".repeat(20);
+    let tokens = count_tokens(&command);
+
+    for max_tokens in [1, 3, 7] {
+        let chunked = PreparedInput::new(&command, max_tokens, LongInputStrategy::Chunk);
+        assert_eq!(chunked.chunks.concat(), command);
+        assert_eq!(chunked.chunk_tokens.iter().sum::<usize>(), tokens);
+        assert!(chunked.chunks.iter().all(|chunk| !chunk.contains('\u{FFFD}')));
+
+        let truncated = PreparedInput::new(&command, max_tokens, LongInputStrategy::Truncate);
+        assert!(command.starts_with(&truncated.chunks[0]));
+        assert!(!truncated.chunks[0].is_empty());
+    }
+
+    // Pieces stay within the limit unless a single character needs more tokens
+    let chunked = PreparedInput::new(&command, 7, LongInputStrategy::Chunk);
+    assert!(chunked.chunk_tokens.iter().all(|tokens| *tokens <= 7));
+}
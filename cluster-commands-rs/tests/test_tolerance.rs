@@ -0,0 +1,45 @@
+use ndarray::Array2;
+use evtx_clustering::clusterer::{Clusterer, DbscanClusterer};
+use evtx_clustering::distance::DistanceMetric;
+use evtx_clustering::tolerance::{KDistanceAnalysis, find_knee};
+
+
+#[test]
+fn test_find_knee() {
+    assert_eq!(find_knee(&[0.1, 0.1, 0.11, 0.12, 0.13, 0.9, 1.5]), 4);
+    assert_eq!(find_knee(&[0.5, 0.5, 0.5]), 2);
+    assert_eq!(find_knee(&[1.0]), 0);
+}
+
+
+#[test]
+fn test_auto_tolerance() {
+    // Two tight groups of ten points and three scattered outliers
+    let mut values = Vec::new();
+    for (x, y) in [(0.0f32, 0.0f32), (10.0, 10.0)] {
+        for i in 0..10 {
+            values.push(x + 0.1 * (i % 3) as f32);
+            values.push(y + 0.1 * (i / 3) as f32);
+        }
+    }
+    values.extend([5.0, -5.0, -6.0, 12.0, 20.0, 0.0]);
+    let dataset = Array2::from_shape_vec((23, 2), values).unwrap();
+
+    let analysis = KDistanceAnalysis::new(&dataset, 3, DistanceMetric::L2).unwrap();
+    assert_eq!(analysis.distances.len(), 23);
+    assert!(analysis.distances.windows(2).all(|w| w[0] <= w[1]));
+    assert!(analysis.tolerance >= 0.1 && analysis.tolerance < 1.0, "{}", analysis.tolerance);
+
+    let df = analysis.to_dataframe().unwrap();
+    assert_eq!(df.shape(), (23, 3));
+
+    let labels = DbscanClusterer::new(3, analysis.tolerance)
+        .fit_predict(&dataset, DistanceMetric::L2)
+        .unwrap();
+    assert!(labels[..10].iter().all(|l| *l == labels[0] && l.is_some()));
+    assert!(labels[10..20].iter().all(|l| *l == labels[10] && l.is_some()));
+    assert_ne!(labels[0], labels[10]);
+    assert!(labels[20..].iter().all(|l| l.is_none()));
+
+    assert!(KDistanceAnalysis::new(&dataset.slice(ndarray::s![..2, ..]).to_owned(), 3, DistanceMetric::L2).is_err());
+}
@@ -1,5 +1,5 @@
 use serde_json::json;
-use evtx_clustering::transformer::{DocumentTransformer, FieldRetriever};
+use evtx_clustering::transformer::{DocumentTransformer, EventFlattener, FieldRetriever, merge_record_schemas};
 
 
 #[test]
@@ -23,4 +23,54 @@ fn test_document_transformer() {
 
     let value = doc_transformer.get_map(json!({"test": "YAY"})).unwrap();
     assert_eq!(json!(value), json!({"field1": "YAY", "field2": "BLAH"}));
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_document_transformer_flattener() {
+    let doc_transformer = DocumentTransformer::empty()
+        .add_field_from_pattern("CommandLine", "Event.EventData.CommandLine").unwrap()
+        .add_field_from_pattern("Computer", "Event.System.Computer").unwrap()
+        .with_flattener(EventFlattener::new(vec![String::from("EventID"), String::from("Provider"), String::from("Computer")]));
+
+    let record = json!({
+        "Event": {
+            "EventData": {
+                "CommandLine": "whoami",
+                "Image": "C:\\Windows\\System32\\whoami.exe",
+                "EventID": "collides"
+            },
+            "System": {
+                "EventID": 1,
+                "Provider": null,
+                "Provider_attributes": {"Name": "Microsoft-Windows-Sysmon"},
+                "Computer": "WORKSTATION"
+            }
+        }
+    });
+
+    let value = doc_transformer.get_map(record).unwrap();
+    // Computer is already extracted with the same value, so it is not added again
+    assert_eq!(json!(value), json!({
+        "CommandLine": "whoami",
+        "Computer": "WORKSTATION",
+        "Image": "C:\\Windows\\System32\\whoami.exe",
+        "EventID": "collides",
+        "System_EventID": 1,
+        "Provider_attributes_Name": "Microsoft-Windows-Sysmon"
+    }));
+}
+
+
+#[test]
+fn test_merge_record_schemas() {
+    let mut records = vec![
+        json!({"a": 1, "b": "x"}).as_object().unwrap().clone(),
+        json!({"a": "one", "c": 2.5}).as_object().unwrap().clone(),
+    ];
+    merge_record_schemas(&mut records);
+
+    assert_eq!(json!(records), json!([
+        {"a": "1", "b": "x", "c": null},
+        {"a": "one", "b": null, "c": 2.5}
+    ]));
+}
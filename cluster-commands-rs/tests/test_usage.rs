@@ -0,0 +1,59 @@
+use std::sync::Arc;
+use evtx_clustering::embedding::EmbeddingsHandler;
+use evtx_clustering::lexical::LexicalEmbedder;
+use evtx_clustering::usage::{Budget, PriceTable, estimate_cost};
+
+
+#[test]
+fn test_budget() {
+    let prices = PriceTable::default()
+        .with_price("my-model", 1.0);
+    assert_eq!(prices.price("text-embedding-3-small"), Some(0.02));
+    assert_eq!(prices.price("my-model"), Some(1.0));
+    assert_eq!(prices.price("unknown"), None);
+    assert!((estimate_cost(500_000, 1.0) - 0.5).abs() < 1e-9);
+
+    let budget = Budget {
+        max_tokens: Some(1000),
+        max_cost: Some(0.5)
+    };
+    assert!(!budget.is_exceeded(999, None));
+    assert!(budget.is_exceeded(1000, None));
+    assert!(budget.is_exceeded(999, Some(1_000.0)));
+    assert!(!Budget::default().is_exceeded(u64::MAX, Some(1.0)));
+}
+
+
+#[tokio::test]
+async fn test_usage_budget() {
+    let embeddings = EmbeddingsHandler::from_provider(Arc::new(LexicalEmbedder::new(16)), 1)
+        .with_batch_size(1, 1000)
+        .with_price_table(PriceTable::default().with_price("lexical-v1-16", 1.0))
+        .with_budget(Budget {
+            max_tokens: Some(1),
+            max_cost: None
+        });
+
+    let input = vec!["whoami", "ipconfig /all", "net user", "whoami"];
+    let result = embeddings.get_embeddings(input).await.unwrap();
+
+    // Only the first request is sent and the other inputs fail
+    let values: Vec<&str> = result.iter()
+        .filter_map(|r| r.as_ref().ok())
+        .map(|v| v.value.as_str())
+        .collect();
+    assert_eq!(values, vec!["whoami", "whoami"]);
+    let failed: Vec<&str> = result.iter()
+        .filter_map(|r| r.as_ref().err())
+        .map(|f| f.value.as_str())
+        .collect();
+    assert_eq!(failed, vec!["ipconfig /all", "net user"]);
+
+    let report = embeddings.usage_report();
+    assert_eq!(report.requests, 1);
+    assert_eq!(report.cache_misses, 3);
+    assert_eq!(report.skipped, 2);
+    assert!(report.prompt_tokens > 0);
+    assert!(report.estimated_cost.is_some());
+    assert!(report.budget_exceeded);
+}